@@ -0,0 +1,290 @@
+use acres::{Configuration, Flags};
+use clap::Parser;
+
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+/// Compress and decompress data with CCSDS adaptive entropy coding.
+#[derive(Parser, Debug)]
+#[command(name = "acres", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Command {
+    /// Compress INPUT into OUTPUT
+    Compress(Args),
+    /// Decompress INPUT into OUTPUT, padded to a whole number of blocks
+    Decompress(Args),
+}
+
+#[derive(clap::Args, Debug)]
+struct Args {
+    /// Input file, or standard input if missing or `-`
+    input: Option<PathBuf>,
+    /// Output file, or standard output if missing or `-`
+    output: Option<PathBuf>,
+
+    /// Bits per sample
+    #[arg(short = 'n', long, default_value_t = 8)]
+    bits_per_sample: usize,
+    /// Samples per block
+    #[arg(short = 'j', long, default_value_t = 16)]
+    block_size: usize,
+    /// Blocks per reference sample interval
+    #[arg(short = 'r', long, default_value_t = 128)]
+    rsi: usize,
+
+    /// Samples are signed
+    #[arg(long, conflicts_with = "szip")]
+    signed: bool,
+    /// Store 17 to 24 bit samples in 3 bytes
+    #[arg(long = "3byte", conflicts_with = "szip")]
+    three_byte: bool,
+    /// Samples are most significant byte first
+    #[arg(long)]
+    msb: bool,
+    /// Use the preprocessor
+    #[arg(long, conflicts_with = "szip")]
+    preprocess: bool,
+    /// Use restricted coding options, for small sample sizes
+    #[arg(long, conflicts_with = "szip")]
+    restricted: bool,
+    /// Pad each reference sample interval to a byte boundary
+    #[arg(long, conflicts_with = "szip")]
+    pad_rsi: bool,
+    /// Do not enforce the standard's restrictions on block size
+    #[arg(long, conflicts_with = "szip")]
+    not_enforce: bool,
+
+    /// Use the szip interface, on the whole input at once
+    #[arg(long)]
+    szip: bool,
+    /// (szip) Samples are least significant byte first
+    #[arg(long, requires = "szip")]
+    lsb: bool,
+    /// (szip) Allow the k = 13 coding option
+    #[arg(long, requires = "szip")]
+    allow_k13: bool,
+    /// (szip) Use the chip option
+    #[arg(long, requires = "szip")]
+    chip: bool,
+    /// (szip) Use entropy coding without preprocessing
+    #[arg(long, requires = "szip")]
+    ec: bool,
+    /// (szip) Use nearest neighbour preprocessing
+    #[arg(long, requires = "szip")]
+    nn: bool,
+    /// (szip) Raw mode
+    #[arg(long, requires = "szip")]
+    raw: bool,
+    /// (szip) Pixels per scanline [default: block size * rsi]
+    #[arg(long, requires = "szip")]
+    pixels_per_scanline: Option<usize>,
+    /// (szip) Size of the decompressed data, in bytes
+    #[arg(long, requires = "szip")]
+    size: Option<usize>,
+}
+
+impl Args {
+    fn configuration(&self) -> std::result::Result<Configuration, acres::ConfigError> {
+        Configuration::builder()
+            .bits_per_sample(self.bits_per_sample)
+            .block_size(self.block_size)
+            .rsi(self.rsi)
+            .set(Flags::DATA_SIGNED, self.signed)
+            .set(Flags::DATA_3BYTE, self.three_byte)
+            .set(Flags::DATA_MSB, self.msb)
+            .set(Flags::DATA_PREPROCESS, self.preprocess)
+            .set(Flags::RESTRICTED, self.restricted)
+            .set(Flags::PAD_RSI, self.pad_rsi)
+            .set(Flags::NOT_ENFORCE, self.not_enforce)
+            .build()
+    }
+
+    #[cfg(feature = "libaec")]
+    fn sz(&self) -> acres::sz::Sz {
+        use acres::sz::Options;
+        let mut options = Options::empty();
+        options.set(Options::LSB, self.lsb);
+        options.set(Options::MSB, self.msb);
+        options.set(Options::ALLOW_K13, self.allow_k13);
+        options.set(Options::CHIP, self.chip);
+        options.set(Options::EC, self.ec);
+        options.set(Options::NN, self.nn);
+        options.set(Options::RAW, self.raw);
+        let pixels_per_scanline = self
+            .pixels_per_scanline
+            .unwrap_or(self.block_size * self.rsi);
+        acres::sz::Sz::new(
+            options,
+            self.bits_per_sample,
+            self.block_size,
+            pixels_per_scanline,
+        )
+    }
+
+    fn open_input(&self) -> io::Result<Box<dyn BufRead>> {
+        Ok(match self.input.as_deref() {
+            Some(path) if path != Path::new("-") => Box::new(io::BufReader::new(File::open(path)?)),
+            _ => Box::new(io::stdin().lock()),
+        })
+    }
+
+    fn open_output(&self) -> io::Result<Box<dyn Write>> {
+        Ok(match self.output.as_deref() {
+            Some(path) if path != Path::new("-") => {
+                Box::new(io::BufWriter::new(File::create(path)?))
+            }
+            _ => Box::new(io::stdout().lock()),
+        })
+    }
+}
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+fn compress(args: &Args, input: impl BufRead, mut output: impl Write) -> Result<()> {
+    if args.szip {
+        return szip_compress(args, input, output);
+    }
+    let mut reader = args.configuration()?.encode_reader(input)?;
+    io::copy(&mut reader, &mut output)?;
+    output.flush()?;
+    Ok(())
+}
+
+fn decompress(args: &Args, input: impl BufRead, mut output: impl Write) -> Result<()> {
+    if args.szip {
+        return szip_decompress(args, input, output);
+    }
+    let mut reader = args.configuration()?.decode_reader(input)?;
+    io::copy(&mut reader, &mut output)?;
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(feature = "libaec")]
+fn szip_compress(args: &Args, mut input: impl BufRead, mut output: impl Write) -> Result<()> {
+    let mut data = vec![];
+    input.read_to_end(&mut data)?;
+    let mut compressed = vec![];
+    output.write_all(args.sz().compress_to_vec(&data, &mut compressed)?)?;
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(feature = "libaec")]
+fn szip_decompress(args: &Args, mut input: impl BufRead, mut output: impl Write) -> Result<()> {
+    let size = args
+        .size
+        .ok_or("--size is needed to decompress with --szip")?;
+    let mut data = vec![];
+    input.read_to_end(&mut data)?;
+    let mut decompressed = vec![];
+    output.write_all(
+        args.sz()
+            .decompress_to_vec(&data, size, &mut decompressed)?,
+    )?;
+    output.flush()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "libaec"))]
+fn szip_compress(_args: &Args, _input: impl BufRead, _output: impl Write) -> Result<()> {
+    Err("--szip needs the libaec feature".into())
+}
+
+#[cfg(not(feature = "libaec"))]
+fn szip_decompress(_args: &Args, _input: impl BufRead, _output: impl Write) -> Result<()> {
+    Err("--szip needs the libaec feature".into())
+}
+
+fn run(cli: &Cli) -> Result<()> {
+    match &cli.command {
+        Command::Compress(args) => compress(args, args.open_input()?, args.open_output()?),
+        Command::Decompress(args) => decompress(args, args.open_input()?, args.open_output()?),
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(&cli) {
+        eprintln!("acres: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compress, decompress, Args, Cli, Command};
+    use acres::{Configuration, Flags};
+    use clap::Parser;
+
+    fn args(argv: &[&str]) -> Args {
+        let mut full = vec!["acres", "compress"];
+        full.extend_from_slice(argv);
+        match Cli::try_parse_from(full).unwrap().command {
+            Command::Compress(args) => args,
+            Command::Decompress(_) => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn configuration() {
+        let args = args(&["-n", "16", "-j", "32", "--msb", "--preprocess", "--pad-rsi"]);
+        assert_eq!(
+            args.configuration().unwrap(),
+            Configuration::new(
+                16,
+                32,
+                128,
+                Flags::DATA_MSB | Flags::DATA_PREPROCESS | Flags::PAD_RSI
+            )
+        );
+        assert!(Cli::try_parse_from(["acres", "compress", "--nn"]).is_err());
+        assert!(Cli::try_parse_from(["acres", "compress", "--szip", "--signed"]).is_err());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let args = args(&["-n", "16", "--preprocess"]);
+        let data: Vec<u8> = (0..4000u32)
+            .flat_map(|i| (i as u16).to_le_bytes())
+            .collect();
+        let mut compressed = vec![];
+        compress(&args, &data[..], &mut compressed).unwrap();
+        assert!(compressed.len() < data.len());
+        let mut decompressed = vec![];
+        decompress(&args, &compressed[..], &mut decompressed).unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    #[test]
+    fn odd_length() {
+        let args = args(&["-n", "16", "--preprocess"]);
+        let data: Vec<u8> = (0..4001u32)
+            .flat_map(|i| (i as u16).to_le_bytes())
+            .collect();
+
+        // a partial sample at the end is an error
+        let err = compress(&args, &data[..data.len() - 1], &mut vec![]).unwrap_err();
+        let err = err.downcast::<std::io::Error>().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        // whole samples, but not whole blocks, come back padded
+        let mut compressed = vec![];
+        compress(&args, &data[..], &mut compressed).unwrap();
+        let mut decompressed = vec![];
+        decompress(&args, &compressed[..], &mut decompressed).unwrap();
+        assert_eq!(decompressed.len(), 4016 * 2);
+        assert_eq!(&decompressed[..data.len()], data);
+        // with the last sample repeated
+        for pad in decompressed[data.len()..].chunks(2) {
+            assert_eq!(pad, 4000u16.to_le_bytes());
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! The small part of the HDF5 C API used by the filter.
+
+#![allow(non_camel_case_types)]
+
+use libc::{c_char, c_int, c_uint, c_ulonglong, c_void, size_t};
+
+pub type hid_t = i64;
+pub type herr_t = c_int;
+pub type htri_t = c_int;
+pub type hsize_t = c_ulonglong;
+pub type hbool_t = u8;
+pub type H5Z_filter_t = c_int;
+
+pub const H5PL_TYPE_FILTER: c_int = 0;
+
+pub const H5Z_CLASS_T_VERS: c_int = 1;
+pub const H5Z_FLAG_REVERSE: c_uint = 0x0100;
+
+pub const H5T_ORDER_LE: c_int = 0;
+pub const H5T_ORDER_BE: c_int = 1;
+
+pub const H5S_MAX_RANK: usize = 32;
+
+pub type H5Z_can_apply_func_t =
+    Option<unsafe extern "C" fn(dcpl_id: hid_t, type_id: hid_t, space_id: hid_t) -> htri_t>;
+pub type H5Z_set_local_func_t =
+    Option<unsafe extern "C" fn(dcpl_id: hid_t, type_id: hid_t, space_id: hid_t) -> herr_t>;
+pub type H5Z_func_t = Option<
+    unsafe extern "C" fn(
+        flags: c_uint,
+        cd_nelmts: size_t,
+        cd_values: *const c_uint,
+        nbytes: size_t,
+        buf_size: *mut size_t,
+        buf: *mut *mut c_void,
+    ) -> size_t,
+>;
+
+#[repr(C)]
+pub struct H5Z_class2_t {
+    pub version: c_int,
+    pub id: H5Z_filter_t,
+    pub encoder_present: c_uint,
+    pub decoder_present: c_uint,
+    pub name: *const c_char,
+    pub can_apply: H5Z_can_apply_func_t,
+    pub set_local: H5Z_set_local_func_t,
+    pub filter: H5Z_func_t,
+}
+
+// the class is immutable, and only ever read by HDF5
+unsafe impl Sync for H5Z_class2_t {}
+
+extern "C" {
+    pub fn H5allocate_memory(size: size_t, clear: hbool_t) -> *mut c_void;
+    pub fn H5free_memory(mem: *mut c_void) -> herr_t;
+
+    pub fn H5Pget_chunk(plist_id: hid_t, max_ndims: c_int, dim: *mut hsize_t) -> c_int;
+    pub fn H5Pget_filter_by_id2(
+        plist_id: hid_t,
+        id: H5Z_filter_t,
+        flags: *mut c_uint,
+        cd_nelmts: *mut size_t,
+        cd_values: *mut c_uint,
+        namelen: size_t,
+        name: *mut c_char,
+        filter_config: *mut c_uint,
+    ) -> herr_t;
+    pub fn H5Pmodify_filter(
+        plist_id: hid_t,
+        filter: H5Z_filter_t,
+        flags: c_uint,
+        cd_nelmts: size_t,
+        cd_values: *const c_uint,
+    ) -> herr_t;
+
+    pub fn H5Tget_offset(type_id: hid_t) -> c_int;
+    pub fn H5Tget_order(type_id: hid_t) -> c_int;
+    pub fn H5Tget_precision(type_id: hid_t) -> size_t;
+    pub fn H5Tget_size(type_id: hid_t) -> size_t;
+}
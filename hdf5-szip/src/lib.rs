@@ -0,0 +1,368 @@
+//! An [HDF5][] dynamic filter plugin for SZIP compression, built on
+//! [`acres::sz`].
+//!
+//! [HDF5]: https://www.hdfgroup.org/solutions/hdf5/
+//!
+//! This is a drop-in replacement for HDF5's built-in SZIP filter
+//! (filter id 4). It uses the same four `cd_values`, and the same
+//! chunk format: a little-endian 32-bit uncompressed size, followed
+//! by the raw szip data.
+
+use std::convert::TryFrom;
+use std::mem::MaybeUninit;
+
+use acres::sz::{Options, Sz};
+use libaec_sys::szlib::{SZ_MAX_BLOCKS_PER_SCANLINE, SZ_MAX_PIXELS_PER_SCANLINE};
+use libc::{c_char, c_int, c_uint, c_void, size_t};
+
+mod ffi;
+
+use ffi::*;
+
+/// The HDF5 filter id for SZIP.
+pub const H5Z_FILTER_SZIP: H5Z_filter_t = 4;
+
+// indices into cd_values
+const PARM_MASK: usize = 0;
+const PARM_PPB: usize = 1;
+const PARM_BPP: usize = 2;
+const PARM_PPS: usize = 3;
+const PARM_COUNT: usize = 4;
+
+// size of the uncompressed size prefix on each chunk
+const PREFIX_SIZE: usize = 4;
+
+static CLASS: H5Z_class2_t = H5Z_class2_t {
+    version: H5Z_CLASS_T_VERS,
+    id: H5Z_FILTER_SZIP,
+    encoder_present: 1,
+    decoder_present: 1,
+    name: b"szip\0".as_ptr() as *const c_char,
+    can_apply: Some(can_apply),
+    set_local: Some(set_local),
+    filter: Some(filter),
+};
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn H5PLget_plugin_type() -> c_int {
+    H5PL_TYPE_FILTER
+}
+
+#[no_mangle]
+#[allow(non_snake_case)]
+pub extern "C" fn H5PLget_plugin_info() -> *const c_void {
+    &CLASS as *const H5Z_class2_t as *const c_void
+}
+
+unsafe extern "C" fn can_apply(_dcpl_id: hid_t, type_id: hid_t, _space_id: hid_t) -> htri_t {
+    let size = H5Tget_size(type_id);
+    if size == 0 {
+        return -1;
+    }
+    let order = H5Tget_order(type_id);
+    let ok = (size <= 4 || size == 8) && (order == H5T_ORDER_LE || order == H5T_ORDER_BE);
+    ok as htri_t
+}
+
+unsafe extern "C" fn set_local(dcpl_id: hid_t, type_id: hid_t, _space_id: hid_t) -> herr_t {
+    let mut flags = 0;
+    let mut values = [0; PARM_COUNT];
+    let mut nelmts = values.len();
+    let err = H5Pget_filter_by_id2(
+        dcpl_id,
+        H5Z_FILTER_SZIP,
+        &mut flags,
+        &mut nelmts,
+        values.as_mut_ptr(),
+        0,
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+    );
+    if err < 0 || nelmts < 2 {
+        return -1;
+    }
+
+    let mut chunk = [0; H5S_MAX_RANK];
+    let ndims = H5Pget_chunk(dcpl_id, chunk.len() as c_int, chunk.as_mut_ptr());
+    if ndims <= 0 {
+        return -1;
+    }
+
+    let datatype = Datatype {
+        size: H5Tget_size(type_id),
+        precision: H5Tget_precision(type_id),
+        offset: H5Tget_offset(type_id),
+        order: H5Tget_order(type_id),
+    };
+    let chunk = &chunk[..ndims as usize];
+    match local_values(values[PARM_MASK], values[PARM_PPB], &datatype, chunk) {
+        Some(values) => H5Pmodify_filter(
+            dcpl_id,
+            H5Z_FILTER_SZIP,
+            flags,
+            values.len(),
+            values.as_ptr(),
+        ),
+        None => -1,
+    }
+}
+
+unsafe extern "C" fn filter(
+    flags: c_uint,
+    cd_nelmts: size_t,
+    cd_values: *const c_uint,
+    nbytes: size_t,
+    buf_size: *mut size_t,
+    buf: *mut *mut c_void,
+) -> size_t {
+    if cd_nelmts != PARM_COUNT || cd_values.is_null() {
+        return 0;
+    }
+    let values = std::slice::from_raw_parts(cd_values, PARM_COUNT);
+    let mut sz = Sz::new(
+        Options::from_bits_truncate(values[PARM_MASK]),
+        values[PARM_BPP] as usize,
+        values[PARM_PPB] as usize,
+        values[PARM_PPS] as usize,
+    );
+
+    let input = std::slice::from_raw_parts(*buf as *const u8, nbytes);
+    let reverse = flags & H5Z_FLAG_REVERSE != 0;
+    let len = if reverse {
+        match uncompressed_size(input) {
+            Some(len) => len,
+            None => return 0,
+        }
+    } else {
+        // like HDF5, assume the data won't get bigger
+        nbytes + PREFIX_SIZE
+    };
+
+    let outbuf = H5allocate_memory(len, 0);
+    if outbuf.is_null() {
+        return 0;
+    }
+    let output = std::slice::from_raw_parts_mut(outbuf as *mut MaybeUninit<u8>, len);
+    let result = if reverse {
+        decompress(&mut sz, input, output)
+    } else {
+        compress(&mut sz, input, output)
+    };
+
+    match result {
+        Some(written) => {
+            H5free_memory(*buf);
+            *buf = outbuf;
+            *buf_size = len;
+            written
+        }
+        None => {
+            H5free_memory(outbuf);
+            0
+        }
+    }
+}
+
+// the parts of a dataset's datatype the filter looks at
+struct Datatype {
+    // in bytes
+    size: usize,
+    // in bits
+    precision: usize,
+    offset: c_int,
+    order: c_int,
+}
+
+/// Fill in the bits per pixel, pixels per scanline, and byte order
+/// for a dataset, in the same way as HDF5's built-in filter.
+fn local_values(
+    mask: c_uint,
+    pixels_per_block: c_uint,
+    datatype: &Datatype,
+    chunk: &[u64],
+) -> Option<[c_uint; PARM_COUNT]> {
+    // samples that don't start at bit zero are coded whole
+    let size = datatype.size * 8;
+    let mut precision = datatype.precision;
+    if precision < size && datatype.offset != 0 {
+        precision = size;
+    }
+    let bits_per_pixel = match precision {
+        1..=24 => precision,
+        25..=32 => 32,
+        33..=64 => 64,
+        _ => return None,
+    };
+
+    let ppb = pixels_per_block as u64;
+    let max_blocks = SZ_MAX_BLOCKS_PER_SCANLINE as u64;
+    let fastest = *chunk.last()?;
+    let pixels_per_scanline = if fastest < ppb {
+        let npoints: u64 = chunk.iter().product();
+        if npoints < ppb {
+            return None;
+        }
+        npoints.min(ppb * max_blocks)
+    } else if fastest <= SZ_MAX_PIXELS_PER_SCANLINE as u64 {
+        fastest.min(ppb * max_blocks)
+    } else {
+        ppb * max_blocks
+    };
+
+    // chunks never carry an szip header, and the byte order comes
+    // from the datatype
+    let mut options = Options::from_bits_truncate(mask) - (Options::LSB | Options::MSB);
+    options |= Options::RAW;
+    options |= match datatype.order {
+        H5T_ORDER_LE => Options::LSB,
+        H5T_ORDER_BE => Options::MSB,
+        _ => return None,
+    };
+
+    Some([
+        options.bits(),
+        pixels_per_block,
+        bits_per_pixel as c_uint,
+        c_uint::try_from(pixels_per_scanline).ok()?,
+    ])
+}
+
+fn uncompressed_size(input: &[u8]) -> Option<usize> {
+    let mut prefix = [0; PREFIX_SIZE];
+    prefix.copy_from_slice(input.get(..PREFIX_SIZE)?);
+    usize::try_from(u32::from_le_bytes(prefix)).ok()
+}
+
+fn compress(sz: &mut Sz, input: &[u8], output: &mut [MaybeUninit<u8>]) -> Option<usize> {
+    if output.len() <= PREFIX_SIZE {
+        return None;
+    }
+    let size = u32::try_from(input.len()).ok()?;
+    let (prefix, rest) = output.split_at_mut(PREFIX_SIZE);
+    for (dest, b) in prefix.iter_mut().zip(size.to_le_bytes().iter()) {
+        *dest = MaybeUninit::new(*b);
+    }
+    let written = sz.compress(input, rest).ok()?.len();
+    Some(PREFIX_SIZE + written)
+}
+
+fn decompress(sz: &mut Sz, input: &[u8], output: &mut [MaybeUninit<u8>]) -> Option<usize> {
+    let size = uncompressed_size(input)?;
+    if size == 0 || output.len() < size {
+        return None;
+    }
+    let written = sz
+        .decompress(&input[PREFIX_SIZE..], &mut output[..size])
+        .ok()?
+        .len();
+    if written != size {
+        return None;
+    }
+    Some(written)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run<F>(f: F, sz: &mut Sz, input: &[u8], len: usize) -> Option<Vec<u8>>
+    where
+        F: Fn(&mut Sz, &[u8], &mut [MaybeUninit<u8>]) -> Option<usize>,
+    {
+        let mut output = Vec::with_capacity(len);
+        let written = f(sz, input, &mut output.spare_capacity_mut()[..len])?;
+        unsafe { output.set_len(written) };
+        Some(output)
+    }
+
+    // an integer type starting at bit zero
+    fn int(size: usize, precision: usize, order: c_int) -> Datatype {
+        Datatype {
+            size,
+            precision,
+            offset: 0,
+            order,
+        }
+    }
+
+    #[test]
+    fn local() {
+        let le = local_values(
+            Options::NN.bits(),
+            32,
+            &int(2, 16, H5T_ORDER_LE),
+            &[10, 1000],
+        )
+        .unwrap();
+        assert_eq!(
+            le,
+            [
+                (Options::NN | Options::RAW | Options::LSB).bits(),
+                32,
+                16,
+                1000
+            ]
+        );
+
+        // short rows span the whole chunk
+        let be = local_values(
+            Options::LSB.bits(),
+            16,
+            &int(4, 28, H5T_ORDER_BE),
+            &[100, 8],
+        )
+        .unwrap();
+        assert_eq!(be, [(Options::RAW | Options::MSB).bits(), 16, 32, 800]);
+
+        // long rows are capped
+        let long = local_values(0, 8, &int(1, 8, H5T_ORDER_LE), &[8192]).unwrap();
+        assert_eq!(long[PARM_PPS], 8 * 128);
+        let long = local_values(0, 8, &int(1, 8, H5T_ORDER_LE), &[2000]).unwrap();
+        assert_eq!(long[PARM_PPS], 8 * 128);
+
+        // an offset precision codes the whole type
+        let offset = Datatype {
+            offset: 4,
+            ..int(2, 12, H5T_ORDER_LE)
+        };
+        let values = local_values(0, 8, &offset, &[1000]).unwrap();
+        assert_eq!(values[PARM_BPP], 16);
+        let values = local_values(0, 8, &int(2, 12, H5T_ORDER_LE), &[1000]).unwrap();
+        assert_eq!(values[PARM_BPP], 12);
+        let offset = Datatype {
+            offset: 1,
+            ..int(4, 20, H5T_ORDER_LE)
+        };
+        let values = local_values(0, 8, &offset, &[1000]).unwrap();
+        assert_eq!(values[PARM_BPP], 32);
+
+        assert!(local_values(0, 32, &int(2, 16, H5T_ORDER_LE), &[2, 8]).is_none());
+        assert!(local_values(0, 32, &int(4, 0, H5T_ORDER_LE), &[1000]).is_none());
+    }
+
+    #[test]
+    fn round_trip() {
+        let data: Vec<u8> = (0..4096u32)
+            .flat_map(|i| ((i % 300) as u16).to_le_bytes().to_vec())
+            .collect();
+        let values =
+            local_values(Options::NN.bits(), 32, &int(2, 16, H5T_ORDER_LE), &[4096]).unwrap();
+        let mut sz = Sz::new(
+            Options::from_bits_truncate(values[PARM_MASK]),
+            values[PARM_BPP] as usize,
+            values[PARM_PPB] as usize,
+            values[PARM_PPS] as usize,
+        );
+
+        let compressed = run(compress, &mut sz, &data, data.len() + PREFIX_SIZE).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(uncompressed_size(&compressed), Some(data.len()));
+
+        let decompressed = run(decompress, &mut sz, &compressed, data.len()).unwrap();
+        assert_eq!(decompressed, data);
+
+        // a truncated prefix is rejected
+        assert!(run(decompress, &mut sz, &compressed[..2], data.len()).is_none());
+    }
+}
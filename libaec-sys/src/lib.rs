@@ -90,6 +90,8 @@ pub const AEC_DATA_ERROR: c_int = -3;
 /// (error) out of memory, or [`aec_stream::next_out`] is not a
 /// multiple of the storage size
 pub const AEC_MEM_ERROR: c_int = -4;
+/// (error) RSI offsets were not enabled, or are invalid
+pub const AEC_RSI_OFFSETS_ERROR: c_int = -5;
 
 // Options for flushing.
 
@@ -149,4 +151,55 @@ extern "C" {
     /// [`aec_decode_end`] in sequence to encode a buffer stored
     /// entirely in memory.
     pub fn aec_buffer_decode(strm: *mut aec_stream) -> c_int;
+
+    // Random access to reference sample intervals, since libaec 1.1.
+    // Offsets are measured in bits from the start of the encoded data.
+
+    /// Record the offset of every RSI while encoding. Call after
+    /// [`aec_encode_init`] and before encoding. Returns an error code.
+    pub fn aec_encode_enable_offsets(strm: *mut aec_stream) -> c_int;
+    /// Store the number of RSI offsets recorded by the encoder in
+    /// `rsi_offsets_count`. Returns an error code.
+    pub fn aec_encode_count_offsets(strm: *mut aec_stream, rsi_offsets_count: *mut size_t)
+        -> c_int;
+    /// Copy the RSI offsets recorded by the encoder into
+    /// `rsi_offsets`, which has room for `rsi_offsets_count`
+    /// entries. Returns an error code.
+    pub fn aec_encode_get_offsets(
+        strm: *mut aec_stream,
+        rsi_offsets: *mut size_t,
+        rsi_offsets_count: size_t,
+    ) -> c_int;
+
+    /// Record the offset of every RSI while decoding. Call after
+    /// [`aec_decode_init`] and before decoding. Returns an error code.
+    pub fn aec_decode_enable_offsets(strm: *mut aec_stream) -> c_int;
+    /// Store the number of RSI offsets recorded by the decoder in
+    /// `rsi_offsets_count`. Returns an error code.
+    pub fn aec_decode_count_offsets(strm: *mut aec_stream, rsi_offsets_count: *mut size_t)
+        -> c_int;
+    /// Copy the RSI offsets recorded by the decoder into
+    /// `rsi_offsets`, which has room for `rsi_offsets_count`
+    /// entries. Returns an error code.
+    pub fn aec_decode_get_offsets(
+        strm: *mut aec_stream,
+        rsi_offsets: *mut size_t,
+        rsi_offsets_count: size_t,
+    ) -> c_int;
+
+    /// Move an initialized decoder to the bit `offset` of its
+    /// input. Returns an error code.
+    pub fn aec_buffer_seek(strm: *mut aec_stream, offset: size_t) -> c_int;
+    /// Decode `size` bytes of output, starting at byte `pos` of the
+    /// decoded data, using the RSI offsets of the whole input.
+    ///
+    /// The stream must be initialized with [`aec_decode_init`], with
+    /// all of the encoded data as input. Returns an error code.
+    pub fn aec_decode_range(
+        strm: *mut aec_stream,
+        rsi_offsets: *const size_t,
+        rsi_offsets_count: size_t,
+        pos: size_t,
+        size: size_t,
+    ) -> c_int;
 }
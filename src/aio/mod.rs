@@ -0,0 +1,335 @@
+//! Asynchronous versions of [`Reader`](crate::Reader) and
+//! [`Writer`](crate::Writer).
+//!
+//! These implement the `tokio` traits with the `tokio` feature, and
+//! the `futures-io` traits with the `futures-io` feature. Unlike the
+//! blocking versions, readers are not buffered, and writers are not
+//! finished on drop: shut them down to end the stream.
+
+use crate::{Decoder, Encoder, ErrorKind, Operation, Status};
+
+use std::io;
+use std::task::{ready, Context, Poll};
+
+#[cfg(feature = "futures-io")]
+mod futures;
+#[cfg(feature = "tokio")]
+mod tokio;
+
+#[derive(Clone, Debug)]
+pub struct Reader<EncDec, T> {
+    encdec: EncDec,
+    // a sample split across reads of the inner reader
+    partial: Vec<u8>,
+    inner: T,
+}
+
+impl<EncDec, T> Reader<EncDec, T> {
+    pub fn new(encdec: EncDec, inner: T) -> Self {
+        Self {
+            encdec,
+            partial: vec![],
+            inner,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Writer<EncDec, T> {
+    encdec: EncDec,
+    pending: Pending,
+    // a sample split across writes
+    partial: Vec<u8>,
+    inner: T,
+}
+
+impl<EncDec, T> Writer<EncDec, T> {
+    pub fn new(encdec: EncDec, inner: T) -> Self {
+        Self::with_capacity(encdec, crate::DEFAULT_BUFFER_SIZE, inner)
+    }
+
+    pub fn with_capacity(encdec: EncDec, capacity: usize, inner: T) -> Self {
+        Self {
+            encdec,
+            pending: Pending {
+                // room for at least one sample
+                buffer: Vec::with_capacity(capacity.max(4)),
+                pos: 0,
+            },
+            partial: vec![],
+            inner,
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+// the runtime traits, reduced to what the adapters need
+
+trait Source {
+    fn poll_fill_buf(&mut self, cx: &mut Context) -> Poll<io::Result<&[u8]>>;
+    fn consume(&mut self, amt: usize);
+}
+
+trait Sink {
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>>;
+}
+
+/// Output waiting to be written to the inner writer.
+#[derive(Clone, Debug)]
+struct Pending {
+    buffer: Vec<u8>,
+    pos: usize,
+}
+
+impl Pending {
+    fn poll_drain(&mut self, sink: &mut impl Sink, cx: &mut Context) -> Poll<io::Result<()>> {
+        while self.pos < self.buffer.len() {
+            let n = ready!(sink.poll_write(cx, &self.buffer[self.pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pos += n;
+        }
+        self.buffer.clear();
+        self.pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn poll_read_encoder(
+    enc: &mut Encoder,
+    partial: &mut Vec<u8>,
+    src: &mut impl Source,
+    cx: &mut Context,
+    buf: &mut [u8],
+) -> Poll<io::Result<usize>> {
+    if enc.is_ended() {
+        return Poll::Ready(Ok(0));
+    }
+    let storage = enc.storage();
+    let mut produced = 0;
+    while produced < buf.len() {
+        let input = match src.poll_fill_buf(cx) {
+            Poll::Ready(input) => input?,
+            Poll::Pending if produced > 0 => break,
+            Poll::Pending => return Poll::Pending,
+        };
+        let inlen = input.len();
+        if !partial.is_empty() || (0 < inlen && inlen < storage) {
+            if inlen == 0 && partial.len() < storage {
+                if produced > 0 {
+                    break;
+                }
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "input ends with a partial sample",
+                )));
+            }
+            let n = (storage - partial.len()).min(inlen);
+            partial.extend_from_slice(&input[..n]);
+            src.consume(n);
+            if partial.len() < storage {
+                continue;
+            }
+            let (rest, out, _) = enc.encode(partial, &mut buf[produced..], false)?;
+            let consumed = partial.len() - rest.len();
+            produced += out.len();
+            partial.drain(..consumed);
+            continue;
+        }
+        let (rest, out, status) = enc.encode(input, &mut buf[produced..], inlen == 0)?;
+        let consumed = inlen - rest.len();
+        produced += out.len();
+        src.consume(consumed);
+        if status == Status::StreamEnd {
+            enc.end()?;
+            break;
+        }
+    }
+    Poll::Ready(Ok(produced))
+}
+
+fn poll_read_decoder(
+    dec: &mut Decoder,
+    src: &mut impl Source,
+    cx: &mut Context,
+    buf: &mut [u8],
+) -> Poll<io::Result<usize>> {
+    if dec.is_ended() {
+        return Poll::Ready(Ok(0));
+    }
+    let mut produced = 0;
+    while produced < buf.len() {
+        let input = match src.poll_fill_buf(cx) {
+            Poll::Ready(input) => input?,
+            Poll::Pending if produced > 0 => break,
+            Poll::Pending => return Poll::Pending,
+        };
+        if input.is_empty() {
+            dec.end()?;
+            break;
+        }
+        let inlen = input.len();
+        let (rest, out, _) = dec.decode(input, &mut buf[produced..], false)?;
+        let consumed = inlen - rest.len();
+        produced += out.len();
+        src.consume(consumed);
+    }
+    Poll::Ready(Ok(produced))
+}
+
+/// Encode a whole sample collected from earlier writes, returning
+/// whether there was room for it.
+fn encode_partial(
+    enc: &mut Encoder,
+    pending: &mut Pending,
+    partial: &mut Vec<u8>,
+) -> io::Result<bool> {
+    let (rest, _, _) = enc.encode(partial, &mut pending.buffer, false)?;
+    if rest.is_empty() {
+        partial.clear();
+    }
+    Ok(partial.is_empty())
+}
+
+fn poll_write_encoder(
+    enc: &mut Encoder,
+    pending: &mut Pending,
+    partial: &mut Vec<u8>,
+    sink: &mut impl Sink,
+    cx: &mut Context,
+    buf: &[u8],
+) -> Poll<io::Result<usize>> {
+    let storage = enc.storage();
+    loop {
+        ready!(pending.poll_drain(sink, cx))?;
+        if enc.is_ended() || buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if partial.len() == storage {
+            if !encode_partial(enc, pending, partial)? && pending.buffer.is_empty() {
+                // no progress at all
+                return Poll::Ready(Ok(0));
+            }
+            continue;
+        }
+        if !partial.is_empty() || buf.len() < storage {
+            let n = (storage - partial.len()).min(buf.len());
+            partial.extend_from_slice(&buf[..n]);
+            return Poll::Ready(Ok(n));
+        }
+        let (rest, _, _) = enc.encode(buf, &mut pending.buffer, false)?;
+        let consumed = buf.len() - rest.len();
+        if consumed > 0 {
+            return Poll::Ready(Ok(consumed));
+        }
+        if pending.buffer.is_empty() {
+            // no progress at all
+            return Poll::Ready(Ok(0));
+        }
+    }
+}
+
+/// Flush and end the encoder, writing all of its output.
+fn poll_finish_encoder(
+    enc: &mut Encoder,
+    pending: &mut Pending,
+    partial: &mut Vec<u8>,
+    sink: &mut impl Sink,
+    cx: &mut Context,
+) -> Poll<io::Result<()>> {
+    let storage = enc.storage();
+    loop {
+        ready!(pending.poll_drain(sink, cx))?;
+        if enc.is_ended() {
+            return Poll::Ready(Ok(()));
+        }
+        if partial.len() == storage {
+            encode_partial(enc, pending, partial)?;
+            continue;
+        }
+        if !partial.is_empty() {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "stream ends with a partial sample",
+            )));
+        }
+        let (_, _, status) = enc.encode(&[], &mut pending.buffer, true)?;
+        if status == Status::StreamEnd {
+            enc.end()?;
+        }
+    }
+}
+
+/// Decode what is left, write all of it, and end the decoder,
+/// failing if the stream stopped partway through a block.
+fn poll_finish_decoder(
+    dec: &mut Decoder,
+    pending: &mut Pending,
+    sink: &mut impl Sink,
+    cx: &mut Context,
+) -> Poll<io::Result<()>> {
+    loop {
+        ready!(pending.poll_drain(sink, cx))?;
+        if dec.is_ended() {
+            return Poll::Ready(Ok(()));
+        }
+        let (_, _, status) = dec.decode(&[], &mut pending.buffer, true)?;
+        if status != Status::NeedOutput {
+            // write out what was decoded, even if it is cut short
+            ready!(pending.poll_drain(sink, cx))?;
+            let err = dec.error(ErrorKind::Stream, Operation::DecodeEnd);
+            dec.end()?;
+            if status != Status::StreamEnd {
+                return Poll::Ready(Err(err.into()));
+            }
+        }
+    }
+}
+
+fn poll_write_decoder(
+    dec: &mut Decoder,
+    pending: &mut Pending,
+    sink: &mut impl Sink,
+    cx: &mut Context,
+    buf: &[u8],
+) -> Poll<io::Result<usize>> {
+    loop {
+        ready!(pending.poll_drain(sink, cx))?;
+        if dec.is_ended() || buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let (rest, _, _) = dec.decode(buf, &mut pending.buffer, false)?;
+        let consumed = buf.len() - rest.len();
+        if consumed > 0 {
+            return Poll::Ready(Ok(consumed));
+        }
+        if pending.buffer.is_empty() {
+            // no progress at all
+            return Poll::Ready(Ok(0));
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use super::{Reader, Sink, Source, Writer};
+use crate::{Decoder, Encoder};
+
+use ::tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+struct Input<'a, T>(Pin<&'a mut T>);
+
+impl<T: AsyncBufRead> Source for Input<'_, T> {
+    fn poll_fill_buf(&mut self, cx: &mut Context) -> Poll<io::Result<&[u8]>> {
+        self.0.as_mut().poll_fill_buf(cx)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.0.as_mut().consume(amt)
+    }
+}
+
+struct Output<'a, T>(Pin<&'a mut T>);
+
+impl<T: AsyncWrite> Sink for Output<'_, T> {
+    fn poll_write(&mut self, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.0.as_mut().poll_write(cx, buf)
+    }
+}
+
+// run a read on the unfilled part of a ReadBuf
+fn read_into(
+    buf: &mut ReadBuf,
+    f: impl FnOnce(&mut [u8]) -> Poll<io::Result<usize>>,
+) -> Poll<io::Result<()>> {
+    let n = ready!(f(buf.initialize_unfilled()))?;
+    buf.advance(n);
+    Poll::Ready(Ok(()))
+}
+
+impl<T> AsyncRead for Reader<Encoder, T>
+where
+    T: AsyncBufRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let encdec = &mut this.encdec;
+        let partial = &mut this.partial;
+        let mut input = Input(Pin::new(&mut this.inner));
+        read_into(buf, |buf| {
+            super::poll_read_encoder(encdec, partial, &mut input, cx, buf)
+        })
+    }
+}
+
+impl<T> AsyncRead for Reader<Decoder, T>
+where
+    T: AsyncBufRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let encdec = &mut this.encdec;
+        let mut input = Input(Pin::new(&mut this.inner));
+        read_into(buf, |buf| {
+            super::poll_read_decoder(encdec, &mut input, cx, buf)
+        })
+    }
+}
+
+impl<T> AsyncWrite for Writer<Encoder, T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut output = Output(Pin::new(&mut this.inner));
+        super::poll_write_encoder(
+            &mut this.encdec,
+            &mut this.pending,
+            &mut this.partial,
+            &mut output,
+            cx,
+            buf,
+        )
+    }
+
+    /// Like the blocking [`Writer`](crate::Writer), this does not end
+    /// the stream. Shutting down does.
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut output = Output(Pin::new(&mut this.inner));
+        ready!(this.pending.poll_drain(&mut output, cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut output = Output(Pin::new(&mut this.inner));
+        ready!(super::poll_finish_encoder(
+            &mut this.encdec,
+            &mut this.pending,
+            &mut this.partial,
+            &mut output,
+            cx
+        ))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl<T> AsyncWrite for Writer<Decoder, T>
+where
+    T: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut output = Output(Pin::new(&mut this.inner));
+        super::poll_write_decoder(&mut this.encdec, &mut this.pending, &mut output, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut output = Output(Pin::new(&mut this.inner));
+        ready!(this.pending.poll_drain(&mut output, cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let mut output = Output(Pin::new(&mut this.inner));
+        ready!(super::poll_finish_decoder(
+            &mut this.encdec,
+            &mut this.pending,
+            &mut output,
+            cx
+        ))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{Reader, Writer};
+    use crate::{Configuration, Flags};
+
+    use ::tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+    use futures::executor::block_on;
+
+    const DATA: &[u8] = b" This is a fun message for you. ";
+
+    fn config() -> Configuration {
+        Configuration::new(8, 16, 16, Flags::DATA_MSB | Flags::DATA_PREPROCESS)
+    }
+
+    #[test]
+    fn encode_reader() {
+        let conf = config();
+        let inner = BufReader::with_capacity(2, DATA);
+        let mut reader = Reader::new(conf.encoder().unwrap(), inner);
+        let mut encoded = vec![];
+        block_on(reader.read_to_end(&mut encoded)).unwrap();
+        let mut decoded = vec![];
+        conf.decode_buffer(&encoded, &mut decoded).unwrap();
+        assert_eq!(decoded, DATA);
+    }
+
+    #[test]
+    fn decode_reader() {
+        let conf = config();
+        let mut encoded = vec![];
+        conf.encode_buffer(DATA, &mut encoded).unwrap();
+        let inner = BufReader::with_capacity(2, &encoded[..]);
+        let mut reader = Reader::new(conf.decoder().unwrap(), inner);
+        let mut decoded = vec![];
+        block_on(reader.read_to_end(&mut decoded)).unwrap();
+        assert_eq!(decoded, DATA);
+    }
+
+    #[test]
+    fn encode_writer() {
+        let conf = config();
+        let mut writer = Writer::with_capacity(conf.encoder().unwrap(), 2, vec![]);
+        block_on(async {
+            writer.write_all(DATA).await?;
+            writer.shutdown().await
+        })
+        .unwrap();
+        let encoded = writer.into_inner();
+        let mut decoded = vec![];
+        conf.decode_buffer(&encoded, &mut decoded).unwrap();
+        assert_eq!(decoded, DATA);
+    }
+
+    #[test]
+    fn decode_writer() {
+        let conf = config();
+        let mut encoded = vec![];
+        conf.encode_buffer(DATA, &mut encoded).unwrap();
+        let mut writer = Writer::with_capacity(conf.decoder().unwrap(), 2, vec![]);
+        block_on(async {
+            writer.write_all(&encoded).await?;
+            writer.shutdown().await
+        })
+        .unwrap();
+        assert_eq!(writer.into_inner(), DATA);
+
+        let mut writer = Writer::new(conf.decoder().unwrap(), vec![]);
+        let err = block_on(async {
+            writer.write_all(&encoded[..encoded.len() / 2]).await?;
+            writer.flush().await?;
+            writer.shutdown().await
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn split_samples() {
+        let conf = Configuration::new(16, 16, 16, Flags::DATA_MSB | Flags::DATA_PREPROCESS);
+        let mut expected = vec![];
+        conf.encode_buffer(DATA, &mut expected).unwrap();
+
+        let inner = BufReader::with_capacity(3, DATA);
+        let mut reader = Reader::new(conf.encoder().unwrap(), inner);
+        let mut encoded = vec![];
+        block_on(reader.read_to_end(&mut encoded)).unwrap();
+        assert_eq!(encoded, expected);
+
+        let inner = BufReader::with_capacity(3, &DATA[..DATA.len() - 1]);
+        let mut reader = Reader::new(conf.encoder().unwrap(), inner);
+        let err = block_on(reader.read_to_end(&mut vec![])).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+        let mut writer = Writer::with_capacity(conf.encoder().unwrap(), 2, vec![]);
+        block_on(async {
+            for piece in DATA.chunks(3) {
+                writer.write_all(piece).await?;
+            }
+            writer.shutdown().await
+        })
+        .unwrap();
+        assert_eq!(writer.into_inner(), expected);
+
+        let mut writer = Writer::with_capacity(conf.decoder().unwrap(), 2, vec![]);
+        block_on(async {
+            for piece in expected.chunks(3) {
+                writer.write_all(piece).await?;
+            }
+            writer.shutdown().await
+        })
+        .unwrap();
+        assert_eq!(writer.into_inner(), DATA);
+
+        let mut writer = Writer::new(conf.encoder().unwrap(), vec![]);
+        let err = block_on(async {
+            writer.write_all(&DATA[..DATA.len() - 1]).await?;
+            writer.shutdown().await
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+}
@@ -1,6 +1,18 @@
+use std::io;
 use std::mem::MaybeUninit;
 
-pub trait Buffer {
+/// Somewhere for an [`Encoder`] or [`Decoder`] to write its output.
+///
+/// # Safety
+///
+/// [`Buffer::write_info`] must return a pointer valid for writes of
+/// the length returned with it, and stay valid until the next call
+/// to [`Buffer::write_data`]. The coders write through it without
+/// any further checks.
+///
+/// [`Encoder`]: crate::Encoder
+/// [`Decoder`]: crate::Decoder
+pub unsafe trait Buffer {
     fn write_info(&mut self) -> (*mut u8, usize);
 
     /// # Safety
@@ -11,7 +23,7 @@ pub trait Buffer {
     unsafe fn write_data(&mut self, amt: usize) -> &mut [u8];
 }
 
-impl Buffer for [u8] {
+unsafe impl Buffer for [u8] {
     fn write_info(&mut self) -> (*mut u8, usize) {
         (self.as_mut_ptr(), self.len())
     }
@@ -21,9 +33,9 @@ impl Buffer for [u8] {
     }
 }
 
-impl Buffer for [MaybeUninit<u8>] {
+unsafe impl Buffer for [MaybeUninit<u8>] {
     fn write_info(&mut self) -> (*mut u8, usize) {
-        (self[0].as_mut_ptr(), self.len())
+        (self.as_mut_ptr() as *mut u8, self.len())
     }
 
     unsafe fn write_data(&mut self, amt: usize) -> &mut [u8] {
@@ -32,10 +44,26 @@ impl Buffer for [MaybeUninit<u8>] {
     }
 }
 
-impl Buffer for Vec<u8> {
+/// Output goes in the spare capacity, after the current contents.
+unsafe impl Buffer for Vec<u8> {
     fn write_info(&mut self) -> (*mut u8, usize) {
+        let spare = self.spare_capacity_mut();
+        (spare.as_mut_ptr() as *mut u8, spare.len())
+    }
+
+    unsafe fn write_data(&mut self, amt: usize) -> &mut [u8] {
         let l = self.len();
-        (self[l..].as_mut_ptr(), self.capacity() - l)
+        self.set_len(l + amt);
+        &mut self[l..]
+    }
+}
+
+/// Output goes in the spare capacity, as for `Vec<u8>`.
+#[cfg(feature = "bytes")]
+unsafe impl Buffer for bytes::BytesMut {
+    fn write_info(&mut self) -> (*mut u8, usize) {
+        let spare = self.spare_capacity_mut();
+        (spare.as_mut_ptr() as *mut u8, spare.len())
     }
 
     unsafe fn write_data(&mut self, amt: usize) -> &mut [u8] {
@@ -44,3 +72,91 @@ impl Buffer for Vec<u8> {
         &mut self[l..]
     }
 }
+
+/// Output goes after the cursor's position, which moves past it.
+unsafe impl Buffer for io::Cursor<&mut [u8]> {
+    fn write_info(&mut self) -> (*mut u8, usize) {
+        let pos = (self.position() as usize).min(self.get_ref().len());
+        let rest = &mut self.get_mut()[pos..];
+        (rest.as_mut_ptr(), rest.len())
+    }
+
+    unsafe fn write_data(&mut self, amt: usize) -> &mut [u8] {
+        let pos = (self.position() as usize).min(self.get_ref().len());
+        self.set_position((pos + amt) as u64);
+        &mut self.get_mut()[pos..pos + amt]
+    }
+}
+
+unsafe impl<B: Buffer + ?Sized> Buffer for &mut B {
+    fn write_info(&mut self) -> (*mut u8, usize) {
+        (**self).write_info()
+    }
+
+    unsafe fn write_data(&mut self, amt: usize) -> &mut [u8] {
+        (**self).write_data(amt)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Configuration, Flags, Status};
+    use std::io;
+    use std::mem::MaybeUninit;
+
+    #[test]
+    fn buffers() {
+        let conf = Configuration::new(8, 16, 16, Flags::DATA_PREPROCESS);
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * i / 7) as u8).collect();
+        let mut expected = vec![];
+        conf.encode_buffer(&data, &mut expected).unwrap();
+
+        // an empty output is no room, not a panic
+        let mut enc = conf.encoder().unwrap();
+        let empty: &mut [MaybeUninit<u8>] = &mut [];
+        let (_, out, status) = enc.encode(&data, empty, false).unwrap();
+        assert!(out.is_empty());
+        assert_eq!(status, Status::NeedOutput);
+
+        // a cursor fills up from its position
+        let mut enc = conf.encoder().unwrap();
+        let mut storage = vec![0; expected.len() + 8];
+        let mut cursor = io::Cursor::new(&mut storage[..]);
+        cursor.set_position(3);
+        let (_, _, status) = enc.encode(&data, &mut cursor, true).unwrap();
+        assert_eq!(status, Status::StreamEnd);
+        assert_eq!(cursor.position() as usize, expected.len() + 3);
+        assert_eq!(&storage[3..expected.len() + 3], expected);
+
+        // through a reference, in the spare capacity
+        let mut enc = conf.encoder().unwrap();
+        let mut vec = Vec::with_capacity(expected.len() + 10);
+        vec.push(42);
+        let mut by_ref = &mut vec;
+        enc.encode(&data, &mut by_ref, true).unwrap();
+        assert_eq!(vec[0], 42);
+        assert_eq!(&vec[1..], expected);
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes_mut() {
+        let conf = Configuration::new(8, 16, 16, Flags::DATA_PREPROCESS);
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * i / 7) as u8).collect();
+        let mut expected = vec![];
+        conf.encode_buffer(&data, &mut expected).unwrap();
+
+        let mut enc = conf.encoder().unwrap();
+        let mut out = bytes::BytesMut::with_capacity(16);
+        let mut input = &data[..];
+        loop {
+            out.reserve(16);
+            let (rest, _, status) = enc.encode(input, &mut out, true).unwrap();
+            input = rest;
+            if status == Status::StreamEnd {
+                break;
+            }
+        }
+        assert_eq!(&out[..], expected);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::{Configuration, Flags};
+
+/// Largest reference sample interval allowed by the standard.
+pub const MAX_RSI: usize = 4096;
+
+/// Block sizes allowed by the standard, unless
+/// [`Flags::NOT_ENFORCE`] is set.
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64];
+
+/// A parameter that does not follow CCSDS 121.0-B-3.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum ConfigError {
+    /// `bits_per_sample` is outside 1 to 32.
+    BitsPerSample(usize),
+    /// `block_size` is not 8, 16, 32 or 64, or with
+    /// [`Flags::NOT_ENFORCE`], not a positive even number.
+    BlockSize(usize),
+    /// `rsi` is outside 1 to [`MAX_RSI`].
+    Rsi(usize),
+    /// [`Flags::DATA_3BYTE`] was set, but `bits_per_sample` is
+    /// outside 17 to 24.
+    ThreeByte(usize),
+    /// [`Flags::RESTRICTED`] was set, but `bits_per_sample` is
+    /// greater than 4.
+    Restricted(usize),
+}
+
+impl ConfigError {
+    /// The name of the offending parameter.
+    pub fn field(&self) -> &'static str {
+        match self {
+            Self::BitsPerSample(_) => "bits_per_sample",
+            Self::BlockSize(_) => "block_size",
+            Self::Rsi(_) => "rsi",
+            Self::ThreeByte(_) => "flags",
+            Self::Restricted(_) => "flags",
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::BitsPerSample(v) => write!(f, "bits_per_sample is {}, must be 1 to 32", v),
+            Self::BlockSize(v) => write!(
+                f,
+                "block_size is {}, must be 8, 16, 32 or 64 (or even with NOT_ENFORCE)",
+                v
+            ),
+            Self::Rsi(v) => write!(f, "rsi is {}, must be 1 to {}", v, MAX_RSI),
+            Self::ThreeByte(v) => write!(
+                f,
+                "DATA_3BYTE set with bits_per_sample {}, must be 17 to 24",
+                v
+            ),
+            Self::Restricted(v) => write!(
+                f,
+                "RESTRICTED set with bits_per_sample {}, must be 1 to 4",
+                v
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for std::io::Error {
+    fn from(err: ConfigError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
+    }
+}
+
+/// Checks parameters before creating a [`Configuration`].
+///
+/// Created by [`Configuration::builder`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ConfigurationBuilder {
+    bits_per_sample: usize,
+    block_size: usize,
+    rsi: usize,
+    flags: Flags,
+}
+
+impl Default for ConfigurationBuilder {
+    fn default() -> Self {
+        Self {
+            bits_per_sample: 0,
+            block_size: 16,
+            rsi: 128,
+            flags: Flags::empty(),
+        }
+    }
+}
+
+impl ConfigurationBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the sample resolution. This must always be provided.
+    pub fn bits_per_sample(mut self, bits_per_sample: usize) -> Self {
+        self.bits_per_sample = bits_per_sample;
+        self
+    }
+
+    /// Set the block size, in samples. Defaults to 16.
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Set the reference sample interval, in blocks. Defaults to 128.
+    pub fn rsi(mut self, rsi: usize) -> Self {
+        self.rsi = rsi;
+        self
+    }
+
+    /// Set all flags at once. Defaults to no flags.
+    pub fn flags(mut self, flags: Flags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Set or clear some flags, leaving the rest alone.
+    pub fn set(mut self, flags: Flags, value: bool) -> Self {
+        self.flags.set(flags, value);
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        validate(self.bits_per_sample, self.block_size, self.rsi, self.flags)
+    }
+
+    pub fn build(&self) -> Result<Configuration, ConfigError> {
+        self.validate()?;
+        Ok(Configuration::new(
+            self.bits_per_sample,
+            self.block_size,
+            self.rsi,
+            self.flags,
+        ))
+    }
+}
+
+pub(crate) fn validate(
+    bits_per_sample: usize,
+    block_size: usize,
+    rsi: usize,
+    flags: Flags,
+) -> Result<(), ConfigError> {
+    if !(1..=32).contains(&bits_per_sample) {
+        return Err(ConfigError::BitsPerSample(bits_per_sample));
+    }
+    let block_ok = if flags.contains(Flags::NOT_ENFORCE) {
+        block_size > 0 && block_size & 1 == 0 && block_size <= u32::MAX as usize
+    } else {
+        BLOCK_SIZES.contains(&block_size)
+    };
+    if !block_ok {
+        return Err(ConfigError::BlockSize(block_size));
+    }
+    if !(1..=MAX_RSI).contains(&rsi) {
+        return Err(ConfigError::Rsi(rsi));
+    }
+    if flags.contains(Flags::DATA_3BYTE) && !(17..=24).contains(&bits_per_sample) {
+        return Err(ConfigError::ThreeByte(bits_per_sample));
+    }
+    if flags.contains(Flags::RESTRICTED) && bits_per_sample > 4 {
+        return Err(ConfigError::Restricted(bits_per_sample));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConfigError;
+    use crate::{Configuration, Flags};
+
+    #[test]
+    fn build_valid() {
+        let conf = Configuration::builder()
+            .bits_per_sample(24)
+            .block_size(32)
+            .rsi(4096)
+            .flags(Flags::DATA_3BYTE | Flags::DATA_PREPROCESS)
+            .build()
+            .unwrap();
+        assert_eq!(conf.bits_per_sample(), 24);
+        assert_eq!(conf.block_size(), 32);
+        assert_eq!(conf.rsi(), 4096);
+        assert!(conf.validate().is_ok());
+    }
+
+    #[test]
+    fn build_invalid() {
+        let b = Configuration::builder().bits_per_sample(8);
+        assert_eq!(
+            b.clone().bits_per_sample(0).build().unwrap_err(),
+            ConfigError::BitsPerSample(0)
+        );
+        assert_eq!(
+            b.clone().bits_per_sample(33).build().unwrap_err(),
+            ConfigError::BitsPerSample(33)
+        );
+        assert_eq!(
+            b.clone().block_size(12).build().unwrap_err(),
+            ConfigError::BlockSize(12)
+        );
+        assert!(b
+            .clone()
+            .block_size(12)
+            .set(Flags::NOT_ENFORCE, true)
+            .build()
+            .is_ok());
+        assert_eq!(
+            b.clone()
+                .block_size(13)
+                .set(Flags::NOT_ENFORCE, true)
+                .build()
+                .unwrap_err(),
+            ConfigError::BlockSize(13)
+        );
+        assert_eq!(
+            b.clone().rsi(4097).build().unwrap_err(),
+            ConfigError::Rsi(4097)
+        );
+        assert_eq!(b.clone().rsi(0).build().unwrap_err().field(), "rsi");
+        assert_eq!(
+            b.clone().set(Flags::DATA_3BYTE, true).build().unwrap_err(),
+            ConfigError::ThreeByte(8)
+        );
+        assert_eq!(
+            b.set(Flags::RESTRICTED, true).build().unwrap_err(),
+            ConfigError::Restricted(8)
+        );
+    }
+}
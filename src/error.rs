@@ -0,0 +1,217 @@
+use crate::sample;
+use crate::Configuration;
+
+/// The broad category of an [`Error`], as reported by libaec.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    Configuration,
+    Stream,
+    Data,
+    Memory,
+    Offsets,
+    Limit,
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Configuration => write!(f, "bad configuration"),
+            Self::Stream => write!(f, "stream closed before all data written"),
+            Self::Data => write!(f, "unexpected data"),
+            // Memory may also be a poorly-sized output buffer
+            Self::Memory => write!(f, "out of memory"),
+            Self::Offsets => write!(f, "RSI offsets unavailable or invalid"),
+            Self::Limit => write!(f, "decompression limit exceeded"),
+        }
+    }
+}
+
+/// The operation that was running when an [`Error`] occurred.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operation {
+    EncodeInit,
+    Encode,
+    EncodeEnd,
+    DecodeInit,
+    Decode,
+    DecodeEnd,
+    DecodeRange,
+}
+
+impl std::fmt::Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::EncodeInit => write!(f, "encoder init"),
+            Self::Encode => write!(f, "encode"),
+            Self::EncodeEnd => write!(f, "encoder end"),
+            Self::DecodeInit => write!(f, "decoder init"),
+            Self::Decode => write!(f, "decode"),
+            Self::DecodeEnd => write!(f, "decoder end"),
+            Self::DecodeRange => write!(f, "range decode"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct Context {
+    operation: Operation,
+    total_in: usize,
+    total_out: usize,
+    rsi_index: Option<usize>,
+}
+
+/// An error from the encoder or decoder, with information on where
+/// in the stream it happened, if known.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Error {
+    kind: ErrorKind,
+    context: Option<Context>,
+}
+
+impl Error {
+    pub fn new(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            context: None,
+        }
+    }
+
+    /// Attach the current position of a coder to this error.
+    pub(crate) fn at(
+        mut self,
+        operation: Operation,
+        conf: &Configuration,
+        total_in: usize,
+        total_out: usize,
+    ) -> Self {
+        // the uncompressed side of the stream tells us the sample
+        let uncompressed = match operation {
+            Operation::EncodeInit | Operation::Encode | Operation::EncodeEnd => total_in,
+            _ => total_out,
+        };
+        let storage = sample::storage_size(conf.bits_per_sample(), conf.flags());
+        let rsi_samples = conf.rsi() * conf.block_size();
+        let rsi_index = (uncompressed / storage).checked_div(rsi_samples);
+        self.context = Some(Context {
+            operation,
+            total_in,
+            total_out,
+            rsi_index,
+        });
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// The operation that failed, if known.
+    pub fn operation(&self) -> Option<Operation> {
+        self.context.map(|c| c.operation)
+    }
+
+    /// Total bytes read by the coder before the error, if known.
+    pub fn total_in(&self) -> Option<usize> {
+        self.context.map(|c| c.total_in)
+    }
+
+    /// Total bytes written by the coder before the error, if known.
+    pub fn total_out(&self) -> Option<usize> {
+        self.context.map(|c| c.total_out)
+    }
+
+    /// Index of the reference sample interval being processed, if
+    /// known.
+    pub fn rsi_index(&self) -> Option<usize> {
+        self.context.and_then(|c| c.rsi_index)
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self::new(kind)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.kind.fmt(f)?;
+        if let Some(c) = self.context {
+            write!(
+                f,
+                " during {} (input byte {}, output byte {}",
+                c.operation, c.total_in, c.total_out
+            )?;
+            if let Some(rsi) = c.rsi_index {
+                write!(f, ", rsi {}", rsi)?;
+            }
+            write!(f, ")")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> Self {
+        use std::io::ErrorKind as IoKind;
+        let kind = match err.kind {
+            ErrorKind::Configuration => IoKind::InvalidInput,
+            ErrorKind::Stream => IoKind::Other,
+            ErrorKind::Data => IoKind::InvalidData,
+            ErrorKind::Memory => IoKind::Other,
+            ErrorKind::Offsets => IoKind::InvalidInput,
+            ErrorKind::Limit => IoKind::InvalidData,
+        };
+        std::io::Error::new(kind, err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ErrorKind, Operation};
+    use crate::{Configuration, Flags};
+
+    #[test]
+    fn init_error_context() {
+        let conf = Configuration::new(0, 8, 1, Flags::empty());
+        let e = conf.encoder().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Configuration);
+        assert_eq!(e.operation(), Some(Operation::EncodeInit));
+    }
+
+    #[test]
+    fn decode_error_context() {
+        // 256 byte reference sample intervals
+        let conf = Configuration::new(8, 16, 16, Flags::DATA_PREPROCESS);
+        let rsi_bytes = 256;
+        let data: Vec<u8> = (0..4 * rsi_bytes as u32)
+            .map(|i| (i * i * 7 % 251) as u8)
+            .collect();
+        let mut encoded = vec![];
+        conf.encode_buffer(&data, &mut encoded).unwrap();
+        // where the third interval starts, give or take a byte
+        let mut start = vec![];
+        conf.encode_buffer(&data[..2 * rsi_bytes], &mut start)
+            .unwrap();
+        let start = start.len();
+
+        // zeros are a fundamental sequence too long to be valid
+        let mut bad = encoded.clone();
+        for b in &mut bad[start + 4..start + 44] {
+            *b = 0;
+        }
+        let mut dec = conf.decoder().unwrap();
+        let mut out = vec![0; data.len() + 64];
+        let e = dec.decode(&bad, &mut out[..], true).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Data);
+        assert_eq!(e.operation(), Some(Operation::Decode));
+        assert_eq!(e.rsi_index(), Some(2));
+        let total_in = e.total_in().unwrap();
+        // the bad sequence ends just past the zeros
+        assert!(start + 4 < total_in && total_in <= start + 52);
+        let total_out = e.total_out().unwrap();
+        assert!(2 * rsi_bytes <= total_out && total_out < 3 * rsi_bytes);
+    }
+}
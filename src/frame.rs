@@ -0,0 +1,498 @@
+//! A small self-describing container for AEC streams.
+//!
+//! A frame records the [`Configuration`] used to encode it, so it can
+//! be decoded without knowing it in advance. All integers are little
+//! endian.
+//!
+//! | size | contents                                        |
+//! |------|-------------------------------------------------|
+//! | 4    | magic, `b"ACRS"`                                |
+//! | 1    | format version, currently 1                     |
+//! | 1    | options, bit 0 set if a checksum is present     |
+//! | 1    | bits per sample                                 |
+//! | 1    | [`Flags`]                                       |
+//! | 2    | block size                                      |
+//! | 2    | reference sample interval                       |
+//! | ...  | payload chunks, each a `u32` length and data    |
+//! | 4    | zero, ending the payload                        |
+//! | 8    | number of uncompressed samples                  |
+//! | 4    | CRC-32 of the uncompressed data, if present     |
+//!
+//! The payload is a single AEC stream, split into chunks so it can be
+//! written without knowing its length first.
+
+use crate::{sample, Configuration, Decoder, Encoder, Flags, Limits, Reader, Writer};
+
+use std::convert::{TryFrom, TryInto};
+use std::io;
+use std::io::{BufRead, Read, Write};
+
+const MAGIC: &[u8; 4] = b"ACRS";
+const VERSION: u8 = 1;
+const OPTION_CHECKSUM: u8 = 1;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut b = [0; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+/// Writes payload chunks, one per write.
+#[derive(Debug)]
+struct ChunkWriter<W> {
+    inner: W,
+}
+
+impl<W: Write> Write for ChunkWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // an empty chunk would end the payload
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = buf.len().min(u32::MAX as usize);
+        self.inner.write_all(&(len as u32).to_le_bytes())?;
+        self.inner.write_all(&buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Encodes data into a frame.
+///
+/// The frame is only complete once [`FrameWriter::finish`] is called.
+#[derive(Debug)]
+pub struct FrameWriter<W: Write> {
+    writer: Writer<Encoder, ChunkWriter<W>>,
+    storage: usize,
+    // uncompressed bytes written
+    total: u64,
+    checksum: Option<crc32fast::Hasher>,
+}
+
+impl<W: Write> FrameWriter<W> {
+    /// Write a frame header for `conf` to `inner`, optionally
+    /// followed by a checksum of the uncompressed data.
+    pub fn new(conf: &Configuration, mut inner: W, checksum: bool) -> io::Result<Self> {
+        if conf.prediction_width().is_some() {
+            // the header has nowhere to record a predictor
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let encoder = conf.encoder()?;
+        let bits = conf.bits_per_sample() as u8;
+        let block_size = u16::try_from(conf.block_size())
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let rsi =
+            u16::try_from(conf.rsi()).map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+
+        let mut header = [0; 12];
+        header[..4].copy_from_slice(MAGIC);
+        header[4] = VERSION;
+        header[5] = if checksum { OPTION_CHECKSUM } else { 0 };
+        header[6] = bits;
+        header[7] = conf.flags().bits() as u8;
+        header[8..10].copy_from_slice(&block_size.to_le_bytes());
+        header[10..12].copy_from_slice(&rsi.to_le_bytes());
+        inner.write_all(&header)?;
+
+        Ok(Self {
+            writer: Writer::new(encoder, ChunkWriter { inner }),
+            storage: sample::storage_size(conf.bits_per_sample(), conf.flags()),
+            total: 0,
+            checksum: if checksum {
+                Some(crc32fast::Hasher::new())
+            } else {
+                None
+            },
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer.get_ref().inner
+    }
+
+    /// End the payload and write the trailer, returning the inner
+    /// writer.
+    pub fn finish(self) -> io::Result<W> {
+        let count = self.total / self.storage as u64;
+        let mut inner = self.writer.finish()?.inner;
+        inner.write_all(&0u32.to_le_bytes())?;
+        inner.write_all(&count.to_le_bytes())?;
+        if let Some(hasher) = self.checksum {
+            inner.write_all(&hasher.finalize().to_le_bytes())?;
+        }
+        inner.flush()?;
+        Ok(inner)
+    }
+}
+
+impl<W: Write> Write for FrameWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // the writer holds on to any partial sample itself
+        let n = self.writer.write(buf)?;
+        self.total += n as u64;
+        if let Some(hasher) = &mut self.checksum {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    /// This flushes the inner writer, but does not end the frame.
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// The end of a frame.
+#[derive(Clone, Copy, Debug)]
+struct Trailer {
+    count: u64,
+    checksum: Option<u32>,
+}
+
+/// Reads payload chunks, always looking one chunk ahead so the
+/// trailer is known before the last of the payload is used.
+#[derive(Debug)]
+struct ChunkReader<R> {
+    inner: R,
+    checksum: bool,
+    buf: Vec<u8>,
+    pos: usize,
+    // bytes of the current chunk not yet read into buf
+    left: u32,
+    // length of the next chunk, or 0 at the end
+    next: u32,
+    trailer: Option<Trailer>,
+}
+
+impl<R: Read> ChunkReader<R> {
+    fn new(mut inner: R, checksum: bool) -> io::Result<Self> {
+        let next = read_u32(&mut inner)?;
+        let mut r = Self {
+            inner,
+            checksum,
+            buf: vec![],
+            pos: 0,
+            left: 0,
+            next,
+            trailer: None,
+        };
+        r.check_end()?;
+        Ok(r)
+    }
+
+    fn check_end(&mut self) -> io::Result<()> {
+        if self.next == 0 && self.trailer.is_none() {
+            let count = read_u64(&mut self.inner)?;
+            let checksum = if self.checksum {
+                Some(read_u32(&mut self.inner)?)
+            } else {
+                None
+            };
+            self.trailer = Some(Trailer { count, checksum });
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ChunkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = {
+            let mut available = self.fill_buf()?;
+            available.read(buf)?
+        };
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read> BufRead for ChunkReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() && (self.left != 0 || self.next != 0) {
+            if self.left == 0 {
+                self.left = self.next;
+            }
+            // the length is untrusted, so read big chunks in pieces
+            let n = (self.left as usize).min(crate::DEFAULT_BUFFER_SIZE);
+            self.buf.resize(n, 0);
+            self.inner.read_exact(&mut self.buf)?;
+            self.pos = 0;
+            self.left -= n as u32;
+            if self.left == 0 {
+                self.next = read_u32(&mut self.inner)?;
+                self.check_end()?;
+            }
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.buf.len());
+    }
+}
+
+/// Decodes a frame, using the configuration stored in it.
+///
+/// Reading fails with [`io::ErrorKind::InvalidData`] if the sample
+/// count or checksum in the frame do not match the decoded data.
+#[derive(Debug)]
+pub struct FrameReader<R: Read> {
+    reader: Reader<Decoder, ChunkReader<R>>,
+    conf: Configuration,
+    // uncompressed bytes read
+    total: u64,
+    checksum: Option<crc32fast::Hasher>,
+    verified: bool,
+}
+
+impl<R: Read> FrameReader<R> {
+    /// Read a frame header from `inner`.
+    pub fn new(inner: R) -> io::Result<Self> {
+        Self::with_limits(inner, Limits::default())
+    }
+
+    /// Read a frame header from `inner`, and decode it within
+    /// `limits`.
+    pub fn with_limits(mut inner: R, limits: Limits) -> io::Result<Self> {
+        let mut header = [0; 12];
+        inner.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(invalid("not an acres frame"));
+        }
+        if header[4] != VERSION {
+            return Err(invalid("unsupported frame version"));
+        }
+        let checksum = header[5] & OPTION_CHECKSUM != 0;
+        let flags = Flags::from_bits(header[7] as u32).ok_or_else(|| invalid("bad frame flags"))?;
+        let conf = Configuration::new(
+            header[6] as usize,
+            u16::from_le_bytes([header[8], header[9]]) as usize,
+            u16::from_le_bytes([header[10], header[11]]) as usize,
+            flags,
+        )
+        .with_limits(limits);
+        let decoder = conf.decoder()?;
+
+        Ok(Self {
+            reader: Reader::new_unbuffered(decoder, ChunkReader::new(inner, checksum)?),
+            conf,
+            total: 0,
+            checksum: if checksum {
+                Some(crc32fast::Hasher::new())
+            } else {
+                None
+            },
+            verified: false,
+        })
+    }
+
+    /// The configuration the frame was encoded with.
+    pub fn configuration(&self) -> &Configuration {
+        &self.conf
+    }
+
+    /// The number of samples in the frame, once the end of the
+    /// payload has been reached.
+    pub fn sample_count(&self) -> Option<u64> {
+        self.reader.get_ref().trailer.map(|t| t.count)
+    }
+
+    fn storage(&self) -> u64 {
+        sample::storage_size(self.conf.bits_per_sample(), self.conf.flags()) as u64
+    }
+
+    /// Uncompressed bytes left to read, once known.
+    fn remaining(&self) -> Option<u64> {
+        let count = self.sample_count()?;
+        Some((count * self.storage()).saturating_sub(self.total))
+    }
+
+    fn verify(&mut self) -> io::Result<()> {
+        if self.verified {
+            return Ok(());
+        }
+        let trailer = self.reader.get_ref().trailer;
+        let trailer = trailer.ok_or_else(|| invalid("frame payload ended early"))?;
+        if self.total != trailer.count * self.storage() {
+            return Err(invalid("frame sample count does not match"));
+        }
+        if let (Some(hasher), Some(expected)) = (self.checksum.take(), trailer.checksum) {
+            if hasher.finalize() != expected {
+                return Err(invalid("frame checksum does not match"));
+            }
+        }
+        self.verified = true;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for FrameReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining() == Some(0) {
+            self.verify()?;
+            return Ok(0);
+        }
+        let mut n = self.reader.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            self.verify()?;
+            return Ok(0);
+        }
+        // padding at the end of the stream may decode as extra
+        // samples, so stop at the sample count
+        if let Some(remaining) = self.remaining() {
+            n = n.min(remaining.try_into().unwrap_or(usize::MAX));
+        }
+        self.total += n as u64;
+        if let Some(hasher) = &mut self.checksum {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FrameReader, FrameWriter};
+    use crate::{Configuration, ErrorKind, Flags, Limits};
+    use std::io::{Read, Write};
+
+    fn data() -> Vec<u8> {
+        (0..3000u32)
+            .flat_map(|i| ((i * 7 % 1000) as u16).to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let data = data();
+        for &checksum in &[true, false] {
+            let conf = Configuration::new(12, 16, 32, Flags::DATA_PREPROCESS);
+            let mut writer = FrameWriter::new(&conf, vec![], checksum).unwrap();
+            for piece in data.chunks(1001) {
+                writer.write_all(piece).unwrap();
+            }
+            let frame = writer.finish().unwrap();
+
+            let mut reader = FrameReader::new(&frame[..]).unwrap();
+            assert_eq!(reader.configuration(), &conf);
+            let mut decoded = vec![];
+            reader.read_to_end(&mut decoded).unwrap();
+            assert_eq!(decoded, data);
+            assert_eq!(reader.sample_count(), Some(3000));
+        }
+    }
+
+    #[test]
+    fn partial_samples() {
+        let data = data();
+        let conf = Configuration::new(16, 16, 32, Flags::empty());
+        let mut writer = FrameWriter::new(&conf, vec![], true).unwrap();
+        for byte in data.chunks(1) {
+            writer.write_all(byte).unwrap();
+        }
+        let frame = writer.finish().unwrap();
+        let mut decoded = vec![];
+        FrameReader::new(&frame[..])
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+
+        let mut writer = FrameWriter::new(&conf, vec![], true).unwrap();
+        writer.write_all(&data[..5]).unwrap();
+        let err = writer.finish().unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn corrupt() {
+        let conf = Configuration::new(16, 16, 32, Flags::DATA_PREPROCESS);
+        let mut writer = FrameWriter::new(&conf, vec![], true).unwrap();
+        writer.write_all(&data()).unwrap();
+        let frame = writer.finish().unwrap();
+
+        // wrong checksum
+        let mut bad = frame.clone();
+        *bad.last_mut().unwrap() ^= 1;
+        let mut reader = FrameReader::new(&bad[..]).unwrap();
+        assert!(reader.read_to_end(&mut vec![]).is_err());
+
+        // truncated
+        let mut reader = FrameReader::new(&frame[..frame.len() - 20]).unwrap();
+        assert!(reader.read_to_end(&mut vec![]).is_err());
+
+        // not a frame
+        assert!(FrameReader::new(&b"ACRZ and more"[..]).is_err());
+
+        // a huge chunk length is read until the data runs out
+        let mut bad = frame[..12].to_vec();
+        bad.extend_from_slice(&u32::MAX.to_le_bytes());
+        bad.extend_from_slice(&frame[16..100]);
+        let mut reader = FrameReader::new(&bad[..]).unwrap();
+        let err = reader.read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn limits() {
+        let conf = Configuration::new(16, 16, 32, Flags::DATA_PREPROCESS);
+        let mut writer = FrameWriter::new(&conf, vec![], false).unwrap();
+        writer.write_all(&vec![0; 1 << 16]).unwrap();
+        let frame = writer.finish().unwrap();
+
+        let limits = Limits {
+            max_output: Some(1000),
+            max_ratio: None,
+        };
+        let mut reader = FrameReader::with_limits(&frame[..], limits).unwrap();
+        assert_eq!(reader.configuration().limits(), limits);
+        let err = reader.read_to_end(&mut vec![]).unwrap_err();
+        let err = err
+            .into_inner()
+            .unwrap()
+            .downcast::<crate::Error>()
+            .unwrap();
+        assert_eq!(err.kind(), ErrorKind::Limit);
+    }
+
+    #[test]
+    fn big_chunk() {
+        // one chunk, longer than is read at once
+        let conf = Configuration::new(16, 16, 32, Flags::empty());
+        let data: Vec<u8> = (0..20000u32)
+            .flat_map(|i| ((i * i) as u16).to_le_bytes())
+            .collect();
+        let mut payload = vec![];
+        conf.encode_buffer(&data, &mut payload).unwrap();
+        assert!(payload.len() > crate::DEFAULT_BUFFER_SIZE);
+
+        let mut frame = FrameWriter::new(&conf, vec![], false)
+            .unwrap()
+            .finish()
+            .unwrap();
+        frame.truncate(12);
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&payload);
+        frame.extend_from_slice(&0u32.to_le_bytes());
+        frame.extend_from_slice(&20000u64.to_le_bytes());
+
+        let mut decoded = vec![];
+        FrameReader::new(&frame[..])
+            .unwrap()
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, data);
+    }
+}
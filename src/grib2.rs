@@ -0,0 +1,626 @@
+//! GRIB2 data representation template 5.42, CCSDS compression.
+//!
+//! Template 5.42 stores a field as simple-packed integers, compressed
+//! with AEC. Packed values `X` unpack to `Y = (R + X * 2^E) / 10^D`,
+//! where `R` is the reference value, and `E` and `D` are the binary
+//! and decimal scale factors.
+//!
+//! A [`Template`] holds the section 5 fields, and decodes the section
+//! 7 payload. Points that are missing in a section 6 bitmap unpack to
+//! NaN.
+//!
+//! To write a field, [`encode_f64`] or [`encode_f32`] choose the
+//! packing parameters for a given [`Precision`], and produce sections
+//! 5 and 7 of a message.
+
+use crate::{Configuration, Error, ErrorKind, Flags};
+
+use std::convert::{TryFrom, TryInto};
+
+/// The length of template 5.42, octets 12 to 25 of section 5.
+pub const TEMPLATE_SIZE: usize = 14;
+
+/// The data representation template number for CCSDS compression.
+pub const TEMPLATE_NUMBER: u16 = 42;
+
+/// Block size used by [`encode_f64`] and [`encode_f32`].
+pub const DEFAULT_BLOCK_SIZE: u8 = 32;
+
+/// Reference sample interval used by [`encode_f64`] and
+/// [`encode_f32`].
+pub const DEFAULT_RSI: u16 = 128;
+
+/// The fields of data representation template 5.42.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Template {
+    /// Reference value `R`, the minimum of the field.
+    pub reference_value: f32,
+    /// Binary scale factor `E`.
+    pub binary_scale: i16,
+    /// Decimal scale factor `D`.
+    pub decimal_scale: i16,
+    /// Number of bits per packed value, or zero for a constant field.
+    pub bits_per_value: u8,
+    /// Type of original field values, 0 for floating point and 1 for
+    /// integer.
+    pub original_type: u8,
+    /// CCSDS compression options, as libaec [`Flags`].
+    pub flags: Flags,
+    /// CCSDS block size.
+    pub block_size: u8,
+    /// CCSDS reference sample interval.
+    pub rsi: u16,
+}
+
+// GRIB2 stores negative integers as sign and magnitude
+fn signed(bytes: [u8; 2]) -> i16 {
+    let v = u16::from_be_bytes(bytes);
+    let magnitude = (v & 0x7fff) as i16;
+    if v & 0x8000 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+fn to_signed(v: i16) -> [u8; 2] {
+    let magnitude = v.unsigned_abs() & 0x7fff;
+    let sign = if v < 0 { 0x8000 } else { 0 };
+    (sign | magnitude).to_be_bytes()
+}
+
+impl Template {
+    /// Read the template from `bytes`, which holds octets 12 to 25 of
+    /// section 5.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < TEMPLATE_SIZE {
+            return Err(ErrorKind::Data.into());
+        }
+        Ok(Self {
+            reference_value: f32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            binary_scale: signed([bytes[4], bytes[5]]),
+            decimal_scale: signed([bytes[6], bytes[7]]),
+            bits_per_value: bytes[8],
+            original_type: bytes[9],
+            flags: Flags::from_bits_truncate(bytes[10] as u32),
+            block_size: bytes[11],
+            rsi: u16::from_be_bytes([bytes[12], bytes[13]]),
+        })
+    }
+
+    /// Write the template as octets 12 to 25 of section 5.
+    pub fn to_bytes(&self) -> [u8; TEMPLATE_SIZE] {
+        let mut bytes = [0; TEMPLATE_SIZE];
+        bytes[0..4].copy_from_slice(&self.reference_value.to_be_bytes());
+        bytes[4..6].copy_from_slice(&to_signed(self.binary_scale));
+        bytes[6..8].copy_from_slice(&to_signed(self.decimal_scale));
+        bytes[8] = self.bits_per_value;
+        bytes[9] = self.original_type;
+        bytes[10] = self.flags.bits() as u8;
+        bytes[11] = self.block_size;
+        bytes[12..14].copy_from_slice(&self.rsi.to_be_bytes());
+        bytes
+    }
+
+    /// The configuration used for the section 7 payload.
+    pub fn configuration(&self) -> Configuration {
+        Configuration::new(
+            self.bits_per_value as usize,
+            self.block_size as usize,
+            self.rsi as usize,
+            self.flags,
+        )
+    }
+
+    /// Unpack `count` values from a section 7 `payload`.
+    ///
+    /// If a section 6 `bitmap` is given, the result has one value for
+    /// each of its grid points, and `count` must be the number of
+    /// points present.
+    pub fn decode_f64(
+        &self,
+        payload: &[u8],
+        count: usize,
+        bitmap: Option<Bitmap>,
+    ) -> Result<Vec<f64>, Error> {
+        self.decode(payload, count, bitmap, |y| y, f64::NAN)
+    }
+
+    /// Unpack `count` values from a section 7 `payload`, as `f32`.
+    ///
+    /// See [`Template::decode_f64`].
+    pub fn decode_f32(
+        &self,
+        payload: &[u8],
+        count: usize,
+        bitmap: Option<Bitmap>,
+    ) -> Result<Vec<f32>, Error> {
+        self.decode(payload, count, bitmap, |y| y as f32, f32::NAN)
+    }
+
+    fn decode<T, F>(
+        &self,
+        payload: &[u8],
+        count: usize,
+        bitmap: Option<Bitmap>,
+        convert: F,
+        missing: T,
+    ) -> Result<Vec<T>, Error>
+    where
+        T: Copy,
+        F: Fn(f64) -> T,
+    {
+        if let Some(ref bitmap) = bitmap {
+            if bitmap.present() != count {
+                return Err(ErrorKind::Data.into());
+            }
+        }
+
+        let reference = self.reference_value as f64;
+        let binary = 2f64.powi(self.binary_scale as i32);
+        let decimal = 10f64.powi(-(self.decimal_scale as i32));
+        let unpack = |x: u32| convert((reference + x as f64 * binary) * decimal);
+
+        let values = if self.bits_per_value == 0 {
+            // a constant field has no payload
+            vec![unpack(0); count]
+        } else {
+            let mut packed = vec![0u32; count];
+            let decoded = self
+                .configuration()
+                .decode_samples(payload, &mut packed)?
+                .len();
+            if decoded != count {
+                return Err(ErrorKind::Data.into());
+            }
+            packed.into_iter().map(unpack).collect()
+        };
+
+        Ok(match bitmap {
+            Some(bitmap) => bitmap.expand(&values, missing),
+            None => values,
+        })
+    }
+}
+
+/// How precisely [`encode_f64`] and [`encode_f32`] keep values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precision {
+    /// Keep this many digits after the decimal point, setting the
+    /// decimal scale factor directly.
+    Decimal(i16),
+    /// Keep this many significant digits of the largest magnitude
+    /// in the field, choosing the decimal scale factor to match.
+    Significant(u8),
+    /// Pack into this many bits per value, choosing the binary scale
+    /// factor to cover the range of the field.
+    Bits(u8),
+}
+
+/// A packed field, ready to be written as sections 5 and 7.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Packed {
+    /// The section 5 template.
+    pub template: Template,
+    /// The number of packed values.
+    pub count: usize,
+    /// The section 7 payload.
+    pub payload: Vec<u8>,
+}
+
+impl Packed {
+    /// The complete section 5, data representation.
+    pub fn section5(&self) -> Result<Vec<u8>, Error> {
+        let count = u32::try_from(self.count).map_err(|_| ErrorKind::Configuration)?;
+        let mut section = Vec::with_capacity(11 + TEMPLATE_SIZE);
+        section.extend_from_slice(&((11 + TEMPLATE_SIZE) as u32).to_be_bytes());
+        section.push(5);
+        section.extend_from_slice(&count.to_be_bytes());
+        section.extend_from_slice(&TEMPLATE_NUMBER.to_be_bytes());
+        section.extend_from_slice(&self.template.to_bytes());
+        Ok(section)
+    }
+
+    /// The complete section 7, data.
+    pub fn section7(&self) -> Result<Vec<u8>, Error> {
+        let len = u32::try_from(5 + self.payload.len()).map_err(|_| ErrorKind::Configuration)?;
+        let mut section = Vec::with_capacity(len as usize);
+        section.extend_from_slice(&len.to_be_bytes());
+        section.push(7);
+        section.extend_from_slice(&self.payload);
+        Ok(section)
+    }
+}
+
+// the largest f32 no greater than x
+fn floor_f32(x: f64) -> f32 {
+    let r = x as f32;
+    if r as f64 <= x {
+        r
+    } else if r > 0.0 {
+        f32::from_bits(r.to_bits() - 1)
+    } else if r == 0.0 {
+        -f32::from_bits(1)
+    } else {
+        f32::from_bits(r.to_bits() + 1)
+    }
+}
+
+// the number of bits needed to hold x
+fn bits_for(x: u64) -> u32 {
+    64 - x.leading_zeros()
+}
+
+/// Pack `values` with CCSDS compression.
+///
+/// The reference value is the minimum of the field, and the packed
+/// values use as few bits as `precision` allows. All values must be
+/// finite.
+pub fn encode_f64(values: &[f64], precision: Precision) -> Result<Packed, Error> {
+    if values.iter().any(|v| !v.is_finite()) {
+        return Err(ErrorKind::Data.into());
+    }
+    if values.is_empty() {
+        return pack(values, 0.0, 0, 0, 0);
+    }
+    let (min, max) = values
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+            (lo.min(v), hi.max(v))
+        });
+
+    let decimal_scale = match precision {
+        Precision::Decimal(d) => d,
+        Precision::Bits(_) => 0,
+        Precision::Significant(digits) => {
+            let largest = min.abs().max(max.abs());
+            if largest == 0.0 {
+                0
+            } else {
+                let d = digits as f64 - 1.0 - largest.log10().floor();
+                d.max(i16::MIN as f64 + 1.0).min(i16::MAX as f64) as i16
+            }
+        }
+    };
+    let decimal = 10f64.powi(decimal_scale as i32);
+    let reference = floor_f32(min * decimal);
+    let range = max * decimal - reference as f64;
+
+    let (binary_scale, bits) = match precision {
+        Precision::Bits(bits) => {
+            if bits == 0 || bits > 32 {
+                return Err(ErrorKind::Configuration.into());
+            }
+            if range == 0.0 {
+                (0, 0)
+            } else {
+                // the smallest E with range / 2^E at most 2^bits - 1,
+                // correcting for rounding in log2
+                let top = ((1u64 << bits) - 1) as f64;
+                let mut e = (range / top).log2().ceil() as i32;
+                while range * 2f64.powi(-e) > top {
+                    e += 1;
+                }
+                while range * 2f64.powi(1 - e) <= top {
+                    e -= 1;
+                }
+                let e = i16::try_from(e).map_err(|_| ErrorKind::Configuration)?;
+                let needed = bits_for((range * 2f64.powi(-(e as i32))).round() as u64);
+                (e, needed)
+            }
+        }
+        _ => (0, bits_for(range.round() as u64)),
+    };
+    if bits > 32 {
+        return Err(ErrorKind::Configuration.into());
+    }
+
+    let scaled: Vec<f64> = values.iter().map(|v| v * decimal).collect();
+    pack(&scaled, reference, binary_scale, decimal_scale, bits as u8)
+}
+
+/// Pack `values` with CCSDS compression.
+///
+/// See [`encode_f64`].
+pub fn encode_f32(values: &[f32], precision: Precision) -> Result<Packed, Error> {
+    let values: Vec<f64> = values.iter().map(|&v| v as f64).collect();
+    encode_f64(&values, precision)
+}
+
+// pack values already multiplied by 10^D
+fn pack(
+    scaled: &[f64],
+    reference_value: f32,
+    binary_scale: i16,
+    decimal_scale: i16,
+    bits_per_value: u8,
+) -> Result<Packed, Error> {
+    let mut flags = Flags::DATA_MSB | Flags::DATA_PREPROCESS;
+    if bits_per_value > 16 && bits_per_value <= 24 {
+        flags |= Flags::DATA_3BYTE;
+    }
+    let template = Template {
+        reference_value,
+        binary_scale,
+        decimal_scale,
+        bits_per_value,
+        original_type: 0,
+        flags,
+        block_size: DEFAULT_BLOCK_SIZE,
+        rsi: DEFAULT_RSI,
+    };
+
+    let mut payload = vec![];
+    if bits_per_value > 0 {
+        let binary = 2f64.powi(-(binary_scale as i32));
+        let top = (1u64 << bits_per_value) - 1;
+        let packed: Vec<u32> = scaled
+            .iter()
+            .map(|v| (((v - reference_value as f64) * binary).round() as u64).min(top) as u32)
+            .collect();
+        template
+            .configuration()
+            .encode_samples(&packed, &mut payload)?;
+    }
+
+    Ok(Packed {
+        template,
+        count: scaled.len(),
+        payload,
+    })
+}
+
+/// A section 6 bitmap, with one bit set for each grid point that has
+/// a value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bitmap<'a> {
+    bits: &'a [u8],
+    points: usize,
+}
+
+impl<'a> Bitmap<'a> {
+    /// A bitmap over `points` grid points, most significant bit first.
+    pub fn new(bits: &'a [u8], points: usize) -> Result<Self, Error> {
+        if bits.len() < points.div_ceil(8) {
+            return Err(ErrorKind::Data.into());
+        }
+        Ok(Self { bits, points })
+    }
+
+    /// The number of grid points covered.
+    pub fn points(&self) -> usize {
+        self.points
+    }
+
+    /// Whether grid point `i` has a value.
+    pub fn get(&self, i: usize) -> bool {
+        i < self.points && self.bits[i / 8] & (0x80 >> (i % 8)) != 0
+    }
+
+    /// The number of grid points with a value.
+    pub fn present(&self) -> usize {
+        (0..self.points).filter(|&i| self.get(i)).count()
+    }
+
+    fn expand<T: Copy>(&self, values: &[T], missing: T) -> Vec<T> {
+        let mut values = values.iter();
+        (0..self.points)
+            .map(|i| {
+                if self.get(i) {
+                    *values.next().unwrap()
+                } else {
+                    missing
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode_f32, encode_f64, Bitmap, Precision, Template};
+    use crate::{Configuration, ErrorKind, Flags};
+
+    fn template(bits_per_value: u8) -> Template {
+        Template {
+            reference_value: 1.5,
+            binary_scale: -1,
+            decimal_scale: 1,
+            bits_per_value,
+            original_type: 0,
+            flags: Flags::DATA_MSB | Flags::DATA_PREPROCESS,
+            block_size: 16,
+            rsi: 32,
+        }
+    }
+
+    fn pack(t: &Template, packed: &[u32]) -> Vec<u8> {
+        let mut payload = vec![];
+        t.configuration()
+            .encode_samples(packed, &mut payload)
+            .unwrap();
+        payload
+    }
+
+    #[test]
+    fn from_bytes() {
+        let bytes = [
+            0x3f, 0xc0, 0x00, 0x00, 0x80, 0x01, 0x00, 0x01, 12, 0, 14, 32, 0x00, 0x80,
+        ];
+        let t = Template::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            t,
+            Template {
+                reference_value: 1.5,
+                binary_scale: -1,
+                decimal_scale: 1,
+                bits_per_value: 12,
+                original_type: 0,
+                flags: Flags::DATA_3BYTE | Flags::DATA_MSB | Flags::DATA_PREPROCESS,
+                block_size: 32,
+                rsi: 128,
+            }
+        );
+        let e = Template::from_bytes(&bytes[..10]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Data);
+    }
+
+    #[test]
+    fn decode() {
+        let packed: Vec<u32> = (0..1000).map(|i| i * 7 % 61).collect();
+        for &bits in &[6, 12, 20] {
+            let t = template(bits);
+            let payload = pack(&t, &packed);
+            let values = t.decode_f64(&payload, packed.len(), None).unwrap();
+            let singles = t.decode_f32(&payload, packed.len(), None).unwrap();
+            for ((&x, &y), &s) in packed.iter().zip(&values).zip(&singles) {
+                let expected = (1.5 + x as f64 * 0.5) / 10.0;
+                assert!((y - expected).abs() < 1e-12);
+                assert!((s - expected as f32).abs() < 1e-6);
+            }
+        }
+
+        // too few values in the payload
+        let t = template(8);
+        let payload = pack(&t, &packed);
+        assert!(t.decode_f64(&payload, packed.len() + 100, None).is_err());
+    }
+
+    #[test]
+    fn decode_constant() {
+        let t = template(0);
+        let values = t.decode_f32(&[], 3, None).unwrap();
+        assert_eq!(values.len(), 3);
+        assert!(values.iter().all(|v| (v - 0.15).abs() < 1e-6));
+    }
+
+    #[test]
+    fn decode_bitmap() {
+        let t = template(8);
+        let payload = pack(&t, &[0, 1, 2, 3]);
+        let bits = [0b1011_0000, 0b0100_0000];
+        let bitmap = Bitmap::new(&bits, 10).unwrap();
+        assert_eq!(bitmap.present(), 4);
+        let values = t.decode_f64(&payload, 4, Some(bitmap)).unwrap();
+        assert_eq!(values.len(), 10);
+        let present: Vec<_> = values.iter().map(|v| !v.is_nan()).collect();
+        assert_eq!(
+            present,
+            [true, false, true, true, false, false, false, false, false, true]
+        );
+        assert!((values[9] - 0.3).abs() < 1e-12);
+
+        assert!(Bitmap::new(&bits, 17).is_err());
+        let e = t.decode_f64(&payload, 3, Some(bitmap)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Data);
+    }
+
+    #[test]
+    fn configuration() {
+        let t = template(16);
+        assert_eq!(
+            t.configuration(),
+            Configuration::new(16, 16, 32, Flags::DATA_MSB | Flags::DATA_PREPROCESS)
+        );
+    }
+
+    #[test]
+    fn to_bytes() {
+        let mut t = template(12);
+        t.binary_scale = -3;
+        t.decimal_scale = 2;
+        t.rsi = 300;
+        assert_eq!(Template::from_bytes(&t.to_bytes()).unwrap(), t);
+        assert_eq!(&t.to_bytes()[4..8], &[0x80, 0x03, 0x00, 0x02]);
+    }
+
+    fn field() -> Vec<f64> {
+        (0..5000)
+            .map(|i| 273.15 + 20.0 * ((i as f64) * 0.01).sin() - (i % 7) as f64 * 0.125)
+            .collect()
+    }
+
+    fn max_error(a: &[f64], b: &[f64]) -> f64 {
+        assert_eq!(a.len(), b.len());
+        a.iter()
+            .zip(b)
+            .map(|(x, y)| (x - y).abs())
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn encode_decimal() {
+        let data = field();
+        let packed = encode_f64(&data, Precision::Decimal(2)).unwrap();
+        let t = packed.template;
+        assert_eq!(t.decimal_scale, 2);
+        assert_eq!(t.binary_scale, 0);
+        assert_eq!(t.bits_per_value, 12);
+        assert!(t.flags.contains(Flags::DATA_MSB | Flags::DATA_PREPROCESS));
+        assert!(packed.payload.len() < data.len() * 2);
+
+        let decoded = t.decode_f64(&packed.payload, packed.count, None).unwrap();
+        assert!(max_error(&data, &decoded) <= 0.005 + 1e-9);
+    }
+
+    #[test]
+    fn encode_significant() {
+        let data = field();
+        let packed = encode_f64(&data, Precision::Significant(4)).unwrap();
+        assert_eq!(packed.template.decimal_scale, 1);
+        let decoded = packed
+            .template
+            .decode_f64(&packed.payload, packed.count, None)
+            .unwrap();
+        assert!(max_error(&data, &decoded) <= 0.05 + 1e-9);
+    }
+
+    #[test]
+    fn encode_bits() {
+        let data = field();
+        let range = data.iter().cloned().fold(f64::NEG_INFINITY, f64::max)
+            - data.iter().cloned().fold(f64::INFINITY, f64::min);
+        for &bits in &[8, 16, 20, 24] {
+            let packed = encode_f64(&data, Precision::Bits(bits)).unwrap();
+            let t = packed.template;
+            assert_eq!(t.decimal_scale, 0);
+            assert_eq!(t.bits_per_value, bits);
+            assert_eq!(t.flags.contains(Flags::DATA_3BYTE), bits > 16);
+
+            let decoded = t.decode_f64(&packed.payload, packed.count, None).unwrap();
+            let step = 2f64.powi(t.binary_scale as i32);
+            assert!(max_error(&data, &decoded) <= step / 2.0 + 1e-9);
+            // one less bit would not have covered the range
+            assert!(range / step > ((1u64 << (bits - 1)) - 1) as f64);
+        }
+    }
+
+    #[test]
+    fn encode_constant() {
+        let packed = encode_f32(&[2.5; 100], Precision::Bits(16)).unwrap();
+        assert_eq!(packed.template.bits_per_value, 0);
+        assert!(packed.payload.is_empty());
+        let decoded = packed.template.decode_f32(&[], 100, None).unwrap();
+        assert_eq!(decoded, vec![2.5; 100]);
+
+        let e = encode_f64(&[1.0, f64::NAN], Precision::Decimal(0)).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Data);
+    }
+
+    #[test]
+    fn sections() {
+        let packed = encode_f64(&[1.0, 2.0, 3.0, 4.0], Precision::Decimal(0)).unwrap();
+        let section5 = packed.section5().unwrap();
+        assert_eq!(section5.len(), 25);
+        assert_eq!(&section5[..11], &[0, 0, 0, 25, 5, 0, 0, 0, 4, 0, 42]);
+        assert_eq!(
+            Template::from_bytes(&section5[11..]).unwrap(),
+            packed.template
+        );
+
+        let section7 = packed.section7().unwrap();
+        assert_eq!(section7.len(), 5 + packed.payload.len());
+        assert_eq!(section7[4], 7);
+        assert_eq!(&section7[5..], &packed.payload[..]);
+    }
+}
@@ -1,4 +1,4 @@
-use crate::{Decoder, Encoder};
+use crate::{Decoder, Encoder, ErrorKind, Operation, Status};
 
 use std::io;
 
@@ -6,6 +6,8 @@ use std::io;
 pub struct Reader<EncDec, T> {
     encdec: EncDec,
     inner: T,
+    // a sample split across reads of the inner reader
+    partial: Vec<u8>,
 }
 
 impl<EncDec, T> Reader<EncDec, T> {
@@ -20,7 +22,11 @@ impl<EncDec, T> Reader<EncDec, T> {
     }
 
     pub fn new_unbuffered(encdec: EncDec, inner: T) -> Self {
-        Self { encdec, inner }
+        Self {
+            encdec,
+            inner,
+            partial: vec![],
+        }
     }
 
     pub fn into_inner(self) -> T {
@@ -36,36 +42,142 @@ impl<EncDec, T> Reader<EncDec, T> {
     }
 }
 
+/// Encodes or decodes data written to it, passing the output on to
+/// an inner writer.
+///
+/// The stream is only complete once [`Writer::finish`] is called.
+/// Flushing writes out the output so far, but does not end the
+/// stream. Dropping the writer tries to finish, ignoring errors.
 #[derive(Clone, Debug)]
 pub struct Writer<EncDec, T> {
     encdec: EncDec,
     buffer: Vec<u8>,
-    inner: T,
+    // a sample split across writes
+    partial: Vec<u8>,
+    // only None once taken by into_inner or finish
+    inner: Option<T>,
+    // kept for drop, which cannot require T: Write
+    finish: fn(&mut Self) -> io::Result<()>,
 }
 
 impl<EncDec, T> Writer<EncDec, T> {
-    pub fn new(encdec: EncDec, inner: T) -> Self {
+    pub fn new(encdec: EncDec, inner: T) -> Self
+    where
+        EncDec: private::Finish,
+        T: io::Write,
+    {
         Self::with_capacity(encdec, crate::DEFAULT_BUFFER_SIZE, inner)
     }
 
-    pub fn with_capacity(encdec: EncDec, capacity: usize, inner: T) -> Self {
+    pub fn with_capacity(encdec: EncDec, capacity: usize, inner: T) -> Self
+    where
+        EncDec: private::Finish,
+        T: io::Write,
+    {
         Self {
             encdec,
-            buffer: Vec::with_capacity(capacity),
-            inner,
+            // room for at least one sample
+            buffer: Vec::with_capacity(capacity.max(4)),
+            partial: vec![],
+            inner: Some(inner),
+            finish: EncDec::finish::<T>,
         }
     }
 
-    pub fn into_inner(self) -> T {
-        self.inner
+    /// End the stream, writing all remaining output, and return the
+    /// inner writer.
+    ///
+    /// A decoder fails with [`ErrorKind::Stream`] if the compressed
+    /// stream stopped partway through a block.
+    ///
+    /// [`ErrorKind::Stream`]: crate::ErrorKind::Stream
+    pub fn finish(mut self) -> io::Result<T> {
+        // take the inner writer even on error, so Drop doesn't try again
+        let result = (self.finish)(&mut self);
+        let inner = self.take();
+        result.map(|_| inner)
+    }
+
+    /// Return the inner writer, without finishing the stream.
+    pub fn into_inner(mut self) -> T {
+        self.take()
     }
 
     pub fn get_ref(&self) -> &T {
-        &self.inner
+        self.inner.as_ref().unwrap()
     }
 
     pub fn get_mut(&mut self) -> &mut T {
-        &mut self.inner
+        self.inner.as_mut().unwrap()
+    }
+
+    fn take(&mut self) -> T {
+        self.inner.take().unwrap()
+    }
+}
+
+impl<EncDec, T> Drop for Writer<EncDec, T> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = (self.finish)(self);
+        }
+    }
+}
+
+mod private {
+    use super::Writer;
+    use std::io;
+
+    /// How a [`Writer`] ends the stream of each coder.
+    pub trait Finish: Sized {
+        fn finish<T: io::Write>(writer: &mut Writer<Self, T>) -> io::Result<()>;
+    }
+}
+
+impl private::Finish for Encoder {
+    fn finish<T: io::Write>(writer: &mut Writer<Self, T>) -> io::Result<()> {
+        if !writer.partial.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "stream ends with a partial sample",
+            ));
+        }
+        let inner = writer.inner.as_mut().unwrap();
+        if !writer.encdec.is_ended() {
+            loop {
+                writer.buffer.clear();
+                let (_, out, status) = writer.encdec.encode(&[], &mut writer.buffer, true)?;
+                inner.write_all(out)?;
+                if status == Status::StreamEnd {
+                    break;
+                }
+            }
+            writer.encdec.end()?;
+        }
+        inner.flush()
+    }
+}
+
+impl private::Finish for Decoder {
+    fn finish<T: io::Write>(writer: &mut Writer<Self, T>) -> io::Result<()> {
+        let inner = writer.inner.as_mut().unwrap();
+        if !writer.encdec.is_ended() {
+            let status = loop {
+                writer.buffer.clear();
+                let (_, out, status) = writer.encdec.decode(&[], &mut writer.buffer, true)?;
+                inner.write_all(out)?;
+                if status != Status::NeedOutput {
+                    break status;
+                }
+            };
+            let err = writer.encdec.error(ErrorKind::Stream, Operation::DecodeEnd);
+            writer.encdec.end()?;
+            if status != Status::StreamEnd {
+                inner.flush()?;
+                return Err(err.into());
+            }
+        }
+        inner.flush()
     }
 }
 
@@ -77,22 +189,42 @@ where
         if self.encdec.is_ended() {
             return Ok(0);
         }
+        let storage = self.encdec.storage();
         let mut produced = 0;
         while produced < buf.len() {
             let input = self.inner.fill_buf()?;
             let inlen = input.len();
-            if inlen == 0 && produced > 0 {
-                // break here -- try to get the flush on a new buffer
-                // since we cannot tell when flushing is done
-                break;
+            if !self.partial.is_empty() || (0 < inlen && inlen < storage) {
+                if inlen == 0 && self.partial.len() < storage {
+                    if produced > 0 {
+                        break;
+                    }
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "input ends with a partial sample",
+                    ));
+                }
+                let n = (storage - self.partial.len()).min(inlen);
+                self.partial.extend_from_slice(&input[..n]);
+                self.inner.consume(n);
+                if self.partial.len() < storage {
+                    continue;
+                }
+                let (rest, out, _) =
+                    self.encdec
+                        .encode(&self.partial, &mut buf[produced..], false)?;
+                let consumed = self.partial.len() - rest.len();
+                self.partial.drain(..consumed);
+                produced += out.len();
+                continue;
             }
-            let (rest, out) = self
-                .encdec
-                .encode(input, &mut buf[produced..], inlen == 0)?;
+            let (rest, out, status) =
+                self.encdec
+                    .encode(input, &mut buf[produced..], inlen == 0)?;
             let consumed = inlen - rest.len();
             self.inner.consume(consumed);
             produced += out.len();
-            if inlen == 0 {
+            if status == Status::StreamEnd {
                 self.encdec.end()?;
                 break;
             }
@@ -116,7 +248,7 @@ where
                 self.encdec.end()?;
                 break;
             }
-            let (rest, out) = self.encdec.decode(input, &mut buf[produced..], false)?;
+            let (rest, out, _) = self.encdec.decode(input, &mut buf[produced..], false)?;
             let consumed = input.len() - rest.len();
             self.inner.consume(consumed);
             produced += out.len();
@@ -125,6 +257,23 @@ where
     }
 }
 
+impl<T> Writer<Encoder, T>
+where
+    T: io::Write,
+{
+    // encode whole samples, writing out all the output
+    fn encode_samples(&mut self, mut input: &[u8]) -> io::Result<()> {
+        let inner = self.inner.as_mut().unwrap();
+        while !input.is_empty() {
+            self.buffer.clear();
+            let (rest, out, _) = self.encdec.encode(input, &mut self.buffer, false)?;
+            inner.write_all(out)?;
+            input = rest;
+        }
+        Ok(())
+    }
+}
+
 impl<T> io::Write for Writer<Encoder, T>
 where
     T: io::Write,
@@ -133,31 +282,26 @@ where
         if self.encdec.is_ended() {
             return Ok(0);
         }
-        let mut consumed = 0;
-        while consumed < buf.len() {
-            self.buffer.clear();
-            let (rest, out) = self
-                .encdec
-                .encode(&buf[consumed..], &mut self.buffer, false)?;
-            if !out.is_empty() {
-                self.inner.write_all(out)?;
+        let storage = self.encdec.storage();
+        if !self.partial.is_empty() || buf.len() < storage {
+            // the encoder only takes whole samples
+            let n = (storage - self.partial.len()).min(buf.len());
+            self.partial.extend_from_slice(&buf[..n]);
+            if self.partial.len() == storage {
+                let sample = std::mem::take(&mut self.partial);
+                self.encode_samples(&sample)?;
             }
-            consumed = buf.len() - rest.len();
+            return Ok(n);
         }
-        Ok(consumed)
+        let n = buf.len() - buf.len() % storage;
+        self.encode_samples(&buf[..n])?;
+        Ok(n)
     }
 
+    /// This writes out what the encoder has produced so far, but
+    /// does not end the stream. Use [`Writer::finish`] for that.
     fn flush(&mut self) -> io::Result<()> {
-        if self.encdec.is_ended() {
-            return Ok(());
-        }
-        self.buffer.clear();
-        let (_, out) = self.encdec.encode(&[], &mut self.buffer, true)?;
-        if !out.is_empty() {
-            self.inner.write_all(out)?;
-        }
-        self.encdec.end()?;
-        self.inner.flush()
+        self.get_mut().flush()
     }
 }
 
@@ -170,21 +314,22 @@ where
             return Ok(0);
         }
         let mut consumed = 0;
-        while consumed < buf.len() {
+        loop {
             self.buffer.clear();
-            let (rest, out) = self
-                .encdec
-                .decode(&buf[consumed..], &mut self.buffer, false)?;
-            if !out.is_empty() {
-                self.inner.write_all(out)?;
-            }
+            let (rest, out, status) =
+                self.encdec
+                    .decode(&buf[consumed..], &mut self.buffer, false)?;
+            self.inner.as_mut().unwrap().write_all(out)?;
             consumed = buf.len() - rest.len();
+            if status != Status::NeedOutput {
+                break;
+            }
         }
         Ok(consumed)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.inner.flush()
+        self.get_mut().flush()
     }
 }
 
@@ -236,8 +381,7 @@ mod test {
         let writer = io::Cursor::new(Vec::<u8>::new());
         let mut writer = Writer::with_capacity(conf.encoder().unwrap(), 64, writer);
         writer.write_all(DATA).unwrap();
-        writer.flush().unwrap();
-        let encoded = writer.into_inner().into_inner();
+        let encoded = writer.finish().unwrap().into_inner();
         let mut decoded = vec![];
         conf.decode_buffer(&encoded, &mut decoded).unwrap();
         assert_eq!(decoded, DATA);
@@ -251,7 +395,130 @@ mod test {
         let writer = io::Cursor::new(Vec::<u8>::new());
         let mut writer = Writer::with_capacity(conf.decoder().unwrap(), 2, writer);
         writer.write_all(&encoded).unwrap();
-        let decoded = writer.into_inner().into_inner();
+        let decoded = writer.finish().unwrap().into_inner();
         assert_eq!(decoded, DATA);
     }
+
+    #[test]
+    fn finish_writer() {
+        let conf = config();
+        let data: Vec<u8> = (0..DATA.len() * 40).map(|i| (i * i % 251) as u8).collect();
+
+        // flushing along the way leaves the stream open, and a tiny
+        // buffer still gets all of the flush output
+        let mut writer = Writer::with_capacity(conf.encoder().unwrap(), 1, vec![]);
+        for piece in data.chunks(DATA.len()) {
+            writer.write_all(piece).unwrap();
+            writer.flush().unwrap();
+        }
+        let encoded = writer.finish().unwrap();
+        let mut decoded = vec![];
+        conf.decode_buffer(&encoded, &mut decoded).unwrap();
+        assert_eq!(decoded, data);
+
+        // dropping finishes too
+        let mut dropped = vec![];
+        {
+            let mut writer = Writer::new(conf.encoder().unwrap(), &mut dropped);
+            writer.write_all(&data).unwrap();
+        }
+        assert_eq!(dropped, encoded);
+
+        // a decoder notices a stream cut short
+        let mut writer = Writer::new(conf.decoder().unwrap(), vec![]);
+        writer.write_all(&encoded[..encoded.len() / 2]).unwrap();
+        let err = writer.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        let mut writer = Writer::new(conf.decoder().unwrap(), vec![]);
+        writer.write_all(&encoded).unwrap();
+        assert_eq!(writer.finish().unwrap(), data);
+    }
+
+    #[test]
+    fn split_samples() {
+        let conf = Configuration::new(16, 16, 8, Flags::DATA_PREPROCESS);
+        let data: Vec<u8> = (0..1000u32)
+            .flat_map(|i| ((i * i / 5) as u16).to_le_bytes())
+            .collect();
+        let mut expected = vec![];
+        conf.encode_buffer(&data, &mut expected).unwrap();
+
+        let mut writer = Writer::new(conf.encoder().unwrap(), vec![]);
+        for piece in data.chunks(3) {
+            writer.write_all(piece).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), expected);
+
+        // through a BufWriter, which flushes along the way
+        let writer = Writer::new(conf.encoder().unwrap(), vec![]);
+        let mut buffered = io::BufWriter::with_capacity(7, writer);
+        for piece in data.chunks(5) {
+            buffered.write_all(piece).unwrap();
+            buffered.flush().unwrap();
+        }
+        let writer = buffered.into_inner().unwrap();
+        assert_eq!(writer.finish().unwrap(), expected);
+
+        let mut writer = Writer::new(conf.decoder().unwrap(), vec![]);
+        for piece in expected.chunks(3) {
+            writer.write_all(piece).unwrap();
+        }
+        let decoded = writer.finish().unwrap();
+        assert_eq!(&decoded[..data.len()], data);
+
+        // a sample left over at the end is an error
+        let mut writer = Writer::new(conf.encoder().unwrap(), vec![]);
+        writer.write_all(&data[..data.len() - 1]).unwrap();
+        let err = writer.finish().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn read_split_samples() {
+        let conf = Configuration::new(16, 16, 8, Flags::DATA_PREPROCESS);
+        let data: Vec<u8> = (0..1000u32)
+            .flat_map(|i| ((i * i / 5) as u16).to_le_bytes())
+            .collect();
+        let mut expected = vec![];
+        conf.encode_buffer(&data, &mut expected).unwrap();
+
+        // an inner reader that splits samples
+        let inner = io::BufReader::with_capacity(3, &data[..]);
+        let mut reader = Reader::new(conf.encoder().unwrap(), inner);
+        let mut encoded = vec![];
+        reader.read_to_end(&mut encoded).unwrap();
+        assert_eq!(encoded, expected);
+
+        // a sample left over at the end is an error, not a hang
+        let inner = io::BufReader::with_capacity(3, &data[..data.len() - 1]);
+        let mut reader = Reader::new(conf.encoder().unwrap(), inner);
+        let err = reader.read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let mut reader = Reader::new(conf.encoder().unwrap(), &data[..1]);
+        let err = reader.read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn finish_once() {
+        // fails every write, counting calls
+        struct Failing(std::rc::Rc<std::cell::Cell<usize>>);
+        impl Write for Failing {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                self.0.set(self.0.get() + 1);
+                Err(io::Error::other("failing"))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                self.0.set(self.0.get() + 1);
+                Ok(())
+            }
+        }
+
+        let conf = Configuration::new(8, 16, 16, Flags::DATA_PREPROCESS);
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let mut writer = Writer::new(conf.encoder().unwrap(), Failing(calls.clone()));
+        writer.write_all(&[1, 2, 3]).unwrap();
+        assert!(writer.finish().is_err());
+        assert_eq!(calls.get(), 1);
+    }
 }
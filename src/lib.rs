@@ -1,150 +1,246 @@
-use libaec_sys::*;
-use libc::{c_int, c_uint, size_t};
+#[cfg(not(any(feature = "libaec", feature = "pure-rust")))]
+compile_error!("acres needs one of the `libaec` or `pure-rust` features");
+
+use std::mem::MaybeUninit;
+use std::ops::Range;
+
+#[cfg(any(feature = "tokio", feature = "futures-io"))]
+pub mod aio;
 
 mod buffer;
 pub use buffer::Buffer;
 
+mod builder;
+pub use builder::{ConfigError, ConfigurationBuilder, BLOCK_SIZES, MAX_RSI};
+
+pub mod frame;
+
+pub mod grib2;
+
+mod error;
+pub use error::{Error, ErrorKind, Operation};
+
 mod io;
 pub use io::{Reader, Writer};
 
+mod limits;
+pub use limits::Limits;
+
+pub mod pack;
+
+#[cfg(feature = "rayon")]
+mod parallel;
+
+mod predict;
+pub use predict::{Average, Med, Neighbours, Predictor, PreviousRow};
+
+pub mod quantize;
+
+mod sample;
+pub use sample::Sample;
+
+mod stats;
+pub use stats::{OptionCounts, Stats};
+
+#[cfg(feature = "libaec")]
 pub mod sz;
 
+#[cfg(feature = "libaec")]
+mod libaec;
+#[cfg(feature = "libaec")]
+use libaec as backend;
+
+// with both features, libaec is used and the native coder is only
+// compared against it in tests
+#[cfg(feature = "pure-rust")]
+#[cfg_attr(feature = "libaec", allow(dead_code, unused_imports))]
+mod native;
+#[cfg(not(feature = "libaec"))]
+use native as backend;
+
 const DEFAULT_BUFFER_SIZE: usize = 8192;
 
+// these are the values libaec uses, but are written out here so that
+// the pure-Rust backend does not need libaec-sys
 bitflags::bitflags! {
     pub struct Flags: u32 {
-        const DATA_SIGNED = AEC_DATA_SIGNED;
-        const DATA_3BYTE = AEC_DATA_3BYTE;
-        const DATA_MSB = AEC_DATA_MSB;
-        const DATA_PREPROCESS = AEC_DATA_PREPROCESS;
-        const RESTRICTED = AEC_RESTRICTED;
-        const PAD_RSI = AEC_PAD_RSI;
-        const NOT_ENFORCE = AEC_NOT_ENFORCE;
+        const DATA_SIGNED = 1;
+        const DATA_3BYTE = 2;
+        const DATA_MSB = 4;
+        const DATA_PREPROCESS = 8;
+        const RESTRICTED = 16;
+        const PAD_RSI = 32;
+        const NOT_ENFORCE = 64;
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
-pub enum Error {
-    Configuration,
-    Stream,
-    Data,
-    Memory,
+/// Where a call to [`Encoder::encode`] or [`Decoder::decode`] left
+/// off, and so what the next call needs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Status {
+    /// The output has no room left, and there may be more to come.
+    /// Call again with more space.
+    NeedOutput,
+    /// The output has room, so the input was used as far as it
+    /// could be. Call again with what is left of it, and more.
+    NeedInput,
+    /// A flush is done. An encoder has written the whole stream,
+    /// and a decoder has decoded a stream that ends on a block.
+    StreamEnd,
 }
 
-impl Error {
-    fn from_int(v: c_int) -> Result<(), Self> {
-        match v {
-            AEC_OK => Ok(()),
-            AEC_CONF_ERROR => Err(Self::Configuration),
-            AEC_STREAM_ERROR => Err(Self::Stream),
-            AEC_DATA_ERROR => Err(Self::Data),
-            AEC_MEM_ERROR => Err(Self::Memory),
-            // this is a lie, but it should also never happen
-            // and I'd rather *this* than panic
-            _ => Err(Self::Configuration),
-        }
-    }
+/// The parameters of a stream. These are plain values, so a
+/// configuration is `Send` and `Sync`, and can be shared between
+/// threads.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Configuration {
+    bits_per_sample: usize,
+    block_size: usize,
+    rsi: usize,
+    flags: Flags,
+    limits: Limits,
+    prediction: Option<predict::Prediction>,
 }
 
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Self::Configuration => write!(f, "bad configuration"),
-            Self::Stream => write!(f, "stream closed before all data written"),
-            Self::Data => write!(f, "unexpected data"),
-            // Memory may also be a poorly-sized output buffer
-            Self::Memory => write!(f, "out of memory"),
+impl Configuration {
+    pub fn new(bits_per_sample: usize, block_size: usize, rsi: usize, flags: Flags) -> Self {
+        Self {
+            bits_per_sample,
+            block_size,
+            rsi,
+            flags,
+            limits: Limits::default(),
+            prediction: None,
         }
     }
-}
 
-impl std::error::Error for Error {}
+    /// This configuration, with `limits` on what decoders created
+    /// from it may produce.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
 
-impl From<Error> for std::io::Error {
-    fn from(err: Error) -> Self {
-        use std::io::ErrorKind;
-        let kind = match err {
-            Error::Configuration => ErrorKind::InvalidInput,
-            Error::Stream => ErrorKind::Other,
-            Error::Data => ErrorKind::InvalidData,
-            Error::Memory => ErrorKind::Other,
-        };
-        std::io::Error::new(kind, err)
+    /// This configuration, with samples replaced by their difference
+    /// from `predictor` before encoding, and restored after decoding.
+    /// Samples are predicted as rows of `width` samples.
+    ///
+    /// Prediction is usually best without [`Flags::DATA_PREPROCESS`].
+    /// It is not available with [`Configuration::decode_range`].
+    pub fn with_predictor<P>(mut self, predictor: P, width: usize) -> Self
+    where
+        P: Predictor + 'static,
+    {
+        self.prediction = Some(predict::Prediction::new(predictor, width));
+        self
     }
-}
 
-#[derive(Debug)]
-pub struct Configuration(aec_stream);
+    /// The row width used for prediction, if this configuration has
+    /// a predictor.
+    pub fn prediction_width(&self) -> Option<usize> {
+        self.prediction.as_ref().map(|p| p.width)
+    }
 
-impl Clone for Configuration {
-    fn clone(&self) -> Self {
-        Self(aec_stream { ..self.0 })
+    /// Start building a configuration that is checked against the
+    /// rules in CCSDS 121.0-B-3.
+    pub fn builder() -> ConfigurationBuilder {
+        ConfigurationBuilder::new()
     }
-}
 
-impl Configuration {
-    pub fn new(bits_per_sample: usize, block_size: usize, rsi: usize, flags: Flags) -> Self {
-        Self(aec_stream {
-            next_in: std::ptr::null(),
-            avail_in: 0,
-            total_in: 0,
-            next_out: std::ptr::null_mut(),
-            avail_out: 0,
-            total_out: 0,
-            bits_per_sample: bits_per_sample as c_uint,
-            block_size: block_size as c_uint,
-            rsi: rsi as c_uint,
-            flags: flags.bits() as c_uint,
-            state: std::ptr::null_mut(),
-        })
+    /// Check this configuration against the rules in CCSDS 121.0-B-3.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        builder::validate(
+            self.bits_per_sample(),
+            self.block_size(),
+            self.rsi(),
+            self.flags(),
+        )
     }
 
     pub fn bits_per_sample(&self) -> usize {
-        self.0.bits_per_sample as usize
+        self.bits_per_sample
     }
 
     pub fn block_size(&self) -> usize {
-        self.0.block_size as usize
+        self.block_size
     }
 
     pub fn rsi(&self) -> usize {
-        self.0.rsi as usize
+        self.rsi
     }
 
     pub fn flags(&self) -> Flags {
-        Flags::from_bits_truncate(self.0.flags as u32)
+        self.flags
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
     }
 
     pub fn encoder(&self) -> Result<Encoder, Error> {
-        Encoder::new(aec_stream { ..self.0 })
+        Encoder::new(self)
     }
 
     pub fn decoder(&self) -> Result<Decoder, Error> {
-        Decoder::new(aec_stream { ..self.0 })
+        Decoder::new(self)
+    }
+
+    /// This configuration, with sample flags adjusted to match `T`
+    /// on this host.
+    fn for_samples<T: Sample>(&self) -> Result<Self, Error> {
+        if self.bits_per_sample() > T::BITS {
+            return Err(ErrorKind::Configuration.into());
+        }
+        let mut conf = self.clone();
+        conf.flags = sample::flags_for::<T>(self.flags);
+        Ok(conf)
+    }
+
+    /// Create an encoder for samples of type `T`.
+    ///
+    /// This overrides [`Flags::DATA_SIGNED`], [`Flags::DATA_MSB`] and
+    /// [`Flags::DATA_3BYTE`] to match `T` and the host.
+    pub fn sample_encoder<T: Sample>(&self) -> Result<Encoder, Error> {
+        self.for_samples::<T>()?.encoder()
+    }
+
+    /// Create a decoder for samples of type `T`.
+    ///
+    /// This overrides [`Flags::DATA_SIGNED`], [`Flags::DATA_MSB`] and
+    /// [`Flags::DATA_3BYTE`] to match `T` and the host.
+    pub fn sample_decoder<T: Sample>(&self) -> Result<Decoder, Error> {
+        self.for_samples::<T>()?.decoder()
     }
 
     pub fn encode_buffer<'a>(
         &self,
-        mut input: &[u8],
+        input: &[u8],
         output: &'a mut Vec<u8>,
     ) -> Result<&'a mut [u8], Error> {
-        let start = output.len();
         let mut enc = self.encoder()?;
+        let start = output.len();
+        enc.encode_buffer(input, output)?;
+        enc.end()?;
+        Ok(&mut output[start..])
+    }
+
+    pub fn encode_samples<'a, T>(
+        &self,
+        mut input: &[T],
+        output: &'a mut Vec<u8>,
+    ) -> Result<&'a mut [u8], Error>
+    where
+        T: Sample,
+    {
+        let start = output.len();
+        let mut enc = self.sample_encoder::<T>()?;
         loop {
-            let flush = input.is_empty();
-            if flush {
-                // give us a bit extra to work with, because we
-                // cannot tell when flushing is done
-                output.reserve(DEFAULT_BUFFER_SIZE);
-            }
-            let (rest, _) = enc.encode(input, output, flush)?;
+            let (rest, _, status) = enc.encode_samples(input, output, true)?;
             input = rest;
-            if output.len() == output.capacity() {
-                output.reserve(DEFAULT_BUFFER_SIZE);
-                continue;
-            }
-            if input.is_empty() && flush {
-                break;
+            match status {
+                Status::StreamEnd => break,
+                Status::NeedOutput => output.reserve(DEFAULT_BUFFER_SIZE),
+                Status::NeedInput => {}
             }
         }
         enc.end()?;
@@ -161,28 +257,72 @@ impl Configuration {
         Ok(Reader::new(self.encoder()?, inner))
     }
 
-    pub fn encode_writer<T>(&self, inner: T) -> Result<Writer<Encoder, T>, Error> {
+    pub fn encode_writer<T: std::io::Write>(&self, inner: T) -> Result<Writer<Encoder, T>, Error> {
         Ok(Writer::new(self.encoder()?, inner))
     }
 
     pub fn decode_buffer<'a>(
         &self,
-        mut input: &[u8],
+        input: &[u8],
         output: &'a mut Vec<u8>,
     ) -> Result<&'a mut [u8], Error> {
-        let start = output.len();
         let mut dec = self.decoder()?;
-        loop {
-            let (rest, _) = dec.decode(input, output, false)?;
+        let start = output.len();
+        dec.decode_buffer(input, output)?;
+        dec.end()?;
+        Ok(&mut output[start..])
+    }
+
+    pub fn decode_samples<'a, T>(
+        &self,
+        mut input: &[u8],
+        output: &'a mut [T],
+    ) -> Result<&'a mut [T], Error>
+    where
+        T: Sample,
+    {
+        let mut produced = 0;
+        let mut dec = self.sample_decoder::<T>()?;
+        while produced < output.len() {
+            let (rest, out, status) = dec.decode_samples(input, &mut output[produced..], false)?;
             input = rest;
-            if input.is_empty() {
+            produced += out.len();
+            if status == Status::NeedInput {
                 break;
             }
-            if output.len() == output.capacity() {
-                output.reserve(DEFAULT_BUFFER_SIZE);
-            }
         }
         dec.end()?;
+        Ok(&mut output[..produced])
+    }
+
+    /// Decode only `samples` from `input`, using the RSI offsets
+    /// recorded while encoding or decoding it. See
+    /// [`Encoder::enable_offsets`].
+    ///
+    /// Only the reference sample intervals holding `samples` are
+    /// decoded.
+    pub fn decode_range<'a>(
+        &self,
+        input: &[u8],
+        offsets: &[usize],
+        samples: Range<usize>,
+        output: &'a mut Vec<u8>,
+    ) -> Result<&'a mut [u8], Error> {
+        let storage = sample::storage_size(self.bits_per_sample, self.flags);
+        let pos = samples.start * storage;
+        let len = samples.end.saturating_sub(samples.start) * storage;
+        if self.prediction.is_some() {
+            // predictions depend on all the samples before
+            let e = Error::new(ErrorKind::Configuration);
+            return Err(e.at(Operation::DecodeRange, self, 0, pos));
+        }
+        let start = output.len();
+        output.reserve(len);
+        let out = &mut output.spare_capacity_mut()[..len];
+        backend::decode_range(self, input, offsets, pos, out)
+            .map_err(|kind| Error::new(kind).at(Operation::DecodeRange, self, 0, pos))?;
+        // safe: decode_range fills all of out, or fails
+        unsafe { output.set_len(start + len) };
         Ok(&mut output[start..])
     }
 
@@ -196,132 +336,500 @@ impl Configuration {
         Ok(Reader::new(self.decoder()?, inner))
     }
 
-    pub fn decode_writer<T>(&self, inner: T) -> Result<Writer<Decoder, T>, Error> {
+    pub fn decode_writer<T: std::io::Write>(&self, inner: T) -> Result<Writer<Decoder, T>, Error> {
         Ok(Writer::new(self.decoder()?, inner))
     }
 }
 
+/// Check the parts of `conf` that the backends know nothing about.
+fn check_init(conf: &Configuration, operation: Operation) -> Result<(), Error> {
+    if conf.prediction_width() == Some(0) {
+        let e = Error::new(ErrorKind::Configuration);
+        return Err(e.at(operation, conf, 0, 0));
+    }
+    Ok(())
+}
+
+/// A streaming encoder.
+///
+/// Encoders are `Send`, so each can be moved to the thread that uses
+/// it.
 #[derive(Debug)]
-pub struct Encoder(aec_stream);
+pub struct Encoder {
+    inner: backend::Encoder,
+    conf: Configuration,
+    offsets: bool,
+    stats: Option<stats::Walker>,
+    predict: Option<predict::State>,
+}
 
 impl Encoder {
-    fn new(strm: aec_stream) -> Result<Self, Error> {
-        let mut s = Self(strm);
-        unsafe { Error::from_int(aec_encode_init(&mut s.0)).map(|_| s) }
+    fn new(conf: &Configuration) -> Result<Self, Error> {
+        check_init(conf, Operation::EncodeInit)?;
+        match backend::Encoder::new(conf) {
+            Ok(inner) => Ok(Self {
+                inner,
+                conf: conf.clone(),
+                offsets: false,
+                stats: None,
+                predict: predict::State::new(conf),
+            }),
+            Err(kind) => Err(Error::new(kind).at(Operation::EncodeInit, conf, 0, 0)),
+        }
+    }
+
+    // bytes per sample, the smallest piece of input the coder takes
+    fn storage(&self) -> usize {
+        sample::storage_size(self.conf.bits_per_sample, self.conf.flags)
+    }
+
+    fn error(&self, kind: ErrorKind, operation: Operation) -> Error {
+        Error::new(kind).at(
+            operation,
+            &self.conf,
+            self.inner.total_in(),
+            self.inner.total_out(),
+        )
     }
 
     pub fn end(&mut self) -> Result<(), Error> {
-        if !self.0.state.is_null() {
-            let r = unsafe { aec_encode_end(&mut self.0) };
-            self.0.state = std::ptr::null_mut();
-            Error::from_int(r)
-        } else {
-            Ok(())
-        }
+        self.inner
+            .end()
+            .map_err(|kind| self.error(kind, Operation::EncodeEnd))
     }
 
     pub fn is_ended(&self) -> bool {
-        self.0.state.is_null()
+        self.inner.is_ended()
+    }
+
+    /// Return the encoder to its starting state, to encode a new
+    /// stream. This works whether or not the last stream was
+    /// finished or ended. Offsets and statistics stay enabled.
+    ///
+    /// The native coder keeps its buffers, but libaec has no way to
+    /// reset a stream: there this frees and allocates its state again,
+    /// no cheaper than a new encoder.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.inner
+            .reset()
+            .map_err(|kind| self.error(kind, Operation::EncodeInit))?;
+        self.restart()
     }
 
+    /// Reset the encoder to encode a new stream with `conf`.
+    pub fn reset_with(&mut self, conf: &Configuration) -> Result<(), Error> {
+        check_init(conf, Operation::EncodeInit)?;
+        self.inner
+            .reset_with(conf)
+            .map_err(|kind| Error::new(kind).at(Operation::EncodeInit, conf, 0, 0))?;
+        self.conf = conf.clone();
+        self.predict = predict::State::new(conf);
+        self.restart()
+    }
+
+    // start over everything kept outside the backend
+    fn restart(&mut self) -> Result<(), Error> {
+        if self.offsets {
+            self.enable_offsets()?;
+        }
+        if self.stats.is_some() {
+            self.stats = Some(stats::Walker::new(&self.conf));
+        }
+        if let Some(state) = &mut self.predict {
+            state.reset();
+        }
+        Ok(())
+    }
+
+    /// Encode all of `input` as a complete stream, appending it to
+    /// `output`, like [`Configuration::encode_buffer`].
+    ///
+    /// The encoder is reset first if it has been used, so one encoder
+    /// can encode many buffers without being created again.
+    pub fn encode_buffer<'a>(
+        &mut self,
+        input: &[u8],
+        output: &'a mut Vec<u8>,
+    ) -> Result<&'a mut [u8], Error> {
+        if self.is_ended() || self.inner.total_in() > 0 || self.inner.total_out() > 0 {
+            self.reset()?;
+        }
+        let start = output.len();
+        self.encode_all(input, output)?;
+        Ok(&mut output[start..])
+    }
+
+    /// Record the bit offset of each reference sample interval as
+    /// it is encoded, for use with [`Configuration::decode_range`].
+    ///
+    /// This must be called before any data is encoded.
+    pub fn enable_offsets(&mut self) -> Result<(), Error> {
+        self.inner
+            .enable_offsets()
+            .map_err(|kind| self.error(kind, Operation::EncodeInit))?;
+        self.offsets = true;
+        Ok(())
+    }
+
+    /// The offsets recorded so far, if enabled with
+    /// [`Encoder::enable_offsets`]. All offsets are available once
+    /// the encoder has been flushed.
+    pub fn offsets(&mut self) -> Result<Vec<usize>, Error> {
+        self.inner
+            .offsets()
+            .map_err(|kind| self.error(kind, Operation::Encode))
+    }
+
+    /// Collect [`Stats`] on the code options chosen, by parsing the
+    /// encoded output as it is produced.
+    ///
+    /// This must be called before any data is encoded.
+    pub fn enable_stats(&mut self) -> Result<(), Error> {
+        if self.inner.total_in() > 0 || self.inner.total_out() > 0 {
+            return Err(self.error(ErrorKind::Configuration, Operation::EncodeInit));
+        }
+        self.stats = Some(stats::Walker::new(&self.conf));
+        Ok(())
+    }
+
+    /// The statistics collected so far, if enabled with
+    /// [`Encoder::enable_stats`]. The last reference sample interval
+    /// is only counted once the encoder has been flushed.
+    pub fn stats(&self) -> Option<Stats> {
+        self.stats
+            .as_ref()
+            .map(|walker| walker.stats(self.inner.total_in()))
+    }
+
+    fn layout<T: Sample>(&self) -> Result<sample::Layout, Error> {
+        sample::Layout::new::<T>(self.conf.bits_per_sample, self.conf.flags)
+            .ok_or_else(|| self.error(ErrorKind::Configuration, Operation::Encode))
+    }
+
+    /// Encode samples of type `T`.
+    ///
+    /// The encoder must have been configured with a sample size and
+    /// signedness compatible with `T`, as returned by
+    /// [`Configuration::sample_encoder`].
+    pub fn encode_samples<'i, 'o, T, B>(
+        &mut self,
+        input: &'i [T],
+        output: &'o mut B,
+        flush: bool,
+    ) -> Result<(&'i [T], &'o mut [u8], Status), Error>
+    where
+        T: Sample,
+        B: Buffer + ?Sized,
+    {
+        let layout = self.layout::<T>()?;
+        if layout.native {
+            let (rest, out, status) = self.encode(sample::as_bytes(input), output, flush)?;
+            let consumed = input.len() - rest.len() / layout.storage;
+            return Ok((&input[consumed..], out, status));
+        }
+
+        // storage is narrower than T, so convert a chunk at a time
+        let mut scratch = sample::scratch();
+        let n = input.len().min(scratch.len() / layout.storage);
+        let bytes = &mut scratch[..n * layout.storage];
+        layout.store(&input[..n], bytes);
+        let (rest, out, status) = self.encode(bytes, output, flush && n == input.len())?;
+        let consumed = n - rest.len() / layout.storage;
+        Ok((&input[consumed..], out, status))
+    }
+
+    /// Encode and flush all of `input`, without ending the encoder.
+    fn encode_all(&mut self, mut input: &[u8], output: &mut Vec<u8>) -> Result<(), Error> {
+        loop {
+            let (rest, _, status) = self.encode(input, output, true)?;
+            input = rest;
+            match status {
+                Status::StreamEnd => return Ok(()),
+                Status::NeedOutput => output.reserve(DEFAULT_BUFFER_SIZE),
+                Status::NeedInput => {}
+            }
+        }
+    }
+
+    /// Encode `input` into `output`, flushing the stream once all of
+    /// `input` is used if `flush` is set. The [`Status`] says whether
+    /// the flush is done.
     pub fn encode<'i, 'o, B>(
         &mut self,
         input: &'i [u8],
         output: &'o mut B,
         flush: bool,
-    ) -> Result<(&'i [u8], &'o mut [u8]), Error>
+    ) -> Result<(&'i [u8], &'o mut [u8], Status), Error>
     where
         B: Buffer + ?Sized,
     {
-        if self.0.state.is_null() {
-            return Err(Error::Stream);
+        if self.is_ended() {
+            return Err(self.error(ErrorKind::Stream, Operation::Encode));
         }
-        self.0.next_in = input.as_ptr();
-        self.0.avail_in = input.len() as size_t;
         let (outptr, outlen) = output.write_info();
-        self.0.next_out = outptr;
-        self.0.avail_out = outlen as size_t;
-        let flush = if flush { AEC_FLUSH } else { AEC_NO_FLUSH };
-        unsafe {
-            let result = aec_encode(&mut self.0, flush);
-            Error::from_int(result).map(move |_| {
-                (
-                    &input[input.len() - self.0.avail_in..],
-                    output.write_data(outlen - self.0.avail_out),
-                )
-            })
+        let out = unsafe { std::slice::from_raw_parts_mut(outptr as *mut MaybeUninit<u8>, outlen) };
+        let (result, flush) = match &mut self.predict {
+            None => (self.inner.encode(input, out, flush), flush),
+            Some(state) => {
+                // code the residuals of as many samples as fit in
+                // scratch, then advance past those actually used
+                let mut scratch = sample::scratch();
+                let n = state.forward(input, &mut scratch) * state.storage();
+                let flush = flush && input.len() - n < state.storage();
+                let result = self.inner.encode(&scratch[..n], out, flush);
+                if let Ok((consumed, _)) = result {
+                    state.advance(&input[..consumed]);
+                }
+                (result, flush)
+            }
+        };
+        let (consumed, produced) = result.map_err(|kind| self.error(kind, Operation::Encode))?;
+        let status = if produced == outlen {
+            Status::NeedOutput
+        } else if flush {
+            Status::StreamEnd
+        } else {
+            Status::NeedInput
+        };
+        let written = unsafe { output.write_data(produced) };
+        if let Some(walker) = &mut self.stats {
+            walker.push(written, self.inner.total_in());
         }
+        Ok((&input[consumed..], written, status))
     }
 }
 
-impl Drop for Encoder {
-    fn drop(&mut self) {
-        let _ = self.end();
-    }
-}
-
+/// A streaming decoder. Like [`Encoder`], it is `Send`.
 #[derive(Debug)]
-pub struct Decoder(aec_stream);
+pub struct Decoder {
+    inner: backend::Decoder,
+    conf: Configuration,
+    offsets: bool,
+    predict: Option<predict::State>,
+}
 
 impl Decoder {
-    fn new(strm: aec_stream) -> Result<Self, Error> {
-        let mut s = Self(strm);
-        unsafe { Error::from_int(aec_decode_init(&mut s.0)).map(|_| s) }
+    fn new(conf: &Configuration) -> Result<Self, Error> {
+        check_init(conf, Operation::DecodeInit)?;
+        match backend::Decoder::new(conf) {
+            Ok(inner) => Ok(Self {
+                inner,
+                conf: conf.clone(),
+                offsets: false,
+                predict: predict::State::new(conf),
+            }),
+            Err(kind) => Err(Error::new(kind).at(Operation::DecodeInit, conf, 0, 0)),
+        }
+    }
+
+    fn error(&self, kind: ErrorKind, operation: Operation) -> Error {
+        Error::new(kind).at(
+            operation,
+            &self.conf,
+            self.inner.total_in(),
+            self.inner.total_out(),
+        )
     }
 
     pub fn end(&mut self) -> Result<(), Error> {
-        if !self.0.state.is_null() {
-            let r = unsafe { aec_decode_end(&mut self.0) };
-            self.0.state = std::ptr::null_mut();
-            Error::from_int(r)
-        } else {
-            Ok(())
-        }
+        self.inner
+            .end()
+            .map_err(|kind| self.error(kind, Operation::DecodeEnd))
     }
 
     pub fn is_ended(&self) -> bool {
-        self.0.state.is_null()
+        self.inner.is_ended()
+    }
+
+    /// Return the decoder to its starting state, to decode a new
+    /// stream. This works whether or not the last stream was
+    /// finished or ended. Offsets stay enabled, and limits are kept.
+    ///
+    /// As with [`Encoder::reset`], this saves no allocation with
+    /// libaec.
+    pub fn reset(&mut self) -> Result<(), Error> {
+        self.inner
+            .reset()
+            .map_err(|kind| self.error(kind, Operation::DecodeInit))?;
+        self.restart()
+    }
+
+    /// Reset the decoder to decode a new stream with `conf`, taking
+    /// its limits too.
+    pub fn reset_with(&mut self, conf: &Configuration) -> Result<(), Error> {
+        check_init(conf, Operation::DecodeInit)?;
+        self.inner
+            .reset_with(conf)
+            .map_err(|kind| Error::new(kind).at(Operation::DecodeInit, conf, 0, 0))?;
+        self.conf = conf.clone();
+        self.predict = predict::State::new(conf);
+        self.restart()
+    }
+
+    // start over everything kept outside the backend
+    fn restart(&mut self) -> Result<(), Error> {
+        if self.offsets {
+            self.enable_offsets()?;
+        }
+        if let Some(state) = &mut self.predict {
+            state.reset();
+        }
+        Ok(())
     }
 
+    /// Decode all of `input`, appending the samples to `output`, like
+    /// [`Configuration::decode_buffer`].
+    ///
+    /// The decoder is reset first if it has been used, so one decoder
+    /// can decode many buffers without being created again.
+    pub fn decode_buffer<'a>(
+        &mut self,
+        mut input: &[u8],
+        output: &'a mut Vec<u8>,
+    ) -> Result<&'a mut [u8], Error> {
+        if self.is_ended() || self.inner.total_in() > 0 || self.inner.total_out() > 0 {
+            self.reset()?;
+        }
+        let start = output.len();
+        loop {
+            let (rest, _, status) = self.decode(input, output, false)?;
+            input = rest;
+            if status != Status::NeedOutput {
+                break;
+            }
+            output.reserve(DEFAULT_BUFFER_SIZE);
+        }
+        Ok(&mut output[start..])
+    }
+
+    // A complete stream decodes to whole blocks, as the encoder pads
+    // the last one. The zero bits padding out the last byte only
+    // start a block, and produce nothing.
+    fn ends_on_block(&self) -> bool {
+        let storage = sample::storage_size(self.conf.bits_per_sample, self.conf.flags);
+        self.inner
+            .total_out()
+            .is_multiple_of(storage * self.conf.block_size)
+    }
+
+    /// Replace the limits on what this decoder may produce, which
+    /// start out as those of its configuration.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.conf.limits = limits;
+    }
+
+    /// Record the bit offset of each reference sample interval as
+    /// it is decoded, to index existing data for use with
+    /// [`Configuration::decode_range`].
+    ///
+    /// This must be called before any data is decoded.
+    pub fn enable_offsets(&mut self) -> Result<(), Error> {
+        self.inner
+            .enable_offsets()
+            .map_err(|kind| self.error(kind, Operation::DecodeInit))?;
+        self.offsets = true;
+        Ok(())
+    }
+
+    /// The offsets recorded so far, if enabled with
+    /// [`Decoder::enable_offsets`].
+    pub fn offsets(&mut self) -> Result<Vec<usize>, Error> {
+        self.inner
+            .offsets()
+            .map_err(|kind| self.error(kind, Operation::Decode))
+    }
+
+    fn layout<T: Sample>(&self) -> Result<sample::Layout, Error> {
+        sample::Layout::new::<T>(self.conf.bits_per_sample, self.conf.flags)
+            .ok_or_else(|| self.error(ErrorKind::Configuration, Operation::Decode))
+    }
+
+    /// Decode samples of type `T`.
+    ///
+    /// The decoder must have been configured with a sample size and
+    /// signedness compatible with `T`, as returned by
+    /// [`Configuration::sample_decoder`].
+    pub fn decode_samples<'i, 'o, T>(
+        &mut self,
+        input: &'i [u8],
+        output: &'o mut [T],
+        flush: bool,
+    ) -> Result<(&'i [u8], &'o mut [T], Status), Error>
+    where
+        T: Sample,
+    {
+        let layout = self.layout::<T>()?;
+        if layout.native {
+            let (rest, out, status) = self.decode(input, sample::as_bytes_mut(output), flush)?;
+            let produced = out.len() / layout.storage;
+            let output = &mut output[..produced];
+            layout.extend(output);
+            return Ok((rest, output, status));
+        }
+
+        // storage is narrower than T, so convert a chunk at a time
+        let mut scratch = sample::scratch();
+        let n = output.len().min(scratch.len() / layout.storage);
+        let (rest, out, status) = self.decode(input, &mut scratch[..n * layout.storage], flush)?;
+        let produced = out.len() / layout.storage;
+        layout.load(out, &mut output[..produced]);
+        Ok((rest, &mut output[..produced], status))
+    }
+
+    /// Decode `input` into `output`. If `flush` is set, the
+    /// [`Status`] says whether the data so far is a whole stream.
     pub fn decode<'i, 'o, B>(
         &mut self,
         input: &'i [u8],
         output: &'o mut B,
         flush: bool,
-    ) -> Result<(&'i [u8], &'o mut [u8]), Error>
+    ) -> Result<(&'i [u8], &'o mut [u8], Status), Error>
     where
         B: Buffer + ?Sized,
     {
-        if self.0.state.is_null() {
-            return Err(Error::Stream);
+        if self.is_ended() {
+            return Err(self.error(ErrorKind::Stream, Operation::Decode));
         }
-        self.0.next_in = input.as_ptr();
-        self.0.avail_in = input.len() as size_t;
-        let (outptr, outlen) = output.write_info();
-        self.0.next_out = outptr;
-        self.0.avail_out = outlen as size_t;
-        let flush = if flush { AEC_FLUSH } else { AEC_NO_FLUSH };
-        unsafe {
-            let result = aec_decode(&mut self.0, flush);
-            Error::from_int(result).map(move |_| {
-                (
-                    &input[input.len() - self.0.avail_in..],
-                    output.write_data(outlen - self.0.avail_out),
-                )
-            })
+        let storage = sample::storage_size(self.conf.bits_per_sample, self.conf.flags);
+        let (outptr, mut outlen) = output.write_info();
+        if let Some(room) = self.conf.limits.room(self.inner.total_out()) {
+            // leave room for one sample past the limit, so that
+            // exceeding it is noticed
+            outlen = outlen.min(room.saturating_add(storage));
         }
-    }
-}
-
-impl Drop for Decoder {
-    fn drop(&mut self) {
-        let _ = self.end();
+        if let Some(state) = &self.predict {
+            // residuals can only be undone a whole sample at a time
+            outlen -= outlen % state.storage();
+        }
+        let out = unsafe { std::slice::from_raw_parts_mut(outptr as *mut MaybeUninit<u8>, outlen) };
+        let (consumed, produced) = self
+            .inner
+            .decode(input, out, flush)
+            .map_err(|kind| self.error(kind, Operation::Decode))?;
+        self.conf
+            .limits
+            .check(self.inner.total_in(), self.inner.total_out())
+            .map_err(|kind| self.error(kind, Operation::Decode))?;
+        // the decoder takes all of its input unless it runs out of
+        // room for a sample
+        let status = if outlen - produced < storage {
+            Status::NeedOutput
+        } else if flush && self.ends_on_block() {
+            Status::StreamEnd
+        } else {
+            Status::NeedInput
+        };
+        let written = unsafe { output.write_data(produced) };
+        if let Some(state) = &mut self.predict {
+            state.inverse(written);
+        }
+        Ok((&input[consumed..], written, status))
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{Configuration, Flags};
+    use super::{Configuration, Decoder, Encoder, ErrorKind, Flags, Limits, Status};
 
     #[test]
     fn roundtrip_stream_vec() {
@@ -341,11 +849,11 @@ mod test {
         let mut decompressed = Vec::with_capacity(data.len() + 1);
         decompressed.push(42);
 
-        let (unused, _) = enc.encode(data, &mut compressed, true).unwrap();
+        let (unused, _, _) = enc.encode(data, &mut compressed, true).unwrap();
         assert_eq!(unused.len(), 0);
         enc.end().unwrap();
 
-        let (unused, _) = dec
+        let (unused, _, _) = dec
             .decode(&compressed[1..], &mut decompressed, true)
             .unwrap();
         assert_eq!(unused.len(), 0);
@@ -356,6 +864,74 @@ mod test {
         assert_eq!(&decompressed[1..], data);
     }
 
+    #[test]
+    fn status() {
+        let conf = Configuration::new(16, 16, 8, Flags::DATA_PREPROCESS);
+        let data: Vec<u8> = (0..3000u32)
+            .flat_map(|i| ((i * i / 3) as u16).to_le_bytes())
+            .collect();
+
+        // drive the encoder with a small fixed buffer until it says
+        // the stream is done
+        let mut enc = conf.encoder().unwrap();
+        let mut input = &data[..];
+        let mut encoded = vec![];
+        loop {
+            let mut buf = Vec::with_capacity(100);
+            let (rest, out, status) = enc.encode(input, &mut buf, true).unwrap();
+            encoded.extend_from_slice(out);
+            input = rest;
+            match status {
+                Status::NeedOutput => assert_eq!(buf.len(), 100),
+                Status::NeedInput => unreachable!(),
+                Status::StreamEnd => break,
+            }
+        }
+        let mut expected = vec![];
+        conf.encode_buffer(&data, &mut expected).unwrap();
+        assert_eq!(encoded, expected);
+
+        let mut dec = conf.decoder().unwrap();
+        let mut out = Vec::with_capacity(data.len() * 2);
+        let half = encoded.len() / 2;
+        let (_, _, status) = dec.decode(&encoded[..half], &mut out, true).unwrap();
+        assert_eq!(status, Status::NeedInput);
+        let (_, _, status) = dec.decode(&encoded[half..], &mut out, true).unwrap();
+        assert_eq!(status, Status::StreamEnd);
+        assert_eq!(&out[..data.len()], data);
+    }
+
+    #[test]
+    fn threads() {
+        fn send<T: Send>() {}
+        fn sync<T: Sync>() {}
+        send::<Encoder>();
+        send::<Decoder>();
+        send::<Configuration>();
+        sync::<Configuration>();
+
+        let conf = std::sync::Arc::new(Configuration::new(8, 16, 32, Flags::DATA_PREPROCESS));
+        let data: Vec<u8> = (0..2048u32).map(|i| (i * i / 9) as u8).collect();
+        let mut enc = conf.encoder().unwrap();
+        let mut dec = conf.decoder().unwrap();
+        let worker = {
+            let conf = conf.clone();
+            let data = data.clone();
+            std::thread::spawn(move || {
+                let mut encoded = vec![];
+                enc.encode_buffer(&data, &mut encoded).unwrap();
+                let mut expected = vec![];
+                conf.encode_buffer(&data, &mut expected).unwrap();
+                assert_eq!(encoded, expected);
+                encoded
+            })
+        };
+        let encoded = worker.join().unwrap();
+        let mut decoded = vec![];
+        dec.decode_buffer(&encoded, &mut decoded).unwrap();
+        assert_eq!(&decoded[..data.len()], data);
+    }
+
     #[test]
     fn roundtrip_buffer() {
         let bits_per_sample = 8;
@@ -372,4 +948,191 @@ mod test {
         conf.decode_buffer(&encoded, &mut decoded).unwrap();
         assert_eq!(decoded, data);
     }
+
+    #[test]
+    fn roundtrip_samples() {
+        let data: Vec<i16> = (0..256).map(|i| (i * 37 % 200 - 100) as i16).collect();
+        for &bits in &[8, 12, 16] {
+            let conf = Configuration::new(bits, 16, 32, Flags::DATA_PREPROCESS);
+            let mut encoded = vec![];
+            let mut decoded = vec![0i16; data.len()];
+            conf.encode_samples(&data, &mut encoded).unwrap();
+            let decoded = conf.decode_samples(&encoded, &mut decoded).unwrap();
+            assert_eq!(decoded, &data[..]);
+        }
+    }
+
+    #[test]
+    fn samples_too_wide() {
+        let conf = Configuration::new(12, 16, 32, Flags::empty());
+        assert!(conf.sample_encoder::<u8>().is_err());
+        assert!(conf.sample_decoder::<u16>().is_ok());
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn too_big_for_c() {
+        // these would fit if truncated to 32 bits
+        let big = 1 << 32;
+        for conf in &[
+            Configuration::new(big + 8, 16, 32, Flags::empty()),
+            Configuration::new(8, 16, big + 1, Flags::empty()),
+        ] {
+            let err = conf.encoder().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Configuration);
+            let err = conf.decoder().unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Configuration);
+        }
+    }
+
+    #[test]
+    fn decode_range() {
+        let conf = Configuration::new(16, 16, 4, Flags::DATA_PREPROCESS);
+        let data: Vec<u8> = (0..1000u32)
+            .flat_map(|i| ((i * i / 7) as u16).to_le_bytes())
+            .collect();
+
+        let mut enc = conf.encoder().unwrap();
+        enc.enable_offsets().unwrap();
+        let mut compressed = Vec::with_capacity(data.len() * 2);
+        let (unused, _, _) = enc.encode(&data, &mut compressed, true).unwrap();
+        assert_eq!(unused.len(), 0);
+        let offsets = enc.offsets().unwrap();
+        enc.end().unwrap();
+        // 1000 samples in intervals of 64
+        assert_eq!(offsets.len(), 16);
+        assert_eq!(offsets[0], 0);
+
+        // decoding finds the same offsets
+        let mut dec = conf.decoder().unwrap();
+        dec.enable_offsets().unwrap();
+        let mut decompressed = Vec::with_capacity(data.len());
+        dec.decode(&compressed, &mut decompressed, true).unwrap();
+        assert_eq!(dec.offsets().unwrap()[..offsets.len()], offsets[..]);
+
+        for range in [0..1, 10..64, 64..128, 100..300, 999..1000] {
+            let mut out = vec![];
+            conf.decode_range(&compressed, &offsets, range.clone(), &mut out)
+                .unwrap();
+            assert_eq!(out, &data[range.start * 2..range.end * 2]);
+        }
+
+        let mut out = vec![];
+        let e = conf
+            .decode_range(&compressed, &offsets[..2], 500..510, &mut out)
+            .unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Offsets);
+        assert!(conf.encoder().unwrap().offsets().is_err());
+    }
+
+    #[test]
+    fn limits() {
+        let conf = Configuration::new(16, 16, 32, Flags::DATA_PREPROCESS);
+        let data = vec![0u8; 1 << 16];
+        let mut compressed = vec![];
+        conf.encode_buffer(&data, &mut compressed).unwrap();
+
+        let limited = |limits| conf.clone().with_limits(limits);
+        let mut out = vec![];
+        limited(Limits {
+            max_output: Some(data.len()),
+            max_ratio: None,
+        })
+        .decode_buffer(&compressed, &mut out)
+        .unwrap();
+        assert_eq!(out, data);
+
+        for &limits in &[
+            Limits {
+                max_output: Some(1000),
+                max_ratio: None,
+            },
+            Limits {
+                max_output: None,
+                max_ratio: Some(10),
+            },
+        ] {
+            let mut out = vec![];
+            let e = limited(limits)
+                .decode_buffer(&compressed, &mut out)
+                .unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::Limit);
+
+            let mut reader = limited(limits).decode_reader(&compressed[..]).unwrap();
+            let e = std::io::copy(&mut reader, &mut std::io::sink()).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+
+            let mut writer = limited(limits).decode_writer(std::io::sink()).unwrap();
+            let e = std::io::Write::write_all(&mut writer, &compressed).unwrap_err();
+            assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+        }
+
+        // limits can also be set on a decoder directly
+        let mut dec = conf.decoder().unwrap();
+        dec.set_limits(Limits {
+            max_output: Some(10),
+            max_ratio: None,
+        });
+        let mut out = Vec::with_capacity(data.len());
+        let e = dec.decode(&compressed, &mut out, true).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::Limit);
+    }
+
+    #[test]
+    fn reset() {
+        let conf = Configuration::new(16, 16, 4, Flags::DATA_PREPROCESS);
+        let tiles: Vec<Vec<u8>> = (0..3u32)
+            .map(|t| {
+                (0..1000u32)
+                    .flat_map(|i| ((i * (t + 1)) as u16).to_le_bytes())
+                    .collect()
+            })
+            .collect();
+
+        let mut enc = conf.encoder().unwrap();
+        let mut dec = conf.decoder().unwrap();
+        enc.enable_offsets().unwrap();
+        for tile in &tiles {
+            let mut expected = vec![];
+            conf.encode_buffer(tile, &mut expected).unwrap();
+            let mut encoded = vec![];
+            enc.encode_buffer(tile, &mut encoded).unwrap();
+            assert_eq!(encoded, expected);
+            assert_eq!(enc.offsets().unwrap().len(), 1000 / 64 + 1);
+
+            let mut decoded = vec![];
+            dec.decode_buffer(&encoded, &mut decoded).unwrap();
+            assert_eq!(&decoded[..tile.len()], &tile[..]);
+        }
+
+        // in the middle of a stream, or after it has ended
+        let mut partial = Vec::with_capacity(100);
+        enc.reset().unwrap();
+        enc.encode(&tiles[0][..500], &mut partial, false).unwrap();
+        enc.reset().unwrap();
+        let mut encoded = vec![];
+        enc.encode_all(&tiles[1], &mut encoded).unwrap();
+        let mut expected = vec![];
+        conf.encode_buffer(&tiles[1], &mut expected).unwrap();
+        assert_eq!(encoded, expected);
+        enc.end().unwrap();
+        enc.reset().unwrap();
+        assert!(!enc.is_ended());
+
+        // and with a different configuration
+        let other = Configuration::new(8, 8, 16, Flags::empty());
+        enc.reset_with(&other).unwrap();
+        dec.reset_with(&other).unwrap();
+        let mut encoded = vec![];
+        enc.encode_buffer(&tiles[2], &mut encoded).unwrap();
+        let mut expected = vec![];
+        other.encode_buffer(&tiles[2], &mut expected).unwrap();
+        assert_eq!(encoded, expected);
+        let mut decoded = vec![];
+        dec.decode_buffer(&encoded, &mut decoded).unwrap();
+        assert_eq!(&decoded[..tiles[2].len()], &tiles[2][..]);
+
+        let bad = Configuration::new(0, 8, 16, Flags::empty());
+        assert!(enc.reset_with(&bad).is_err());
+    }
 }
@@ -0,0 +1,272 @@
+//! Encoder and decoder backed by the C libaec library.
+
+use crate::{Configuration, ErrorKind, Flags};
+
+use libaec_sys::*;
+use libc::{c_int, c_uint, size_t};
+
+use std::convert::TryFrom;
+use std::mem::MaybeUninit;
+
+// Flags are defined without reference to libaec, so make sure they
+// agree with the C library
+const _: () = {
+    assert!(Flags::DATA_SIGNED.bits() == AEC_DATA_SIGNED);
+    assert!(Flags::DATA_3BYTE.bits() == AEC_DATA_3BYTE);
+    assert!(Flags::DATA_MSB.bits() == AEC_DATA_MSB);
+    assert!(Flags::DATA_PREPROCESS.bits() == AEC_DATA_PREPROCESS);
+    assert!(Flags::RESTRICTED.bits() == AEC_RESTRICTED);
+    assert!(Flags::PAD_RSI.bits() == AEC_PAD_RSI);
+    assert!(Flags::NOT_ENFORCE.bits() == AEC_NOT_ENFORCE);
+};
+
+fn check(v: c_int) -> Result<(), ErrorKind> {
+    match v {
+        AEC_OK => Ok(()),
+        AEC_CONF_ERROR => Err(ErrorKind::Configuration),
+        AEC_STREAM_ERROR => Err(ErrorKind::Stream),
+        AEC_DATA_ERROR => Err(ErrorKind::Data),
+        AEC_MEM_ERROR => Err(ErrorKind::Memory),
+        AEC_RSI_OFFSETS_ERROR => Err(ErrorKind::Offsets),
+        // this is a lie, but it should also never happen
+        // and I'd rather *this* than panic
+        _ => Err(ErrorKind::Configuration),
+    }
+}
+
+// libaec takes its parameters as unsigned ints, so anything too big
+// for one is a configuration error rather than something to truncate
+fn stream(conf: &Configuration) -> Result<aec_stream, ErrorKind> {
+    let uint = |v: usize| c_uint::try_from(v).map_err(|_| ErrorKind::Configuration);
+    Ok(aec_stream {
+        next_in: std::ptr::null(),
+        avail_in: 0,
+        total_in: 0,
+        next_out: std::ptr::null_mut(),
+        avail_out: 0,
+        total_out: 0,
+        bits_per_sample: uint(conf.bits_per_sample())?,
+        block_size: uint(conf.block_size())?,
+        rsi: uint(conf.rsi())?,
+        flags: conf.flags().bits() as c_uint,
+        state: std::ptr::null_mut(),
+    })
+}
+
+// run one step of the coder, returning (consumed, produced)
+fn step(
+    strm: &mut aec_stream,
+    f: unsafe extern "C" fn(*mut aec_stream, c_int) -> c_int,
+    input: &[u8],
+    output: &mut [MaybeUninit<u8>],
+    flush: bool,
+) -> Result<(usize, usize), ErrorKind> {
+    strm.next_in = input.as_ptr();
+    strm.avail_in = input.len() as size_t;
+    strm.next_out = output.as_mut_ptr() as *mut u8;
+    strm.avail_out = output.len() as size_t;
+    let flush = if flush { AEC_FLUSH } else { AEC_NO_FLUSH };
+    let result = unsafe { f(strm, flush) };
+    check(result)?;
+    Ok((input.len() - strm.avail_in, output.len() - strm.avail_out))
+}
+
+// read recorded RSI offsets, with the count and get functions of
+// either the encoder or decoder
+fn offsets(
+    strm: &mut aec_stream,
+    count: unsafe extern "C" fn(*mut aec_stream, *mut size_t) -> c_int,
+    get: unsafe extern "C" fn(*mut aec_stream, *mut size_t, size_t) -> c_int,
+) -> Result<Vec<usize>, ErrorKind> {
+    let mut n = 0;
+    check(unsafe { count(strm, &mut n) })?;
+    let mut offsets = vec![0; n];
+    check(unsafe { get(strm, offsets.as_mut_ptr(), n) })?;
+    Ok(offsets)
+}
+
+/// Decode `output.len()` bytes starting at byte `pos` of the decoded
+/// data, using the RSI offsets of `input`.
+pub(crate) fn decode_range(
+    conf: &Configuration,
+    input: &[u8],
+    rsi_offsets: &[usize],
+    pos: usize,
+    output: &mut [MaybeUninit<u8>],
+) -> Result<(), ErrorKind> {
+    let mut dec = Decoder::new(conf)?;
+    let strm = &mut dec.0;
+    strm.next_in = input.as_ptr();
+    strm.avail_in = input.len();
+    strm.next_out = output.as_mut_ptr() as *mut u8;
+    strm.avail_out = output.len();
+    check(unsafe {
+        aec_decode_range(
+            strm,
+            rsi_offsets.as_ptr(),
+            rsi_offsets.len(),
+            pos,
+            output.len(),
+        )
+    })?;
+    dec.end()
+}
+
+#[derive(Debug)]
+pub(crate) struct Encoder(aec_stream);
+
+impl Encoder {
+    pub fn new(conf: &Configuration) -> Result<Self, ErrorKind> {
+        let mut s = Self(stream(conf)?);
+        check(unsafe { aec_encode_init(&mut s.0) })?;
+        Ok(s)
+    }
+
+    pub fn end(&mut self) -> Result<(), ErrorKind> {
+        if !self.0.state.is_null() {
+            let r = unsafe { aec_encode_end(&mut self.0) };
+            self.0.state = std::ptr::null_mut();
+            check(r)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// libaec has no way to reset a stream, so this ends it and
+    /// starts again with the same parameters. Its state is freed and
+    /// allocated again, so unlike the native coder this saves nothing.
+    pub fn reset(&mut self) -> Result<(), ErrorKind> {
+        self.end()?;
+        self.0.total_in = 0;
+        self.0.total_out = 0;
+        check(unsafe { aec_encode_init(&mut self.0) })
+    }
+
+    pub fn reset_with(&mut self, conf: &Configuration) -> Result<(), ErrorKind> {
+        self.end()?;
+        self.0 = stream(conf)?;
+        check(unsafe { aec_encode_init(&mut self.0) })
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.0.state.is_null()
+    }
+
+    pub fn total_in(&self) -> usize {
+        self.0.total_in
+    }
+
+    pub fn total_out(&self) -> usize {
+        self.0.total_out
+    }
+
+    pub fn encode(
+        &mut self,
+        input: &[u8],
+        output: &mut [MaybeUninit<u8>],
+        flush: bool,
+    ) -> Result<(usize, usize), ErrorKind> {
+        step(&mut self.0, aec_encode, input, output, flush)
+    }
+
+    pub fn enable_offsets(&mut self) -> Result<(), ErrorKind> {
+        check(unsafe { aec_encode_enable_offsets(&mut self.0) })
+    }
+
+    pub fn offsets(&mut self) -> Result<Vec<usize>, ErrorKind> {
+        offsets(
+            &mut self.0,
+            aec_encode_count_offsets,
+            aec_encode_get_offsets,
+        )
+    }
+}
+
+impl Drop for Encoder {
+    fn drop(&mut self) {
+        let _ = self.end();
+    }
+}
+
+// The raw pointers make aec_stream !Send, but libaec keeps all of a
+// stream's state in memory owned by that stream, with nothing global
+// or thread-local. next_in and next_out only point at our buffers for
+// the length of one call.
+unsafe impl Send for Encoder {}
+
+#[derive(Debug)]
+pub(crate) struct Decoder(aec_stream);
+
+impl Decoder {
+    pub fn new(conf: &Configuration) -> Result<Self, ErrorKind> {
+        let mut s = Self(stream(conf)?);
+        check(unsafe { aec_decode_init(&mut s.0) })?;
+        Ok(s)
+    }
+
+    pub fn end(&mut self) -> Result<(), ErrorKind> {
+        if !self.0.state.is_null() {
+            let r = unsafe { aec_decode_end(&mut self.0) };
+            self.0.state = std::ptr::null_mut();
+            check(r)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// As for the encoder, this ends the stream and starts again.
+    pub fn reset(&mut self) -> Result<(), ErrorKind> {
+        self.end()?;
+        self.0.total_in = 0;
+        self.0.total_out = 0;
+        check(unsafe { aec_decode_init(&mut self.0) })
+    }
+
+    pub fn reset_with(&mut self, conf: &Configuration) -> Result<(), ErrorKind> {
+        self.end()?;
+        self.0 = stream(conf)?;
+        check(unsafe { aec_decode_init(&mut self.0) })
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.0.state.is_null()
+    }
+
+    pub fn total_in(&self) -> usize {
+        self.0.total_in
+    }
+
+    pub fn total_out(&self) -> usize {
+        self.0.total_out
+    }
+
+    pub fn decode(
+        &mut self,
+        input: &[u8],
+        output: &mut [MaybeUninit<u8>],
+        flush: bool,
+    ) -> Result<(usize, usize), ErrorKind> {
+        step(&mut self.0, aec_decode, input, output, flush)
+    }
+
+    pub fn enable_offsets(&mut self) -> Result<(), ErrorKind> {
+        check(unsafe { aec_decode_enable_offsets(&mut self.0) })
+    }
+
+    pub fn offsets(&mut self) -> Result<Vec<usize>, ErrorKind> {
+        offsets(
+            &mut self.0,
+            aec_decode_count_offsets,
+            aec_decode_get_offsets,
+        )
+    }
+}
+
+impl Drop for Decoder {
+    fn drop(&mut self) {
+        let _ = self.end();
+    }
+}
+
+// as for Encoder
+unsafe impl Send for Decoder {}
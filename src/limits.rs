@@ -0,0 +1,61 @@
+use crate::ErrorKind;
+
+/// Limits on how much a [`Decoder`](crate::Decoder) may produce, to
+/// guard against corrupt or hostile streams.
+///
+/// Exceeding a limit fails with [`ErrorKind::Limit`]. Limits count
+/// every decoded byte, including any padding samples at the end of
+/// the stream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Limits {
+    /// Maximum number of decoded bytes.
+    pub max_output: Option<usize>,
+    /// Maximum ratio of decoded bytes to compressed bytes read so
+    /// far. A single byte of compressed zeros can decode to thousands
+    /// of bytes, so this should be generous.
+    pub max_ratio: Option<usize>,
+}
+
+impl Limits {
+    /// How many more bytes may be decoded after `total_out`, if
+    /// limited.
+    pub(crate) fn room(&self, total_out: usize) -> Option<usize> {
+        self.max_output.map(|max| max.saturating_sub(total_out))
+    }
+
+    pub(crate) fn check(&self, total_in: usize, total_out: usize) -> Result<(), ErrorKind> {
+        if let Some(max) = self.max_output {
+            if total_out > max {
+                return Err(ErrorKind::Limit);
+            }
+        }
+        if let Some(ratio) = self.max_ratio {
+            if total_out > total_in.saturating_mul(ratio) {
+                return Err(ErrorKind::Limit);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Limits;
+    use crate::ErrorKind;
+
+    #[test]
+    fn check() {
+        let limits = Limits {
+            max_output: Some(100),
+            max_ratio: Some(10),
+        };
+        assert_eq!(limits.room(40), Some(60));
+        assert_eq!(limits.room(140), Some(0));
+        assert_eq!(Limits::default().room(40), None);
+
+        assert!(limits.check(10, 100).is_ok());
+        assert_eq!(limits.check(20, 101), Err(ErrorKind::Limit));
+        assert_eq!(limits.check(5, 51), Err(ErrorKind::Limit));
+        assert!(Limits::default().check(0, usize::MAX).is_ok());
+    }
+}
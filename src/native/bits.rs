@@ -0,0 +1,221 @@
+use std::mem::MaybeUninit;
+
+/// Packs bits, most significant first, into a byte queue.
+#[derive(Clone, Debug, Default)]
+pub(super) struct BitWriter {
+    bytes: Vec<u8>,
+    // index of the first byte not yet handed out
+    pos: usize,
+    acc: u64,
+    // number of bits waiting in acc, always < 8 between calls
+    nbits: u32,
+    // total bits written
+    written: usize,
+    // true if anything at all has been written
+    used: bool,
+}
+
+impl BitWriter {
+    /// Write the low `bits` bits of `value`, with `bits` at most 32.
+    pub fn put(&mut self, value: u32, bits: u32) {
+        if bits == 0 {
+            return;
+        }
+        let value = value as u64 & (u64::MAX >> (64 - bits));
+        self.acc = (self.acc << bits) | value;
+        self.nbits += bits;
+        self.written += bits as usize;
+        self.used = true;
+        while self.nbits >= 8 {
+            self.nbits -= 8;
+            self.bytes.push((self.acc >> self.nbits) as u8);
+        }
+        self.acc &= (1 << self.nbits) - 1;
+    }
+
+    /// Write a fundamental sequence: `fs` zeros and then a one.
+    pub fn put_fs(&mut self, mut fs: u64) {
+        while fs >= 32 {
+            self.put(0, 32);
+            fs -= 32;
+        }
+        self.put(1, fs as u32 + 1);
+    }
+
+    /// Pad with zeros to a byte boundary.
+    pub fn pad(&mut self) {
+        if self.nbits > 0 {
+            self.put(0, 8 - self.nbits);
+        }
+    }
+
+    /// Pad the final byte, and make sure at least one byte is
+    /// produced, just like libaec.
+    pub fn finish(&mut self) {
+        if !self.used {
+            self.put(0, 8);
+        }
+        self.pad();
+    }
+
+    /// Bits written so far.
+    pub fn position(&self) -> usize {
+        self.written
+    }
+
+    /// Start over, keeping the allocated queue.
+    pub fn clear(&mut self) {
+        let mut bytes = std::mem::take(&mut self.bytes);
+        bytes.clear();
+        *self = Self {
+            bytes,
+            ..Self::default()
+        };
+    }
+
+    pub fn has_pending(&self) -> bool {
+        self.pos < self.bytes.len()
+    }
+
+    /// Copy finished bytes into `output`, returning how many.
+    pub fn drain(&mut self, output: &mut [MaybeUninit<u8>]) -> usize {
+        let n = (self.bytes.len() - self.pos).min(output.len());
+        for (o, b) in output.iter_mut().zip(&self.bytes[self.pos..self.pos + n]) {
+            *o = MaybeUninit::new(*b);
+        }
+        self.pos += n;
+        if self.pos == self.bytes.len() {
+            self.bytes.clear();
+            self.pos = 0;
+        }
+        n
+    }
+}
+
+/// Unpacks bits, most significant first, pulling whole bytes from the
+/// input only as needed.
+#[derive(Clone, Debug, Default)]
+pub(super) struct BitReader {
+    acc: u64,
+    nbits: u32,
+    // zeros counted so far in an unfinished fundamental sequence
+    fs: u64,
+    // total bytes taken from the input
+    read: usize,
+}
+
+impl BitReader {
+    // make sure `bits` bits are available, returning the number of
+    // bytes taken from the input
+    fn fill(&mut self, input: &[u8], bits: u32) -> (bool, usize) {
+        let mut used = 0;
+        while self.nbits < bits {
+            match input.get(used) {
+                Some(b) => {
+                    self.acc = (self.acc << 8) | *b as u64;
+                    self.nbits += 8;
+                    self.read += 1;
+                    used += 1;
+                }
+                None => return (false, used),
+            }
+        }
+        (true, used)
+    }
+
+    /// Read `bits` bits, at most 32. Returns `None` if the input ran
+    /// out; the bytes read so far are kept for the next call.
+    pub fn get(&mut self, input: &mut &[u8], bits: u32) -> Option<u32> {
+        if bits == 0 {
+            return Some(0);
+        }
+        let (ok, used) = self.fill(input, bits);
+        *input = &input[used..];
+        if !ok {
+            return None;
+        }
+        self.nbits -= bits;
+        let v = (self.acc >> self.nbits) as u32;
+        self.acc &= (1 << self.nbits) - 1;
+        Some(v)
+    }
+
+    /// Read a fundamental sequence, returning the number of zeros.
+    pub fn get_fs(&mut self, input: &mut &[u8]) -> Option<u64> {
+        loop {
+            if self.nbits == 0 {
+                let (ok, used) = self.fill(input, 1);
+                *input = &input[used..];
+                if !ok {
+                    return None;
+                }
+            }
+            if self.acc == 0 {
+                self.fs += self.nbits as u64;
+                self.nbits = 0;
+                continue;
+            }
+            // acc is nonzero and holds exactly nbits bits
+            let width = 64 - self.acc.leading_zeros();
+            let zeros = self.nbits - width;
+            self.nbits = width - 1;
+            self.acc &= (1 << self.nbits) - 1;
+            let fs = self.fs + zeros as u64;
+            self.fs = 0;
+            return Some(fs);
+        }
+    }
+
+    /// Bits consumed so far.
+    pub fn position(&self) -> usize {
+        self.read * 8 - self.nbits as usize
+    }
+
+    /// Zeros counted so far in an unfinished fundamental sequence.
+    pub fn pending_fs(&self) -> u64 {
+        self.fs
+    }
+
+    /// Discard bits up to the next byte boundary of the input.
+    pub fn align(&mut self) {
+        self.nbits -= self.nbits % 8;
+        self.acc &= (1u64 << self.nbits).wrapping_sub(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BitReader, BitWriter};
+    use std::mem::MaybeUninit;
+
+    #[test]
+    fn roundtrip() {
+        let mut w = BitWriter::default();
+        w.put(0b101, 3);
+        w.put_fs(40);
+        w.put(0xdead_beef, 32);
+        w.put_fs(0);
+        w.pad();
+        w.put(0x3, 2);
+        w.finish();
+        let mut out = [MaybeUninit::uninit(); 64];
+        let n = w.drain(&mut out);
+        let bytes: Vec<u8> = out[..n]
+            .iter()
+            .map(|b| unsafe { b.assume_init() })
+            .collect();
+
+        // split the input to exercise resuming
+        let mut r = BitReader::default();
+        let (mut first, mut second) = bytes.split_at(3);
+        assert_eq!(r.get(&mut first, 3), Some(0b101));
+        assert_eq!(r.get_fs(&mut first), None);
+        assert!(first.is_empty());
+        assert_eq!(r.get_fs(&mut second), Some(40));
+        assert_eq!(r.get(&mut second, 32), Some(0xdead_beef));
+        assert_eq!(r.get_fs(&mut second), Some(0));
+        r.align();
+        assert_eq!(r.get(&mut second, 2), Some(0x3));
+        assert!(second.is_empty());
+    }
+}
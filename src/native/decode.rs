@@ -0,0 +1,459 @@
+use super::bits::BitReader;
+use super::{Params, ROS, SEGMENT};
+use crate::{Configuration, ErrorKind};
+
+use std::convert::TryFrom;
+use std::mem::MaybeUninit;
+
+/// The largest fundamental sequence allowed in a second extension
+/// block, as in libaec.
+const SE_MAX: u64 = 90;
+
+/// Where the decoder is within a coded block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Id,
+    LowEntropy,
+    ZeroRef,
+    Zero,
+    ZeroOut,
+    SeRef,
+    Se,
+    SplitRef,
+    SplitFs,
+    SplitLsb,
+    Uncomp,
+}
+
+/// Output space for one call to [`Decoder::decode`].
+struct Sink<'a> {
+    buf: &'a mut [MaybeUninit<u8>],
+    n: usize,
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct Decoder {
+    p: Params,
+    bits: BitReader,
+    mode: Mode,
+    // whether the current block starts with a reference sample
+    has_ref: bool,
+    // split parameter of the current block
+    k: u32,
+    // position within the current block
+    i: usize,
+    // samples in the current run of zero blocks
+    zero_samples: usize,
+    // fundamental sequences of the current split block
+    fs: Vec<u32>,
+    fs_done: usize,
+    // blocks finished in the current interval
+    blocks: usize,
+    // last output sample, for undoing the preprocessor
+    last: u32,
+    // decoded bytes waiting for room in the output
+    pending: Vec<u8>,
+    pos: usize,
+    // bit offset of each interval, if enabled
+    offsets: Option<Vec<usize>>,
+    ended: bool,
+    total_in: usize,
+    total_out: usize,
+}
+
+impl Decoder {
+    pub fn new(conf: &Configuration) -> Result<Self, ErrorKind> {
+        let p = Params::new(conf)?;
+        let bs = p.block_size;
+        Ok(Self {
+            p,
+            bits: BitReader::default(),
+            mode: Mode::Id,
+            has_ref: false,
+            k: 0,
+            i: 0,
+            zero_samples: 0,
+            fs: vec![0; bs],
+            fs_done: 0,
+            blocks: 0,
+            last: 0,
+            pending: vec![],
+            pos: 0,
+            offsets: None,
+            ended: false,
+            total_in: 0,
+            total_out: 0,
+        })
+    }
+
+    pub fn end(&mut self) -> Result<(), ErrorKind> {
+        self.ended = true;
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> Result<(), ErrorKind> {
+        self.bits = BitReader::default();
+        self.mode = Mode::Id;
+        self.has_ref = false;
+        self.k = 0;
+        self.i = 0;
+        self.zero_samples = 0;
+        self.fs_done = 0;
+        self.blocks = 0;
+        self.last = 0;
+        self.pending.clear();
+        self.pos = 0;
+        self.offsets = None;
+        self.ended = false;
+        self.total_in = 0;
+        self.total_out = 0;
+        Ok(())
+    }
+
+    pub fn reset_with(&mut self, conf: &Configuration) -> Result<(), ErrorKind> {
+        let p = Params::new(conf)?;
+        self.fs.resize(p.block_size, 0);
+        self.p = p;
+        self.reset()
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.ended
+    }
+
+    pub fn total_in(&self) -> usize {
+        self.total_in
+    }
+
+    pub fn total_out(&self) -> usize {
+        self.total_out
+    }
+
+    pub fn decode(
+        &mut self,
+        input: &[u8],
+        output: &mut [MaybeUninit<u8>],
+        _flush: bool,
+    ) -> Result<(usize, usize), ErrorKind> {
+        let mut rest = input;
+        let mut out = Sink { buf: output, n: 0 };
+        let mut result = Ok(true);
+        while let Ok(true) = result {
+            result = self.step(&mut rest, &mut out);
+        }
+        // count what was done even on error, so it can be reported
+        let consumed = input.len() - rest.len();
+        self.total_in += consumed;
+        self.total_out += out.n;
+        result?;
+        Ok((consumed, out.n))
+    }
+
+    pub fn enable_offsets(&mut self) -> Result<(), ErrorKind> {
+        self.offsets.get_or_insert_with(Vec::new);
+        Ok(())
+    }
+
+    pub fn offsets(&mut self) -> Result<Vec<usize>, ErrorKind> {
+        self.offsets.clone().ok_or(ErrorKind::Offsets)
+    }
+
+    /// Move waiting bytes to the output, and return true if there is
+    /// room for another sample.
+    fn room(&mut self, out: &mut Sink) -> bool {
+        let n = (self.pending.len() - self.pos).min(out.buf.len() - out.n);
+        for (o, b) in out.buf[out.n..]
+            .iter_mut()
+            .zip(&self.pending[self.pos..self.pos + n])
+        {
+            *o = MaybeUninit::new(*b);
+        }
+        out.n += n;
+        self.pos += n;
+        if self.pos == self.pending.len() {
+            self.pending.clear();
+            self.pos = 0;
+            out.n < out.buf.len()
+        } else {
+            false
+        }
+    }
+
+    /// Output a decoded sample, undoing the preprocessor if needed.
+    fn put(&mut self, d: u32, out: &mut Sink) -> Result<(), ErrorKind> {
+        // a sample or mapped residual never needs more than n bits,
+        // and if it fits the reconstructed value stays in range too
+        if d > self.p.mask() {
+            return Err(ErrorKind::Data);
+        }
+        let v = if !self.p.preprocess {
+            d
+        } else if self.has_ref && self.i == 0 {
+            // reference samples are stored as-is
+            if self.p.signed {
+                self.p.extend(d)
+            } else {
+                d
+            }
+        } else if self.p.signed {
+            let xmax = self.p.xmax as i64;
+            let last = self.last as i32 as i64;
+            let d = d as i64;
+            let half = (d >> 1) + (d & 1);
+            let theta = if last < 0 {
+                xmax + last + 1
+            } else {
+                xmax - last
+            };
+            (if half <= theta {
+                if d & 1 == 0 {
+                    last + (d >> 1)
+                } else {
+                    last - half
+                }
+            } else if last < 0 {
+                d - xmax - 1
+            } else {
+                xmax - d
+            }) as u32
+        } else {
+            let xmax = self.p.xmax;
+            let upper = self.last > xmax - self.last;
+            let theta = if upper { xmax - self.last } else { self.last };
+            let half = (d >> 1) + (d & 1);
+            if half <= theta {
+                if d & 1 == 0 {
+                    self.last + (d >> 1)
+                } else {
+                    self.last - half
+                }
+            } else if upper {
+                xmax.wrapping_sub(d)
+            } else {
+                d
+            }
+        };
+        self.last = v;
+        self.p.write_sample(v, &mut self.pending);
+        self.i += 1;
+        self.room(out);
+        Ok(())
+    }
+
+    /// Finish a block, and move on to the next.
+    fn next_block(&mut self) {
+        self.blocks += 1;
+        if self.blocks >= self.p.rsi {
+            self.blocks = 0;
+            if self.p.pad_rsi {
+                self.bits.align();
+            }
+        }
+        self.mode = Mode::Id;
+    }
+
+    /// Run one step of decoding, returning false if more input or
+    /// more output space is needed.
+    fn step(&mut self, input: &mut &[u8], out: &mut Sink) -> Result<bool, ErrorKind> {
+        let n = self.p.bits_per_sample;
+        let bs = self.p.block_size;
+        match self.mode {
+            Mode::Id => {
+                if !self.room(out) {
+                    return Ok(false);
+                }
+                let start = self.bits.position();
+                let id = match self.bits.get(input, self.p.id_len) {
+                    Some(id) => id,
+                    None => return Ok(false),
+                };
+                if let (0, Some(offsets)) = (self.blocks, &mut self.offsets) {
+                    offsets.push(start);
+                }
+                self.has_ref = self.p.preprocess && self.blocks == 0;
+                self.i = 0;
+                self.mode = if id == 0 {
+                    Mode::LowEntropy
+                } else if id == self.p.id_uncomp() {
+                    Mode::Uncomp
+                } else {
+                    self.k = id - 1;
+                    Mode::SplitRef
+                };
+            }
+            Mode::LowEntropy => {
+                self.mode = match self.bits.get(input, 1) {
+                    Some(0) => Mode::ZeroRef,
+                    Some(_) => Mode::SeRef,
+                    None => return Ok(false),
+                };
+            }
+            Mode::ZeroRef | Mode::SeRef | Mode::SplitRef => {
+                if self.has_ref {
+                    if !self.room(out) {
+                        return Ok(false);
+                    }
+                    match self.bits.get(input, n) {
+                        Some(r) => self.put(r, out)?,
+                        None => return Ok(false),
+                    }
+                }
+                self.mode = match self.mode {
+                    Mode::ZeroRef => Mode::Zero,
+                    Mode::SeRef => Mode::Se,
+                    _ => Mode::SplitFs,
+                };
+            }
+            Mode::Zero => {
+                let fs = match self.bits.get_fs(input) {
+                    Some(fs) => fs,
+                    None => return self.check_fs(SEGMENT as u64),
+                };
+                let remaining = self.p.rsi - self.blocks;
+                let count = match fs + 1 {
+                    c if c == ROS as u64 => remaining.min(SEGMENT - self.blocks % SEGMENT),
+                    c if c > ROS as u64 => fs as usize,
+                    c => c as usize,
+                };
+                if count > remaining {
+                    return Err(ErrorKind::Data);
+                }
+                self.blocks += count - 1;
+                self.zero_samples = count * bs;
+                self.mode = Mode::ZeroOut;
+            }
+            Mode::ZeroOut => {
+                while self.i < self.zero_samples {
+                    if !self.room(out) {
+                        return Ok(false);
+                    }
+                    self.put(0, out)?;
+                }
+                self.next_block();
+            }
+            Mode::Se => {
+                while self.i < bs {
+                    if !self.room(out) {
+                        return Ok(false);
+                    }
+                    let m = match self.bits.get_fs(input) {
+                        Some(m) => m,
+                        None => return self.check_fs(SE_MAX),
+                    };
+                    if m > SE_MAX {
+                        return Err(ErrorKind::Data);
+                    }
+                    // find the largest beta with beta(beta+1)/2 <= m
+                    let mut beta = 0;
+                    while (beta + 1) * (beta + 2) / 2 <= m {
+                        beta += 1;
+                    }
+                    let d1 = m - beta * (beta + 1) / 2;
+                    if self.i & 1 == 0 {
+                        self.put((beta - d1) as u32, out)?;
+                    }
+                    self.put(d1 as u32, out)?;
+                }
+                self.next_block();
+            }
+            Mode::SplitFs => {
+                // only the reference sample has been output so far
+                let skip = self.has_ref as usize;
+                let limit = (bs * n as usize) as u64;
+                while skip + self.fs_done < bs {
+                    let fs = match self.bits.get_fs(input) {
+                        Some(fs) => fs,
+                        None => return self.check_fs(limit),
+                    };
+                    if fs > limit {
+                        return Err(ErrorKind::Data);
+                    }
+                    self.fs[skip + self.fs_done] = fs as u32;
+                    self.fs_done += 1;
+                }
+                self.fs_done = 0;
+                self.mode = Mode::SplitLsb;
+            }
+            Mode::SplitLsb => {
+                while self.i < bs {
+                    if !self.room(out) {
+                        return Ok(false);
+                    }
+                    let lsb = match self.bits.get(input, self.k) {
+                        Some(lsb) => lsb,
+                        None => return Ok(false),
+                    };
+                    let fs = self.fs[self.i] as u64;
+                    let d = u32::try_from(fs << self.k | lsb as u64);
+                    self.put(d.map_err(|_| ErrorKind::Data)?, out)?;
+                }
+                self.next_block();
+            }
+            Mode::Uncomp => {
+                while self.i < bs {
+                    if !self.room(out) {
+                        return Ok(false);
+                    }
+                    match self.bits.get(input, n) {
+                        Some(v) => self.put(v, out)?,
+                        None => return Ok(false),
+                    }
+                }
+                self.next_block();
+            }
+        }
+        Ok(true)
+    }
+
+    /// Called when a fundamental sequence is unfinished, to stop
+    /// corrupt data from reading zeros forever.
+    fn check_fs(&self, limit: u64) -> Result<bool, ErrorKind> {
+        if self.bits.pending_fs() > limit {
+            Err(ErrorKind::Data)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+/// Decode `output.len()` bytes starting at byte `pos` of the decoded
+/// data, using the RSI offsets of `input`.
+pub(crate) fn decode_range(
+    conf: &Configuration,
+    input: &[u8],
+    rsi_offsets: &[usize],
+    pos: usize,
+    output: &mut [MaybeUninit<u8>],
+) -> Result<(), ErrorKind> {
+    let mut dec = Decoder::new(conf)?;
+    let rsi_bytes = dec.p.rsi_len() * dec.p.storage;
+    let start = *rsi_offsets.get(pos / rsi_bytes).ok_or(ErrorKind::Offsets)?;
+    let mut input = input.get(start / 8..).ok_or(ErrorKind::Offsets)?;
+    dec.bits
+        .get(&mut input, (start % 8) as u32)
+        .ok_or(ErrorKind::Data)?;
+
+    // decode and throw away the start of the interval
+    let mut skip = pos % rsi_bytes;
+    let mut scratch = [MaybeUninit::uninit(); 256];
+    while skip > 0 {
+        let n = skip.min(scratch.len());
+        let (consumed, produced) = dec.decode(input, &mut scratch[..n], true)?;
+        if produced == 0 {
+            return Err(ErrorKind::Data);
+        }
+        input = &input[consumed..];
+        skip -= produced;
+    }
+
+    let mut done = 0;
+    while done < output.len() {
+        let (consumed, produced) = dec.decode(input, &mut output[done..], true)?;
+        if produced == 0 {
+            return Err(ErrorKind::Data);
+        }
+        input = &input[consumed..];
+        done += produced;
+    }
+    Ok(())
+}
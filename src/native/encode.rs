@@ -0,0 +1,383 @@
+use super::bits::BitWriter;
+use super::{Params, ROS, SEGMENT};
+use crate::{Configuration, ErrorKind};
+
+use std::mem::MaybeUninit;
+
+#[derive(Clone, Debug)]
+pub(crate) struct Encoder {
+    p: Params,
+    // raw samples of the current reference sample interval
+    raw: Vec<u32>,
+    // preprocessed samples
+    d: Vec<u32>,
+    out: BitWriter,
+    // k of the previous split block, a starting point for the next
+    k: u32,
+    // bit offset of each interval, if enabled
+    offsets: Option<Vec<usize>>,
+    flushed: bool,
+    ended: bool,
+    total_in: usize,
+    total_out: usize,
+}
+
+impl Encoder {
+    pub fn new(conf: &Configuration) -> Result<Self, ErrorKind> {
+        let p = Params::new(conf)?;
+        let rsi_len = p.rsi_len();
+        Ok(Self {
+            p,
+            raw: Vec::with_capacity(rsi_len),
+            d: vec![0; rsi_len],
+            out: BitWriter::default(),
+            k: 0,
+            offsets: None,
+            flushed: false,
+            ended: false,
+            total_in: 0,
+            total_out: 0,
+        })
+    }
+
+    pub fn end(&mut self) -> Result<(), ErrorKind> {
+        self.ended = true;
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> Result<(), ErrorKind> {
+        self.raw.clear();
+        self.out.clear();
+        self.k = 0;
+        self.offsets = None;
+        self.flushed = false;
+        self.ended = false;
+        self.total_in = 0;
+        self.total_out = 0;
+        Ok(())
+    }
+
+    pub fn reset_with(&mut self, conf: &Configuration) -> Result<(), ErrorKind> {
+        let p = Params::new(conf)?;
+        let rsi_len = p.rsi_len();
+        self.raw.clear();
+        self.raw.reserve(rsi_len);
+        self.d.resize(rsi_len, 0);
+        self.p = p;
+        self.reset()
+    }
+
+    pub fn is_ended(&self) -> bool {
+        self.ended
+    }
+
+    pub fn total_in(&self) -> usize {
+        self.total_in
+    }
+
+    pub fn total_out(&self) -> usize {
+        self.total_out
+    }
+
+    pub fn encode(
+        &mut self,
+        input: &[u8],
+        output: &mut [MaybeUninit<u8>],
+        flush: bool,
+    ) -> Result<(usize, usize), ErrorKind> {
+        let storage = self.p.storage;
+        let rsi_len = self.p.rsi_len();
+        let mut consumed = 0;
+        let mut produced = 0;
+        loop {
+            produced += self.out.drain(&mut output[produced..]);
+            if self.out.has_pending() {
+                break;
+            }
+            if self.raw.len() == rsi_len {
+                self.encode_rsi(self.p.rsi);
+                self.raw.clear();
+                continue;
+            }
+            if self.flushed {
+                break;
+            }
+            let avail = (input.len() - consumed) / storage;
+            if avail > 0 {
+                let n = avail.min(rsi_len - self.raw.len());
+                let bytes = &input[consumed..consumed + n * storage];
+                let p = &self.p;
+                self.raw
+                    .extend(bytes.chunks_exact(storage).map(|s| p.read_sample(s)));
+                consumed += n * storage;
+                continue;
+            }
+            if flush {
+                if !self.raw.is_empty() {
+                    // pad the last interval with its last sample, but
+                    // only code the blocks that hold real data
+                    let blocks = self.raw.len().div_ceil(self.p.block_size);
+                    let last = self.raw[self.raw.len() - 1];
+                    self.raw.resize(rsi_len, last);
+                    self.encode_rsi(blocks);
+                    self.raw.clear();
+                }
+                self.out.finish();
+                self.flushed = true;
+                continue;
+            }
+            break;
+        }
+        self.total_in += consumed;
+        self.total_out += produced;
+        Ok((consumed, produced))
+    }
+
+    pub fn enable_offsets(&mut self) -> Result<(), ErrorKind> {
+        self.offsets.get_or_insert_with(Vec::new);
+        Ok(())
+    }
+
+    pub fn offsets(&mut self) -> Result<Vec<usize>, ErrorKind> {
+        self.offsets.clone().ok_or(ErrorKind::Offsets)
+    }
+
+    fn preprocess(&mut self) {
+        let x = &self.raw;
+        let d = &mut self.d;
+        d[0] = 0;
+        if self.p.signed {
+            let xmax = self.p.xmax as i64;
+            let xmin = -xmax - 1;
+            let mut prev = self.p.extend(x[0]) as i32 as i64;
+            for i in 1..x.len() {
+                let cur = self.p.extend(x[i]) as i32 as i64;
+                d[i] = if cur < prev {
+                    let delta = prev - cur;
+                    if delta <= xmax - prev {
+                        2 * delta - 1
+                    } else {
+                        xmax - cur
+                    }
+                } else {
+                    let delta = cur - prev;
+                    if delta <= prev - xmin {
+                        2 * delta
+                    } else {
+                        cur - xmin
+                    }
+                } as u32;
+                prev = cur;
+            }
+        } else {
+            let xmax = self.p.xmax as u64;
+            for i in 1..x.len() {
+                let (prev, cur) = (x[i - 1] as u64, x[i] as u64);
+                d[i] = if cur >= prev {
+                    let delta = cur - prev;
+                    if delta <= prev {
+                        2 * delta
+                    } else {
+                        cur
+                    }
+                } else {
+                    let delta = prev - cur;
+                    if delta <= xmax - prev {
+                        2 * delta - 1
+                    } else {
+                        xmax - cur
+                    }
+                } as u32;
+            }
+        }
+    }
+
+    /// Code the first `blocks` blocks of the current interval.
+    fn encode_rsi(&mut self, blocks: usize) {
+        let bs = self.p.block_size;
+        let ref_sample = self.raw[0];
+        if let Some(offsets) = &mut self.offsets {
+            offsets.push(self.out.position());
+        }
+        if self.p.preprocess {
+            self.preprocess();
+        } else {
+            self.d.copy_from_slice(&self.raw);
+        }
+
+        let mut zero_blocks = 0;
+        let mut zero_ref = false;
+        for b in 0..blocks {
+            let has_ref = self.p.preprocess && b == 0;
+            let start = b * bs;
+            let skip = has_ref as usize;
+            if self.d[start + skip..start + bs].iter().any(|&v| v != 0) {
+                if zero_blocks > 0 {
+                    self.encode_zero(zero_blocks, false, zero_ref, ref_sample);
+                    zero_blocks = 0;
+                }
+                self.encode_block(start, has_ref, ref_sample);
+            } else {
+                zero_blocks += 1;
+                if zero_blocks == 1 {
+                    zero_ref = has_ref;
+                }
+                if b + 1 == blocks || (b + 1) % SEGMENT == 0 {
+                    // longer runs to the end of a segment use the
+                    // remainder of segment code
+                    let ros = zero_blocks > 4;
+                    self.encode_zero(zero_blocks, ros, zero_ref, ref_sample);
+                    zero_blocks = 0;
+                }
+            }
+        }
+
+        if self.p.pad_rsi {
+            self.out.pad();
+        }
+    }
+
+    fn encode_zero(&mut self, zero_blocks: u32, ros: bool, has_ref: bool, ref_sample: u32) {
+        let n = self.p.bits_per_sample;
+        self.out.put(0, self.p.id_len + 1);
+        if has_ref {
+            self.out.put(ref_sample, n);
+        }
+        let fs = if ros {
+            ROS - 1
+        } else if zero_blocks >= ROS {
+            zero_blocks
+        } else {
+            zero_blocks - 1
+        };
+        self.out.put_fs(fs as u64);
+    }
+
+    fn encode_block(&mut self, start: usize, has_ref: bool, ref_sample: u32) {
+        let bs = self.p.block_size;
+        let n = self.p.bits_per_sample;
+        let skip = has_ref as usize;
+        let uncomp_len = ((bs - skip) * n as usize) as u64;
+
+        let split_len = if self.p.id_len > 1 {
+            self.assess_splitting(start, skip)
+        } else {
+            u64::MAX
+        };
+        let se_len = self.assess_se(start, uncomp_len);
+
+        let block = &self.d[start..start + bs];
+        if split_len < uncomp_len && split_len < se_len {
+            // splitting, or fundamental sequence if k is 0
+            let k = self.k;
+            self.out.put(k + 1, self.p.id_len);
+            if has_ref {
+                self.out.put(ref_sample, n);
+            }
+            for &v in &block[skip..] {
+                self.out.put_fs((v >> k) as u64);
+            }
+            if k > 0 {
+                for &v in &block[skip..] {
+                    self.out.put(v, k);
+                }
+            }
+        } else if split_len >= uncomp_len && uncomp_len <= se_len {
+            self.out.put(self.p.id_uncomp(), self.p.id_len);
+            if has_ref {
+                self.out.put(ref_sample, n);
+            }
+            for &v in &block[skip..] {
+                self.out.put(v, n);
+            }
+        } else {
+            // second extension
+            self.out.put(1, self.p.id_len + 1);
+            if has_ref {
+                self.out.put(ref_sample, n);
+            }
+            for pair in block.chunks_exact(2) {
+                let d = pair[0] as u64 + pair[1] as u64;
+                self.out.put_fs(d * (d + 1) / 2 + pair[1] as u64);
+            }
+        }
+    }
+
+    fn block_fs(&self, start: usize, k: u32) -> u64 {
+        self.d[start..start + self.p.block_size]
+            .iter()
+            .map(|&v| (v >> k) as u64)
+            .sum()
+    }
+
+    /// Find the best k for splitting, starting from the last k used,
+    /// and return the length of the coded block. This is the same
+    /// search libaec uses, which relies on the length having a
+    /// single minimum in k.
+    fn assess_splitting(&mut self, start: usize, skip: usize) -> u64 {
+        let this_bs = (self.p.block_size - skip) as u64;
+        let mut len_min = u64::MAX;
+        let mut k = self.k;
+        let mut k_min = k;
+        let mut no_turn = k == 0;
+        let mut up = true;
+
+        loop {
+            let fs_len = self.block_fs(start, k);
+            let len = fs_len + this_bs * (k as u64 + 1);
+
+            if len < len_min {
+                if len_min < u64::MAX {
+                    no_turn = true;
+                }
+                len_min = len;
+                k_min = k;
+
+                if up {
+                    if fs_len < this_bs || k >= self.p.kmax {
+                        if no_turn {
+                            break;
+                        }
+                        k = self.k - 1;
+                        up = false;
+                        no_turn = true;
+                    } else {
+                        k += 1;
+                    }
+                } else {
+                    if fs_len >= this_bs || k == 0 {
+                        break;
+                    }
+                    k -= 1;
+                }
+            } else {
+                if no_turn {
+                    break;
+                }
+                k = self.k - 1;
+                up = false;
+                no_turn = true;
+            }
+        }
+        self.k = k_min;
+        len_min
+    }
+
+    /// Length of the block coded with the second extension option,
+    /// or `u64::MAX` if it is longer than the uncompressed block.
+    fn assess_se(&self, start: usize, uncomp_len: u64) -> u64 {
+        let mut len = 1;
+        for pair in self.d[start..start + self.p.block_size].chunks_exact(2) {
+            let d = pair[0] as u64 + pair[1] as u64;
+            // avoid overflow for wide samples
+            if d > uncomp_len {
+                return u64::MAX;
+            }
+            len += d * (d + 1) / 2 + pair[1] as u64 + 1;
+            if len > uncomp_len {
+                return u64::MAX;
+            }
+        }
+        len
+    }
+}
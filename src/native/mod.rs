@@ -0,0 +1,549 @@
+//! A pure-Rust implementation of the CCSDS 121.0-B-3 adaptive entropy
+//! coder and preprocessor.
+//!
+//! This follows libaec closely, including its choice of code options,
+//! so that both produce identical output.
+
+use crate::{sample, Configuration, ErrorKind, Flags, MAX_RSI};
+
+mod bits;
+
+mod decode;
+pub(crate) use decode::{decode_range, Decoder};
+
+mod encode;
+pub(crate) use encode::Encoder;
+
+/// The coded zero block count that means "remainder of segment".
+const ROS: u32 = 5;
+
+/// Zero block runs never cross a segment of this many blocks.
+const SEGMENT: usize = 64;
+
+/// Parameters derived from a [`Configuration`], shared by the
+/// encoder and decoder.
+#[derive(Clone, Debug)]
+struct Params {
+    bits_per_sample: u32,
+    block_size: usize,
+    rsi: usize,
+    storage: usize,
+    id_len: u32,
+    kmax: u32,
+    signed: bool,
+    msb: bool,
+    preprocess: bool,
+    pad_rsi: bool,
+    xmax: u32,
+}
+
+impl Params {
+    fn new(conf: &Configuration) -> Result<Self, ErrorKind> {
+        let bits = conf.bits_per_sample();
+        let block_size = conf.block_size();
+        let rsi = conf.rsi();
+        let flags = conf.flags();
+
+        if bits == 0 || bits > 32 {
+            return Err(ErrorKind::Configuration);
+        }
+        if flags.contains(Flags::NOT_ENFORCE) {
+            if block_size == 0 || block_size & 1 != 0 {
+                return Err(ErrorKind::Configuration);
+            }
+        } else if ![8, 16, 32, 64].contains(&block_size) {
+            return Err(ErrorKind::Configuration);
+        }
+        if rsi == 0 || rsi > MAX_RSI {
+            return Err(ErrorKind::Configuration);
+        }
+
+        let id_len = if bits > 16 {
+            5
+        } else if bits > 8 {
+            4
+        } else if flags.contains(Flags::RESTRICTED) {
+            match bits {
+                1..=2 => 1,
+                3..=4 => 2,
+                _ => return Err(ErrorKind::Configuration),
+            }
+        } else {
+            3
+        };
+
+        let signed = flags.contains(Flags::DATA_SIGNED);
+        let xmax = if signed {
+            (1u32 << (bits - 1)) - 1
+        } else {
+            u32::MAX >> (32 - bits)
+        };
+
+        Ok(Self {
+            bits_per_sample: bits as u32,
+            block_size,
+            rsi,
+            storage: sample::storage_size(bits, flags),
+            id_len,
+            // may wrap for id_len 1, but splitting is never used then
+            kmax: (1u32 << id_len).wrapping_sub(3),
+            signed,
+            msb: flags.contains(Flags::DATA_MSB),
+            preprocess: flags.contains(Flags::DATA_PREPROCESS),
+            pad_rsi: flags.contains(Flags::PAD_RSI),
+            xmax,
+        })
+    }
+
+    fn rsi_len(&self) -> usize {
+        self.rsi * self.block_size
+    }
+
+    fn mask(&self) -> u32 {
+        u32::MAX >> (32 - self.bits_per_sample)
+    }
+
+    /// Id of the uncompressed option.
+    fn id_uncomp(&self) -> u32 {
+        (1 << self.id_len) - 1
+    }
+
+    fn read_sample(&self, bytes: &[u8]) -> u32 {
+        let mut v = 0u32;
+        for (i, b) in bytes.iter().enumerate() {
+            let shift = if self.msb { self.storage - 1 - i } else { i };
+            v |= (*b as u32) << (8 * shift);
+        }
+        v & self.mask()
+    }
+
+    fn write_sample(&self, v: u32, out: &mut Vec<u8>) {
+        let bytes = v.to_le_bytes();
+        if self.msb {
+            out.extend(bytes[..self.storage].iter().rev());
+        } else {
+            out.extend_from_slice(&bytes[..self.storage]);
+        }
+    }
+
+    /// Sign-extend an n-bit sample.
+    fn extend(&self, v: u32) -> u32 {
+        let m = 1u32 << (self.bits_per_sample - 1);
+        (v ^ m).wrapping_sub(m)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Configuration, Flags};
+
+    // a small xorshift generator, so tests need no dependencies
+    pub(super) struct Rng(u64);
+
+    impl Rng {
+        pub fn new(seed: u64) -> Self {
+            Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+        }
+
+        pub fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 32) as u32
+        }
+    }
+
+    /// Generate `count` samples of `conf`, in storage layout, from a
+    /// mix of smooth, noisy, constant and zero stretches.
+    pub(super) fn generate(conf: &Configuration, count: usize, seed: u64) -> Vec<u8> {
+        let mut rng = Rng::new(seed);
+        let bits = conf.bits_per_sample() as u32;
+        let flags = conf.flags();
+        let storage = crate::sample::storage_size(bits as usize, flags);
+        let mask = u32::MAX >> (32 - bits);
+        let mut out = Vec::with_capacity(count * storage);
+        let mut v: u32 = rng.next() & mask;
+        let mut i = 0;
+        while i < count {
+            let run = (rng.next() % 300) as usize + 1;
+            let kind = rng.next() % 4;
+            for _ in 0..run.min(count - i) {
+                v = match kind {
+                    // smooth
+                    0 => v.wrapping_add((rng.next() % 7).wrapping_sub(3)),
+                    // noise
+                    1 => rng.next(),
+                    // constant
+                    2 => v,
+                    // zero
+                    _ => 0,
+                } & mask;
+                let mut s = v;
+                if flags.contains(Flags::DATA_SIGNED) && bits < 32 {
+                    // sign-extend into the storage size
+                    let m = 1u32 << (bits - 1);
+                    s = (v ^ m).wrapping_sub(m);
+                }
+                let bytes = s.to_le_bytes();
+                if flags.contains(Flags::DATA_MSB) {
+                    out.extend(bytes[..storage].iter().rev());
+                } else {
+                    out.extend_from_slice(&bytes[..storage]);
+                }
+                i += 1;
+            }
+        }
+        out
+    }
+
+    pub(super) fn configs() -> Vec<Configuration> {
+        let mut confs = vec![];
+        for &bits in &[1, 2, 3, 4, 5, 8, 9, 12, 16, 17, 24, 25, 32] {
+            for &(block_size, rsi) in &[(8, 1), (16, 32), (32, 128), (64, 3)] {
+                for &flags in &[
+                    Flags::DATA_PREPROCESS,
+                    Flags::DATA_PREPROCESS | Flags::DATA_SIGNED,
+                    Flags::DATA_MSB,
+                    Flags::DATA_SIGNED | Flags::DATA_MSB,
+                    Flags::DATA_PREPROCESS | Flags::PAD_RSI,
+                ] {
+                    confs.push(Configuration::new(bits, block_size, rsi, flags));
+                    if bits <= 4 {
+                        confs.push(Configuration::new(
+                            bits,
+                            block_size,
+                            rsi,
+                            flags | Flags::RESTRICTED,
+                        ));
+                    }
+                    if (17..=24).contains(&bits) {
+                        confs.push(Configuration::new(
+                            bits,
+                            block_size,
+                            rsi,
+                            flags | Flags::DATA_3BYTE,
+                        ));
+                    }
+                }
+            }
+        }
+        confs.push(Configuration::new(
+            12,
+            10,
+            7,
+            Flags::DATA_PREPROCESS | Flags::NOT_ENFORCE,
+        ));
+        confs
+    }
+
+    // encode and decode a whole buffer, in small pieces
+    pub(super) fn roundtrip(conf: &Configuration, data: &[u8], chunk: usize) -> Vec<u8> {
+        let mut enc = super::Encoder::new(conf).unwrap();
+        let mut encoded = vec![];
+        let mut out = [std::mem::MaybeUninit::uninit(); 37];
+        let mut input = data;
+        loop {
+            let take = input.len().min(chunk);
+            let flush = take == input.len();
+            let (consumed, produced) = enc.encode(&input[..take], &mut out, flush).unwrap();
+            encoded.extend(out[..produced].iter().map(|b| unsafe { b.assume_init() }));
+            input = &input[consumed..];
+            if flush && consumed == take && produced < out.len() {
+                break;
+            }
+        }
+        encoded
+    }
+
+    #[test]
+    fn roundtrip_generated() {
+        for (n, conf) in configs().iter().enumerate() {
+            let storage = crate::sample::storage_size(conf.bits_per_sample(), conf.flags());
+            let blocks = 300 + n % 17;
+            let data = generate(conf, blocks * conf.block_size(), n as u64);
+            let encoded = roundtrip(conf, &data, 101);
+
+            // decode in awkward pieces as well
+            let mut dec = super::Decoder::new(conf).unwrap();
+            let mut decoded = vec![];
+            let mut out = [std::mem::MaybeUninit::uninit(); 29];
+            let mut input = &encoded[..];
+            loop {
+                let take = input.len().min(13);
+                let (consumed, produced) = dec.decode(&input[..take], &mut out, false).unwrap();
+                decoded.extend(out[..produced].iter().map(|b| unsafe { b.assume_init() }));
+                input = &input[consumed..];
+                if input.is_empty() && produced == 0 {
+                    break;
+                }
+            }
+
+            // a trailing zero run or padding may decode as extra
+            // samples, just as in libaec
+            assert!(decoded.len() >= data.len(), "{:?}", conf);
+            // non-preprocessed signed data comes back without sign
+            // extension, so compare only the sample bits
+            let mask = u32::MAX >> (32 - conf.bits_per_sample());
+            let msb = conf.flags().contains(Flags::DATA_MSB);
+            for (a, b) in data.chunks(storage).zip(decoded.chunks(storage)) {
+                let read = |s: &[u8]| {
+                    let mut v = 0u32;
+                    for (i, b) in s.iter().enumerate() {
+                        let i = if msb { storage - 1 - i } else { i };
+                        v |= (*b as u32) << (8 * i);
+                    }
+                    v & mask
+                };
+                assert_eq!(read(a), read(b), "{:?}", conf);
+            }
+        }
+    }
+
+    #[test]
+    fn partial_rsi() {
+        let conf = Configuration::new(16, 16, 32, Flags::DATA_PREPROCESS);
+        let data = generate(&conf, 16 * 45, 7);
+        let encoded = roundtrip(&conf, &data, 1000);
+        let mut decoded = vec![];
+        conf.decode_buffer(&encoded, &mut decoded).unwrap();
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn random_input() {
+        // hostile streams may fail, but must not panic
+        for (n, conf) in configs().iter().enumerate().step_by(7) {
+            let mut rng = Rng::new(n as u64);
+            for &len in &[1, 10, 100, 1000] {
+                let input: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+                let mut dec = super::Decoder::new(conf).unwrap();
+                let mut out = vec![std::mem::MaybeUninit::uninit(); 4096];
+                let _ = dec.decode(&input, &mut out, true);
+            }
+        }
+        let conf = Configuration::new(4, 16, 32, Flags::DATA_PREPROCESS);
+        for seed in 0..2000 {
+            let mut rng = Rng::new(seed);
+            let input: Vec<u8> = (0..64).map(|_| rng.next() as u8).collect();
+            let mut dec = super::Decoder::new(&conf).unwrap();
+            let mut out = vec![std::mem::MaybeUninit::uninit(); 4096];
+            let _ = dec.decode(&input, &mut out, true);
+        }
+    }
+
+    // two RSIs of zeros, a ramp, noise and a constant, in stretches
+    // of eight samples
+    fn fixture_data(conf: &Configuration) -> Vec<u8> {
+        let bits = conf.bits_per_sample() as u32;
+        let flags = conf.flags();
+        let storage = crate::sample::storage_size(bits as usize, flags);
+        let mask = u32::MAX >> (32 - bits);
+        let count = 2 * conf.rsi() * conf.block_size();
+        let mut out = vec![];
+        for i in 0..count as u32 {
+            let v = match i / 8 % 4 {
+                0 => 0,
+                1 => 100 + 3 * i,
+                2 => i.wrapping_mul(2_654_435_761) >> 7,
+                _ => 5,
+            } & mask;
+            let mut s = v;
+            if flags.contains(Flags::DATA_SIGNED) && bits < 32 {
+                let m = 1 << (bits - 1);
+                s = (v ^ m).wrapping_sub(m);
+            }
+            let bytes = s.to_le_bytes();
+            if flags.contains(Flags::DATA_MSB) {
+                out.extend(bytes[..storage].iter().rev());
+            } else {
+                out.extend_from_slice(&bytes[..storage]);
+            }
+        }
+        out
+    }
+
+    // streams of fixture_data as libaec encodes them, which the
+    // differential tests check, so that pure-rust builds decode
+    // libaec's output too
+    fn fixtures() -> Vec<(Configuration, &'static [u8])> {
+        vec![
+            (
+                Configuration::new(8, 8, 1, Flags::DATA_PREPROCESS),
+                &[0x00, 0x0b, 0x7c, 0x55, 0x56, 0xaa, 0xa0],
+            ),
+            (
+                Configuration::new(12, 16, 2, Flags::DATA_PREPROCESS | Flags::DATA_SIGNED),
+                &[
+                    0x50, 0x00, 0xfe, 0x00, 0x03, 0xfc, 0x00, 0x00, 0x00, 0x21, 0x99, 0x99, 0x99,
+                    0xa9, 0x55, 0x50, 0x11, 0xfe, 0xb5, 0x0c, 0x86, 0x42, 0xe1, 0x90, 0xb8, 0x67,
+                    0xbb, 0xfa, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xa0, 0x01, 0xfc,
+                    0x00, 0x00, 0x00, 0x7f, 0x80, 0x00, 0x00, 0x04, 0x33, 0x33, 0x33, 0x35, 0x0a,
+                    0xaa, 0x03, 0x1f, 0xe9, 0xd0, 0xc8, 0x5c, 0x32, 0x19, 0x0b, 0xd5, 0xb6, 0xbf,
+                    0xa0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                ],
+            ),
+            (
+                Configuration::new(16, 16, 2, Flags::DATA_MSB),
+                &[
+                    0x6f, 0xf1, 0x10, 0x84, 0x21, 0x08, 0x40, 0x00, 0x00, 0x00, 0x00, 0x39, 0xf1,
+                    0x15, 0x0b, 0x74, 0x7f, 0xbc, 0xd9, 0x78, 0xa7, 0x34, 0x70, 0xf0, 0x42, 0xac,
+                    0x0c, 0x67, 0xde, 0x23, 0xab, 0xdf, 0x74, 0x00, 0x14, 0x00, 0x14, 0x00, 0x14,
+                    0x00, 0x14, 0x00, 0x14, 0x00, 0x14, 0x00, 0x14, 0x00, 0x16, 0x3f, 0xd5, 0x55,
+                    0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2e, 0x5f, 0xc5, 0x97, 0x46, 0xbd,
+                    0xdc, 0x7f, 0x36, 0x88, 0xf2, 0x56, 0xae, 0x24, 0x69, 0xf2, 0x25, 0xbf, 0xe1,
+                    0x8d, 0x9d, 0x5b, 0x59, 0x28, 0x00, 0x14, 0x00, 0x14, 0x00, 0x14, 0x00, 0x14,
+                    0x00, 0x14, 0x00, 0x14, 0x00, 0x14, 0x00, 0x14,
+                ],
+            ),
+            (
+                Configuration::new(
+                    24,
+                    16,
+                    1,
+                    Flags::DATA_PREPROCESS | Flags::DATA_3BYTE | Flags::DATA_MSB,
+                ),
+                &[
+                    0x20, 0x00, 0x00, 0x07, 0xf0, 0x00, 0x1f, 0xe0, 0x00, 0x00, 0x9b, 0x6d, 0xb6,
+                    0xbe, 0x37, 0x79, 0xb0, 0xd5, 0x14, 0x7f, 0xf9, 0x43, 0xad, 0xfe, 0x68, 0xe7,
+                    0x1b, 0xbd, 0x1c, 0x6e, 0xf3, 0x71, 0xbb, 0xd0, 0x73, 0xb8, 0xad, 0xbe, 0xfb,
+                    0xad, 0xf7, 0xd7, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                ],
+            ),
+            (
+                Configuration::new(32, 8, 2, Flags::DATA_PREPROCESS),
+                &[
+                    0x00, 0x00, 0x00, 0x00, 0x02, 0x40, 0x00, 0x1f, 0xf3, 0x6d, 0xb6, 0xd9, 0x01,
+                    0xc6, 0xef, 0x36, 0x55, 0x56, 0x1c, 0x88, 0x66, 0x1c, 0x88, 0x65, 0xf0, 0xf0,
+                    0x42, 0x1c, 0x88, 0x67, 0xd4, 0x67, 0xde, 0x1c, 0x88, 0x66, 0x1c, 0x88, 0x66,
+                    0xa0, 0x00, 0xff, 0xbe, 0xfa, 0xf0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                ],
+            ),
+            (
+                Configuration::new(3, 8, 4, Flags::DATA_PREPROCESS | Flags::RESTRICTED),
+                &[
+                    0x03, 0xcd, 0x6f, 0x7b, 0x7b, 0x4e, 0xfd, 0xa2, 0x07, 0x9a, 0xde, 0xf6, 0xd5,
+                    0xd3, 0x4d, 0x50, 0x7f, 0x80,
+                ],
+            ),
+            (
+                Configuration::new(8, 16, 2, Flags::DATA_PREPROCESS | Flags::PAD_RSI),
+                &[
+                    0x80, 0x1f, 0xc0, 0x00, 0x7f, 0x80, 0x00, 0x02, 0x6d, 0xb6, 0xdb, 0x03, 0xe0,
+                    0x38, 0x0f, 0xfa, 0xe7, 0x37, 0xcd, 0xeb, 0x9d, 0x00, 0x00, 0x00, 0x00, 0x00,
+                    0x80, 0x1f, 0xc0, 0x00, 0x00, 0x07, 0xf8, 0x00, 0x00, 0x26, 0xdb, 0x6d, 0xa8,
+                    0x2a, 0xaa, 0x80, 0x7f, 0xec, 0xbc, 0xcb, 0xcb, 0xc8, 0x00, 0x00, 0x00, 0x00,
+                ],
+            ),
+        ]
+    }
+
+    #[test]
+    fn decode_fixtures() {
+        for (conf, encoded) in fixtures() {
+            let data = fixture_data(&conf);
+            let mut dec = super::Decoder::new(&conf).unwrap();
+            let mut out = vec![std::mem::MaybeUninit::uninit(); data.len()];
+            let (consumed, produced) = dec.decode(encoded, &mut out, true).unwrap();
+            assert_eq!(consumed, encoded.len(), "{:?}", conf);
+            let decoded: Vec<u8> = out[..produced]
+                .iter()
+                .map(|b| unsafe { b.assume_init() })
+                .collect();
+            assert_eq!(decoded, data, "{:?}", conf);
+        }
+    }
+
+    #[test]
+    fn empty_input() {
+        let conf = Configuration::new(8, 16, 32, Flags::DATA_PREPROCESS);
+        assert_eq!(roundtrip(&conf, &[], 10), vec![0]);
+    }
+
+    #[test]
+    fn bad_configuration() {
+        let bad = [
+            Configuration::new(0, 16, 32, Flags::empty()),
+            Configuration::new(33, 16, 32, Flags::empty()),
+            Configuration::new(8, 12, 32, Flags::empty()),
+            Configuration::new(8, 13, 32, Flags::NOT_ENFORCE),
+            Configuration::new(8, 16, 4097, Flags::empty()),
+            Configuration::new(8, 16, 32, Flags::RESTRICTED),
+        ];
+        for conf in bad.iter() {
+            assert!(super::Encoder::new(conf).is_err(), "{:?}", conf);
+            assert!(super::Decoder::new(conf).is_err(), "{:?}", conf);
+        }
+    }
+
+    /// Compare against libaec on generated data.
+    #[cfg(feature = "libaec")]
+    mod differential {
+        use super::{configs, fixture_data, fixtures, generate, roundtrip};
+        use crate::libaec;
+        use std::mem::MaybeUninit;
+
+        #[test]
+        fn encode_matches_libaec() {
+            for (n, conf) in configs().iter().enumerate() {
+                let blocks = 200 + n % 23;
+                let data = generate(conf, blocks * conf.block_size(), n as u64 + 1000);
+                let native = roundtrip(conf, &data, 77);
+
+                let mut enc = libaec::Encoder::new(conf).unwrap();
+                let mut out = vec![MaybeUninit::uninit(); data.len() * 2 + 64];
+                let (consumed, produced) = enc.encode(&data, &mut out, true).unwrap();
+                assert_eq!(consumed, data.len());
+                let reference: Vec<u8> = out[..produced]
+                    .iter()
+                    .map(|b| unsafe { b.assume_init() })
+                    .collect();
+
+                assert_eq!(native, reference, "{:?}", conf);
+            }
+        }
+
+        #[test]
+        fn fixtures_match_libaec() {
+            for (conf, encoded) in fixtures() {
+                let data = fixture_data(&conf);
+                let mut enc = libaec::Encoder::new(&conf).unwrap();
+                let mut out = vec![MaybeUninit::uninit(); data.len() * 2 + 64];
+                let (_, produced) = enc.encode(&data, &mut out, true).unwrap();
+                let reference: Vec<u8> = out[..produced]
+                    .iter()
+                    .map(|b| unsafe { b.assume_init() })
+                    .collect();
+                assert_eq!(reference, encoded, "{:?}", conf);
+            }
+        }
+
+        #[test]
+        fn decode_matches_libaec() {
+            for (n, conf) in configs().iter().enumerate() {
+                let blocks = 200 + n % 23;
+                let data = generate(conf, blocks * conf.block_size(), n as u64 + 2000);
+                let mut encoded = vec![];
+                conf.encode_buffer(&data, &mut encoded).unwrap();
+
+                let decode = |native: bool| {
+                    let mut out = vec![MaybeUninit::uninit(); data.len()];
+                    let (_, produced) = if native {
+                        let mut dec = crate::native::Decoder::new(conf).unwrap();
+                        dec.decode(&encoded, &mut out, true).unwrap()
+                    } else {
+                        let mut dec = libaec::Decoder::new(conf).unwrap();
+                        dec.decode(&encoded, &mut out, true).unwrap()
+                    };
+                    out[..produced]
+                        .iter()
+                        .map(|b| unsafe { b.assume_init() })
+                        .collect::<Vec<u8>>()
+                };
+
+                assert_eq!(decode(true), decode(false), "{:?}", conf);
+            }
+        }
+    }
+}
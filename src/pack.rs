@@ -0,0 +1,459 @@
+//! Conversion between tightly bit-packed samples and the storage
+//! layout the coder uses.
+//!
+//! [`Unpack`] turns packed samples into storage units, and [`Pack`]
+//! turns them back. Either can wrap a reader or a writer, so they fit
+//! in front of [`Reader`] and [`Writer`] without buffering the whole
+//! stream.
+//!
+//! [`Reader`]: crate::Reader
+//! [`Writer`]: crate::Writer
+
+use crate::{sample, Configuration, Error, ErrorKind, Flags};
+
+use std::io;
+use std::io::{BufRead, Read, Write};
+
+/// The order of bits within each byte of a packed stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BitOrder {
+    /// The first sample starts at the most significant bit.
+    Msb,
+    /// The first sample starts at the least significant bit.
+    Lsb,
+}
+
+/// Sample layout on both sides, and bits not yet used.
+#[derive(Clone, Debug)]
+struct Bits {
+    bits: u32,
+    storage: usize,
+    msb: bool,
+    signed: bool,
+    order: BitOrder,
+    acc: u64,
+    len: u32,
+}
+
+impl Bits {
+    fn new(conf: &Configuration, order: BitOrder) -> Result<Self, Error> {
+        let bits = conf.bits_per_sample();
+        if bits == 0 || bits > 32 {
+            return Err(ErrorKind::Configuration.into());
+        }
+        let flags = conf.flags();
+        Ok(Self {
+            bits: bits as u32,
+            storage: sample::storage_size(bits, flags),
+            msb: flags.contains(Flags::DATA_MSB),
+            signed: flags.contains(Flags::DATA_SIGNED),
+            order,
+            acc: 0,
+            len: 0,
+        })
+    }
+
+    fn mask(&self) -> u64 {
+        (1 << self.bits) - 1
+    }
+
+    /// Unpack all of `input`, appending whole samples to `output`.
+    fn unpack(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        for &b in input {
+            match self.order {
+                BitOrder::Msb => self.acc = (self.acc << 8) | b as u64,
+                BitOrder::Lsb => self.acc |= (b as u64) << self.len,
+            }
+            self.len += 8;
+            while self.len >= self.bits {
+                self.len -= self.bits;
+                let v = match self.order {
+                    BitOrder::Msb => self.acc >> self.len,
+                    BitOrder::Lsb => {
+                        let v = self.acc;
+                        self.acc >>= self.bits;
+                        v
+                    }
+                };
+                self.store(v & self.mask(), output);
+            }
+            if self.order == BitOrder::Msb {
+                self.acc &= (1 << self.len) - 1;
+            }
+        }
+    }
+
+    fn store(&self, v: u64, output: &mut Vec<u8>) {
+        let v = if self.signed {
+            let shift = 64 - self.bits;
+            (((v << shift) as i64) >> shift) as u64
+        } else {
+            v
+        };
+        for i in 0..self.storage {
+            let i = if self.msb { self.storage - 1 - i } else { i };
+            output.push((v >> (8 * i)) as u8);
+        }
+    }
+
+    /// Pack the whole samples in `input`, appending whole bytes to
+    /// `output`.
+    fn pack(&mut self, input: &[u8], output: &mut Vec<u8>) {
+        for bytes in input.chunks_exact(self.storage) {
+            let mut v = 0u64;
+            for (i, b) in bytes.iter().enumerate() {
+                let i = if self.msb { self.storage - 1 - i } else { i };
+                v |= (*b as u64) << (8 * i);
+            }
+            let v = v & self.mask();
+            match self.order {
+                BitOrder::Msb => self.acc = (self.acc << self.bits) | v,
+                BitOrder::Lsb => self.acc |= v << self.len,
+            }
+            self.len += self.bits;
+            while self.len >= 8 {
+                self.len -= 8;
+                match self.order {
+                    BitOrder::Msb => output.push((self.acc >> self.len) as u8),
+                    BitOrder::Lsb => {
+                        output.push(self.acc as u8);
+                        self.acc >>= 8;
+                    }
+                }
+            }
+            if self.order == BitOrder::Msb {
+                self.acc &= (1 << self.len) - 1;
+            }
+        }
+    }
+
+    /// The last partial byte when packing, padded with zeros.
+    fn finish(&mut self, output: &mut Vec<u8>) {
+        if self.len > 0 {
+            output.push(match self.order {
+                BitOrder::Msb => (self.acc << (8 - self.len)) as u8,
+                BitOrder::Lsb => self.acc as u8,
+            });
+        }
+        self.acc = 0;
+        self.len = 0;
+    }
+}
+
+/// Converted bytes waiting to be read or written.
+#[derive(Clone, Debug, Default)]
+struct Pending {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl Pending {
+    fn fill<R: Read>(
+        &mut self,
+        inner: &mut R,
+        mut convert: impl FnMut(&[u8], &mut Vec<u8>, bool),
+    ) -> io::Result<&[u8]> {
+        let mut chunk = [0; crate::DEFAULT_BUFFER_SIZE];
+        while self.pos == self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+            let n = inner.read(&mut chunk)?;
+            convert(&chunk[..n], &mut self.buf, n == 0);
+            if n == 0 {
+                break;
+            }
+        }
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = (self.buf.len() - self.pos).min(buf.len());
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        n
+    }
+}
+
+/// Unpacks samples, from an inner reader or into an inner writer.
+///
+/// When reading, trailing bits too few for a sample are dropped.
+#[derive(Clone, Debug)]
+pub struct Unpack<T> {
+    bits: Bits,
+    pending: Pending,
+    inner: T,
+}
+
+impl<T> Unpack<T> {
+    /// Unpack samples of the size given by `conf`, into storage units
+    /// laid out as its flags describe.
+    pub fn new(conf: &Configuration, order: BitOrder, inner: T) -> Result<Self, Error> {
+        Ok(Self {
+            bits: Bits::new(conf, order)?,
+            pending: Pending::default(),
+            inner,
+        })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Read> Read for Unpack<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fill_buf()?;
+        Ok(self.pending.read(buf))
+    }
+}
+
+impl<T: Read> BufRead for Unpack<T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let bits = &mut self.bits;
+        self.pending
+            .fill(&mut self.inner, |input, out, _| bits.unpack(input, out))
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pending.pos += amt;
+    }
+}
+
+impl<T: Write> Write for Unpack<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.buf.clear();
+        self.bits.unpack(buf, &mut self.pending.buf);
+        self.inner.write_all(&self.pending.buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Packs samples, from an inner reader or into an inner writer.
+///
+/// The last byte is padded with zero bits. When writing, it is only
+/// written by [`Pack::finish`]. Input that ends part way through a
+/// storage unit is an error, when reading or finishing.
+#[derive(Clone, Debug)]
+pub struct Pack<T> {
+    bits: Bits,
+    // bytes of a sample split between reads or writes
+    partial: Vec<u8>,
+    pending: Pending,
+    inner: T,
+}
+
+impl<T> Pack<T> {
+    /// Pack storage units laid out as described by `conf` into
+    /// samples of its size.
+    pub fn new(conf: &Configuration, order: BitOrder, inner: T) -> Result<Self, Error> {
+        Ok(Self {
+            bits: Bits::new(conf, order)?,
+            partial: vec![],
+            pending: Pending::default(),
+            inner,
+        })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Pack `input` after any partial sample left from last time.
+    fn pack(bits: &mut Bits, partial: &mut Vec<u8>, mut input: &[u8], out: &mut Vec<u8>) {
+        if !partial.is_empty() {
+            let n = (bits.storage - partial.len()).min(input.len());
+            partial.extend_from_slice(&input[..n]);
+            input = &input[n..];
+            if partial.len() == bits.storage {
+                bits.pack(partial, out);
+                partial.clear();
+            }
+        }
+        let whole = input.len() - input.len() % bits.storage;
+        bits.pack(&input[..whole], out);
+        partial.extend_from_slice(&input[whole..]);
+    }
+}
+
+impl<T: Write> Pack<T> {
+    /// Write the last partial byte, and return the inner writer.
+    pub fn finish(mut self) -> io::Result<T> {
+        if !self.partial.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "input ends with a partial sample",
+            ));
+        }
+        self.pending.buf.clear();
+        self.bits.finish(&mut self.pending.buf);
+        self.inner.write_all(&self.pending.buf)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<T: Read> Read for Pack<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fill_buf()?;
+        Ok(self.pending.read(buf))
+    }
+}
+
+impl<T: Read> BufRead for Pack<T> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let bits = &mut self.bits;
+        let partial = &mut self.partial;
+        let buf = self.pending.fill(&mut self.inner, |input, out, end| {
+            Self::pack(bits, partial, input, out);
+            if end {
+                bits.finish(out);
+            }
+        })?;
+        // everything packed has been read, so only the partial sample
+        // is left over
+        if buf.is_empty() && !self.partial.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "input ends with a partial sample",
+            ));
+        }
+        Ok(buf)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pending.pos += amt;
+    }
+}
+
+impl<T: Write> Write for Pack<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.buf.clear();
+        Self::pack(
+            &mut self.bits,
+            &mut self.partial,
+            buf,
+            &mut self.pending.buf,
+        );
+        self.inner.write_all(&self.pending.buf)?;
+        Ok(buf.len())
+    }
+
+    /// This flushes whole bytes only. Use [`Pack::finish`] to write
+    /// the last partial byte.
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BitOrder, Pack, Unpack};
+    use crate::{Configuration, Flags};
+
+    use std::io::{BufReader, Read, Write};
+
+    #[test]
+    fn layout() {
+        let conf = Configuration::new(12, 16, 8, Flags::DATA_MSB);
+        let mut out = vec![];
+        Unpack::new(&conf, BitOrder::Msb, &[0xab, 0xcd, 0xef, 0x12][..])
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        // the last 8 bits are too few for a sample
+        assert_eq!(out, [0x0a, 0xbc, 0x0d, 0xef]);
+
+        let conf = Configuration::new(12, 16, 8, Flags::DATA_SIGNED);
+        let mut out = vec![];
+        Unpack::new(&conf, BitOrder::Lsb, &[0xab, 0xcd, 0xef][..])
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(out, [0xab, 0xfd, 0xfc, 0xfe]);
+
+        let mut packed = vec![];
+        Pack::new(&conf, BitOrder::Lsb, &out[..])
+            .unwrap()
+            .read_to_end(&mut packed)
+            .unwrap();
+        assert_eq!(packed, [0xab, 0xcd, 0xef]);
+
+        // a partial last byte is padded
+        let mut pack = Pack::new(&conf, BitOrder::Msb, vec![]).unwrap();
+        pack.write_all(&[0x23]).unwrap();
+        pack.write_all(&[0x01]).unwrap();
+        assert_eq!(pack.finish().unwrap(), [0x12, 0x30]);
+
+        assert!(Unpack::new(
+            &Configuration::new(33, 16, 8, Flags::empty()),
+            BitOrder::Msb,
+            ()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn partial_sample() {
+        // two 12 bit samples, and half of a third
+        let conf = Configuration::new(12, 16, 8, Flags::empty());
+        let input = [0x23, 0x01, 0x56, 0x04, 0x89];
+        let mut reader = Pack::new(&conf, BitOrder::Lsb, &input[..]).unwrap();
+        let mut output = vec![];
+        let err = reader.read_to_end(&mut output).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(output, [0x23, 0x61, 0x45]);
+    }
+
+    #[test]
+    fn roundtrip() {
+        for &bits in &[12, 14, 20] {
+            for &order in &[BitOrder::Msb, BitOrder::Lsb] {
+                let conf = Configuration::new(bits, 16, 8, Flags::DATA_PREPROCESS);
+                let count = 1000;
+                let packed: Vec<u8> = (0..count * bits / 8).map(|i| (i * 7 / 3) as u8).collect();
+
+                // reading
+                let input = Unpack::new(&conf, order, &packed[..]).unwrap();
+                let mut encoded = conf.encode_reader(input).unwrap();
+                let decoded = conf.decode_reader(BufReader::new(&mut encoded)).unwrap();
+                let mut output = vec![];
+                Pack::new(&conf, order, decoded)
+                    .unwrap()
+                    .read_to_end(&mut output)
+                    .unwrap();
+                assert_eq!(&output[..packed.len()], packed);
+
+                // writing
+                let decoder = conf.decode_writer(Pack::new(&conf, order, vec![]).unwrap());
+                let encoder = conf.encode_writer(decoder.unwrap()).unwrap();
+                let mut input = Unpack::new(&conf, order, encoder).unwrap();
+                for piece in packed.chunks(33) {
+                    input.write_all(piece).unwrap();
+                }
+                let decoder = input.into_inner().finish().unwrap();
+                let output = decoder.finish().unwrap().finish().unwrap();
+                assert_eq!(&output[..packed.len()], packed);
+            }
+        }
+    }
+}
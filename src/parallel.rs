@@ -0,0 +1,231 @@
+use crate::{predict, sample, Configuration, Error};
+
+use rayon::prelude::*;
+
+// the smallest piece of input worth encoding on its own
+const MIN_CHUNK_SIZE: usize = 1 << 20;
+
+impl Configuration {
+    /// Encode a buffer like [`Configuration::encode_buffer`], using
+    /// all of rayon's threads.
+    ///
+    /// The input is split into runs of whole reference sample
+    /// intervals, which are encoded independently and then joined
+    /// bit for bit, so the result decodes with an ordinary
+    /// [`Decoder`](crate::Decoder). Code options may be chosen
+    /// differently than by `encode_buffer`, as each run starts
+    /// without knowledge of the one before it.
+    pub fn encode_buffer_parallel<'a>(
+        &self,
+        input: &[u8],
+        output: &'a mut Vec<u8>,
+    ) -> Result<&'a mut [u8], Error> {
+        let rsi_bytes = self.rsi_bytes();
+        if rsi_bytes == 0 {
+            // let the encoder report the bad configuration
+            return self.encode_buffer(input, output);
+        }
+        let rsis = input.len() / rsi_bytes;
+        let threads = rayon::current_num_threads();
+        let chunk_rsis = rsis.div_ceil(threads);
+        let min_rsis = MIN_CHUNK_SIZE.div_ceil(rsi_bytes);
+        self.encode_chunks(input, output, chunk_rsis.max(min_rsis))
+    }
+
+    fn rsi_bytes(&self) -> usize {
+        let storage = sample::storage_size(self.bits_per_sample, self.flags);
+        self.rsi * self.block_size * storage
+    }
+
+    fn encode_chunks<'a>(
+        &self,
+        input: &[u8],
+        output: &'a mut Vec<u8>,
+        chunk_rsis: usize,
+    ) -> Result<&'a mut [u8], Error> {
+        if let Some(state) = predict::State::new(self) {
+            // let the encoder check the configuration before
+            // predicting anything with it
+            self.encoder()?;
+            // predict everything up front, so that chunks can be
+            // coded apart
+            let mut residuals = vec![0; input.len()];
+            let n = state.forward(input, &mut residuals) * state.storage();
+            let mut conf = self.clone();
+            conf.prediction = None;
+            return conf.encode_chunks(&residuals[..n], output, chunk_rsis);
+        }
+
+        // partial samples are ignored by the encoder anyway, and
+        // should not end up alone in the last chunk
+        let storage = sample::storage_size(self.bits_per_sample, self.flags);
+        let input = &input[..input.len() - input.len() % storage];
+
+        let rsi_bytes = self.rsi_bytes();
+        let chunk = chunk_rsis * rsi_bytes;
+        let count = input.len().div_ceil(chunk);
+        if count <= 1 {
+            return self.encode_buffer(input, output);
+        }
+
+        let pieces = (0..count)
+            .into_par_iter()
+            .map(|i| {
+                let start = i * chunk;
+                let last = i + 1 == count;
+                let mut enc = self.encoder()?;
+                let mut out = vec![];
+                if last {
+                    enc.encode_all(&input[start..], &mut out)?;
+                    enc.end()?;
+                    let bits = out.len() * 8;
+                    return Ok((out, bits));
+                }
+
+                // encode the first interval of the next chunk too,
+                // as its offset is where this chunk's bits end
+                let end = (start + chunk + rsi_bytes).min(input.len());
+                enc.enable_offsets()?;
+                enc.encode_all(&input[start..end], &mut out)?;
+                let bits = enc.offsets()?[chunk_rsis];
+                enc.end()?;
+                Ok((out, bits))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let start = output.len();
+        let mut bits = 0;
+        for (data, len) in pieces {
+            append_bits(output, &mut bits, &data, len);
+        }
+        Ok(&mut output[start..])
+    }
+}
+
+/// Append the first `len` bits of `src` to `out`, which ends with
+/// `bits` bits written since the last byte-aligned start.
+fn append_bits(out: &mut Vec<u8>, bits: &mut usize, src: &[u8], len: usize) {
+    let shift = *bits % 8;
+    // where the first byte touched by this call starts
+    let base = out.len() - (shift != 0) as usize;
+    let src = &src[..len.div_ceil(8)];
+    for (i, &b) in src.iter().enumerate() {
+        // drop anything past len in the last byte
+        let rem = len % 8;
+        let b = if i + 1 == src.len() && rem != 0 {
+            b & !(0xff >> rem)
+        } else {
+            b
+        };
+        if shift == 0 {
+            out.push(b);
+        } else {
+            *out.last_mut().unwrap() |= b >> shift;
+            out.push(b << (8 - shift));
+        }
+    }
+    out.truncate(base + (shift + len).div_ceil(8));
+    *bits += len;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{append_bits, MIN_CHUNK_SIZE};
+    use crate::{Configuration, Flags, Med};
+
+    #[test]
+    fn append() {
+        let mut out = vec![];
+        let mut bits = 0;
+        append_bits(&mut out, &mut bits, &[0b1010_1111], 3);
+        append_bits(&mut out, &mut bits, &[0xff, 0b1100_0000], 10);
+        append_bits(&mut out, &mut bits, &[0b0110_0000], 3);
+        assert_eq!(bits, 16);
+        assert_eq!(out, [0b1011_1111, 0b1111_1011]);
+    }
+
+    #[test]
+    fn encode_chunks() {
+        let data: Vec<u8> = (0..5000u32)
+            .flat_map(|i| ((i * i / 13 + i % 7) as u16).to_le_bytes())
+            .collect();
+        for &flags in &[
+            Flags::DATA_PREPROCESS,
+            Flags::DATA_PREPROCESS | Flags::PAD_RSI,
+            Flags::empty(),
+        ] {
+            let conf = Configuration::new(16, 16, 3, flags);
+            for &chunk_rsis in &[1, 4, 25, 1000] {
+                let mut encoded = vec![7];
+                conf.encode_chunks(&data, &mut encoded, chunk_rsis).unwrap();
+                assert_eq!(encoded[0], 7);
+                let mut decoded = vec![];
+                conf.decode_buffer(&encoded[1..], &mut decoded).unwrap();
+                assert_eq!(&decoded[..data.len()], data, "{:?}", flags);
+            }
+        }
+
+        let conf = Configuration::new(16, 16, 3, Flags::empty()).with_predictor(Med, 50);
+        let mut encoded = vec![];
+        conf.encode_chunks(&data, &mut encoded, 4).unwrap();
+        let mut decoded = vec![];
+        conf.decode_buffer(&encoded, &mut decoded).unwrap();
+        assert_eq!(&decoded[..data.len()], data);
+
+        // bad configurations fail as they do serially
+        for conf in &[
+            Configuration::new(0, 16, 4, Flags::DATA_SIGNED).with_predictor(Med, 4),
+            Configuration::new(40, 16, 4, Flags::empty()).with_predictor(Med, 4),
+            Configuration::new(16, 16, 4, Flags::empty()).with_predictor(Med, 0),
+        ] {
+            assert!(conf.encode_buffer(&data, &mut vec![]).is_err());
+            assert!(conf.encode_buffer_parallel(&data, &mut vec![]).is_err());
+        }
+
+        let conf = Configuration::new(16, 16, 3, Flags::DATA_PREPROCESS);
+        let mut parallel = vec![];
+        conf.encode_buffer_parallel(&data, &mut parallel).unwrap();
+        let mut serial = vec![];
+        conf.encode_buffer(&data, &mut serial).unwrap();
+        assert_eq!(parallel, serial);
+    }
+
+    #[test]
+    fn encode_large() {
+        // several chunks of at least MIN_CHUNK_SIZE, plus a bit more
+        let conf = Configuration::new(16, 16, 3, Flags::DATA_PREPROCESS);
+        let data: Vec<u8> = (0..(5 << 20) / 2 + 1234u32)
+            .map(|i| (i.wrapping_mul(i) >> 7 & 0x3ff) + i % 5)
+            .flat_map(|v| (v as u16).to_le_bytes())
+            .collect();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .unwrap();
+        let mut parallel = vec![];
+        pool.install(|| conf.encode_buffer_parallel(&data, &mut parallel))
+            .unwrap();
+        let mut serial = vec![];
+        conf.encode_buffer(&data, &mut serial).unwrap();
+        // each chunk starts its choice of code options afresh, so
+        // compare what comes back out
+        let mut from_parallel = vec![];
+        conf.decode_buffer(&parallel, &mut from_parallel).unwrap();
+        let mut from_serial = vec![];
+        conf.decode_buffer(&serial, &mut from_serial).unwrap();
+        assert!(from_parallel == from_serial);
+        assert!(from_parallel[..data.len()] == data[..]);
+
+        // the first chunk, coded the same either way, must not end
+        // on a byte, and there must be more after it
+        let rsi_bytes = conf.rsi_bytes();
+        let chunk_rsis = (data.len() / rsi_bytes)
+            .div_ceil(4)
+            .max(MIN_CHUNK_SIZE.div_ceil(rsi_bytes));
+        assert!(data.len() > 3 * chunk_rsis * rsi_bytes);
+        let mut enc = conf.encoder().unwrap();
+        enc.enable_offsets().unwrap();
+        enc.encode_all(&data, &mut vec![]).unwrap();
+        assert_ne!(enc.offsets().unwrap()[chunk_rsis] % 8, 0);
+    }
+}
@@ -0,0 +1,460 @@
+//! Prediction ahead of the coder, for data where the unit-delay
+//! predictor of [`Flags::DATA_PREPROCESS`] does poorly.
+//!
+//! Samples are treated as rows of `width` samples. Each is replaced
+//! by its difference from a prediction, wrapped to the sample size
+//! and folded so that small differences of either sign become small
+//! values, which is what the coder does best on. Decoding undoes
+//! this exactly, so prediction is always lossless.
+//!
+//! [`Flags::DATA_PREPROCESS`]: crate::Flags::DATA_PREPROCESS
+
+use crate::{sample, Configuration, Flags};
+
+use std::any::TypeId;
+use std::collections::VecDeque;
+use std::panic::RefUnwindSafe;
+use std::sync::Arc;
+
+/// Samples already seen around the one being predicted. Each is
+/// `None` at the edges of the data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Neighbours {
+    /// The sample before this one in its row.
+    pub left: Option<i64>,
+    /// The sample above this one, in the previous row.
+    pub up: Option<i64>,
+    /// The sample before `up`.
+    pub up_left: Option<i64>,
+}
+
+/// Predicts each sample from its [`Neighbours`].
+///
+/// Predictions are clamped to the range of the sample type, so any
+/// value may be returned. This is implemented for closures taking
+/// `&Neighbours`.
+///
+/// Configurations using predictors with no state, like the ones here,
+/// are equal if their predictor types and widths are. Other
+/// predictors are only equal to themselves, shared between clones of
+/// a configuration.
+pub trait Predictor: Send + Sync + RefUnwindSafe {
+    fn predict(&self, neighbours: &Neighbours) -> i64;
+}
+
+impl<F> Predictor for F
+where
+    F: Fn(&Neighbours) -> i64 + Send + Sync + RefUnwindSafe,
+{
+    fn predict(&self, neighbours: &Neighbours) -> i64 {
+        self(neighbours)
+    }
+}
+
+/// Predict each sample from the one above it. For interleaved
+/// channels, use a width of the number of channels to predict from
+/// the previous sample of the same channel.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PreviousRow;
+
+impl Predictor for PreviousRow {
+    fn predict(&self, n: &Neighbours) -> i64 {
+        n.up.or(n.left).unwrap_or(0)
+    }
+}
+
+/// Predict each sample from the average of the samples to its left
+/// and above it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Average;
+
+impl Predictor for Average {
+    fn predict(&self, n: &Neighbours) -> i64 {
+        match (n.left, n.up) {
+            (Some(a), Some(b)) => (a + b).div_euclid(2),
+            (a, b) => a.or(b).unwrap_or(0),
+        }
+    }
+}
+
+/// The median edge detector of LOCO-I and JPEG-LS, which picks the
+/// sample to the left or above at edges, and a planar prediction
+/// elsewhere.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Med;
+
+impl Predictor for Med {
+    fn predict(&self, n: &Neighbours) -> i64 {
+        match (n.left, n.up, n.up_left) {
+            (Some(a), Some(b), Some(c)) => {
+                if c >= a.max(b) {
+                    a.min(b)
+                } else if c <= a.min(b) {
+                    a.max(b)
+                } else {
+                    a + b - c
+                }
+            }
+            (a, b, _) => a.or(b).unwrap_or(0),
+        }
+    }
+}
+
+/// A predictor and the row width it is used with.
+#[derive(Clone)]
+pub(crate) struct Prediction {
+    pub predictor: Arc<dyn Predictor>,
+    pub width: usize,
+    key: Key,
+}
+
+// what a predictor is compared by: predictors with no state, like the
+// built-in ones, always predict the same way, so they are compared by
+// type, and anything else only by which one it is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Key {
+    Type(TypeId),
+    Shared(usize),
+}
+
+impl Prediction {
+    pub fn new<P>(predictor: P, width: usize) -> Self
+    where
+        P: Predictor + 'static,
+    {
+        let predictor = Arc::new(predictor);
+        let key = if std::mem::size_of::<P>() == 0 {
+            Key::Type(TypeId::of::<P>())
+        } else {
+            Key::Shared(Arc::as_ptr(&predictor) as usize)
+        };
+        Prediction {
+            predictor,
+            width,
+            key,
+        }
+    }
+}
+
+impl std::fmt::Debug for Prediction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("Prediction")
+            .field("predictor", &self.key)
+            .field("width", &self.width)
+            .finish()
+    }
+}
+
+impl PartialEq for Prediction {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key && self.width == other.width
+    }
+}
+
+impl Eq for Prediction {}
+
+impl std::hash::Hash for Prediction {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+        self.width.hash(state);
+    }
+}
+
+/// Prediction state for one stream.
+pub(crate) struct State {
+    prediction: Prediction,
+    bits: u32,
+    storage: usize,
+    msb: bool,
+    signed: bool,
+    // the last width + 1 samples, and how many have been seen
+    history: VecDeque<i64>,
+    count: usize,
+}
+
+impl std::fmt::Debug for State {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("State")
+            .field("prediction", &self.prediction)
+            .field("count", &self.count)
+            .finish()
+    }
+}
+
+impl State {
+    /// Prediction state for `conf`, if it has a predictor.
+    pub fn new(conf: &Configuration) -> Option<Self> {
+        let prediction = conf.prediction.clone()?;
+        let bits = conf.bits_per_sample.min(32) as u32;
+        Some(Self {
+            history: VecDeque::with_capacity(prediction.width + 1),
+            prediction,
+            bits,
+            storage: sample::storage_size(conf.bits_per_sample, conf.flags),
+            msb: conf.flags.contains(Flags::DATA_MSB),
+            signed: conf.flags.contains(Flags::DATA_SIGNED),
+            count: 0,
+        })
+    }
+
+    pub fn storage(&self) -> usize {
+        self.storage
+    }
+
+    /// Forget all samples seen, as at the start of a stream.
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.count = 0;
+    }
+
+    fn mask(&self) -> u64 {
+        (1 << self.bits) - 1
+    }
+
+    /// Sign-extend the low bits of `v`.
+    fn extend(&self, v: i64) -> i64 {
+        let shift = 64 - self.bits;
+        (v << shift) >> shift
+    }
+
+    /// Bring `v` into the range of a sample, modulo its size.
+    fn wrap(&self, v: i64) -> i64 {
+        if self.signed {
+            self.extend(v)
+        } else {
+            (v as u64 & self.mask()) as i64
+        }
+    }
+
+    fn read(&self, bytes: &[u8]) -> i64 {
+        let mut v = 0u64;
+        for (i, b) in bytes.iter().enumerate() {
+            let i = if self.msb { self.storage - 1 - i } else { i };
+            v |= (*b as u64) << (8 * i);
+        }
+        self.wrap(v as i64)
+    }
+
+    fn write(&self, v: i64, bytes: &mut [u8]) {
+        let v = self.wrap(v) as u64;
+        for (i, b) in bytes.iter_mut().enumerate() {
+            let i = if self.msb { self.storage - 1 - i } else { i };
+            *b = (v >> (8 * i)) as u8;
+        }
+    }
+
+    fn predict(&self, index: usize, sample: impl Fn(usize) -> i64) -> i64 {
+        let width = self.prediction.width;
+        let first_col = index.is_multiple_of(width);
+        let first_row = index < width;
+        let neighbours = Neighbours {
+            left: (!first_col).then(|| sample(index - 1)),
+            up: (!first_row).then(|| sample(index - width)),
+            up_left: (!first_col && !first_row).then(|| sample(index - width - 1)),
+        };
+        let p = self.prediction.predictor.predict(&neighbours);
+        if self.signed {
+            let max = (1i64 << (self.bits - 1)) - 1;
+            p.clamp(-max - 1, max)
+        } else {
+            p.clamp(0, self.mask() as i64)
+        }
+    }
+
+    /// Sample `index` of the stream, where samples from `count` on
+    /// are in `data`.
+    fn sample(&self, data: &[u8], index: usize) -> i64 {
+        if index >= self.count {
+            let i = (index - self.count) * self.storage;
+            self.read(&data[i..i + self.storage])
+        } else {
+            self.history[self.history.len() - (self.count - index)]
+        }
+    }
+
+    /// Write residuals for the next samples of `input` to `output`,
+    /// without advancing. Returns the number of samples written.
+    pub fn forward(&self, input: &[u8], output: &mut [u8]) -> usize {
+        let n = (input.len() / self.storage).min(output.len() / self.storage);
+        let outputs = output.chunks_exact_mut(self.storage);
+        for (i, (bytes, out)) in input.chunks_exact(self.storage).zip(outputs).enumerate() {
+            let x = self.read(bytes);
+            let p = self.predict(self.count + i, |j| self.sample(input, j));
+            let d = self.extend(x - p);
+            // fold so that 0, -1, 1, -2, ... become 0, 1, 2, 3, ...
+            let z = ((d << 1) ^ (d >> 63)) as u64 & self.mask();
+            self.write(z as i64, out);
+        }
+        n
+    }
+
+    /// Advance past `input`, once its residuals have been coded.
+    pub fn advance(&mut self, input: &[u8]) {
+        for bytes in input.chunks_exact(self.storage) {
+            let x = self.read(bytes);
+            self.push(x);
+        }
+    }
+
+    /// Replace the decoded residuals in `data` with samples, in
+    /// place, and advance past them.
+    pub fn inverse(&mut self, data: &mut [u8]) {
+        for i in 0..data.len() / self.storage {
+            let pos = i * self.storage;
+            let z = self.read(&data[pos..pos + self.storage]) as u64 & self.mask();
+            let d = (z >> 1) as i64 ^ -((z & 1) as i64);
+            // samples before this one in data are already restored
+            let p = self.predict(self.count + i, |j| self.sample(data, j));
+            self.write(p + d, &mut data[pos..pos + self.storage]);
+        }
+        self.advance(data);
+    }
+
+    fn push(&mut self, x: i64) {
+        if self.history.len() > self.prediction.width {
+            self.history.pop_front();
+        }
+        self.history.push_back(x);
+        self.count += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Average, Med, Neighbours, Predictor, PreviousRow};
+    use crate::{Configuration, Flags};
+
+    fn image(width: usize, height: usize) -> Vec<u16> {
+        (0..width * height)
+            .map(|i| {
+                let (x, y) = ((i % width) as i64, (i / width) as i64);
+                (2000 + 7 * x + 13 * y + (x * y) % 5) as u16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn predictors() {
+        let n = Neighbours {
+            left: Some(10),
+            up: Some(20),
+            up_left: Some(12),
+        };
+        assert_eq!(PreviousRow.predict(&n), 20);
+        assert_eq!(Average.predict(&n), 15);
+        assert_eq!(Med.predict(&n), 18);
+        assert_eq!(
+            Med.predict(&Neighbours {
+                up_left: Some(25),
+                ..n
+            }),
+            10
+        );
+        assert_eq!(
+            Med.predict(&Neighbours {
+                up_left: Some(5),
+                ..n
+            }),
+            20
+        );
+        let edge = Neighbours {
+            left: Some(-3),
+            ..Neighbours::default()
+        };
+        assert_eq!(PreviousRow.predict(&edge), -3);
+        assert_eq!(Average.predict(&edge), -3);
+        assert_eq!(Med.predict(&Neighbours::default()), 0);
+    }
+
+    #[test]
+    fn equality() {
+        let conf = Configuration::new(16, 16, 32, Flags::empty());
+        let med = conf.clone().with_predictor(Med, 4);
+        assert_eq!(med, conf.clone().with_predictor(Med, 4));
+        assert_ne!(med, conf.clone().with_predictor(Med, 8));
+        assert_ne!(med, conf.clone().with_predictor(Average, 4));
+        assert_ne!(med, conf);
+
+        // a predictor with state is only equal to itself
+        let offset = 3;
+        let f = conf.with_predictor(move |n: &Neighbours| n.left.unwrap_or(0) + offset, 4);
+        assert_eq!(f, f.clone());
+        assert_ne!(f, f.clone().with_predictor(move |_: &Neighbours| offset, 4));
+
+        fn unwind_safe<T: std::panic::RefUnwindSafe + std::panic::UnwindSafe>(_: &T) {}
+        unwind_safe(&med);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let width = 37;
+        let samples = image(width, 50);
+        let data: Vec<u8> = samples.iter().flat_map(|s| s.to_be_bytes()).collect();
+        let base = Configuration::new(16, 16, 8, Flags::DATA_MSB);
+        let mut plain = vec![];
+        base.encode_buffer(&data, &mut plain).unwrap();
+
+        let confs = [
+            base.clone().with_predictor(PreviousRow, width),
+            base.clone().with_predictor(Average, width),
+            base.clone().with_predictor(Med, width),
+            base.clone()
+                .with_predictor(|n: &Neighbours| n.left.unwrap_or(0), width),
+        ];
+        for conf in &confs {
+            let mut encoded = vec![];
+            conf.encode_buffer(&data, &mut encoded).unwrap();
+            assert!(encoded.len() < plain.len() * 2 / 3);
+            let mut decoded = vec![];
+            conf.decode_buffer(&encoded, &mut decoded).unwrap();
+            assert_eq!(&decoded[..data.len()], data);
+
+            // streaming, in pieces that split rows
+            let mut writer = conf.encode_writer(vec![]).unwrap();
+            for piece in data.chunks(102) {
+                std::io::Write::write_all(&mut writer, piece).unwrap();
+            }
+            let streamed = writer.finish().unwrap();
+            assert_eq!(streamed, encoded);
+            let mut reader = conf.decode_reader(&streamed[..]).unwrap();
+            let mut decoded = vec![];
+            std::io::Read::read_to_end(&mut reader, &mut decoded).unwrap();
+            assert_eq!(&decoded[..data.len()], data);
+        }
+        assert_ne!(confs[0], confs[1]);
+        assert_eq!(confs[0], confs[0].clone());
+    }
+
+    #[test]
+    fn wrapping() {
+        // extreme predictions and values still round trip
+        for &flags in &[Flags::empty(), Flags::DATA_SIGNED, Flags::DATA_3BYTE] {
+            let conf = Configuration::new(20, 8, 4, flags)
+                .with_predictor(|n: &Neighbours| n.up.unwrap_or(i64::MAX), 3);
+            let storage = if flags.contains(Flags::DATA_3BYTE) {
+                3
+            } else {
+                4
+            };
+            let values: Vec<u32> = vec![0, 0xfffff, 0x80000, 0x7ffff, 1, 0xfffff, 0, 5, 0x12345];
+            let mut data = vec![];
+            for v in &values {
+                let v = if flags.contains(Flags::DATA_SIGNED) {
+                    ((v << 12) as i32 >> 12) as u32
+                } else {
+                    *v
+                };
+                data.extend_from_slice(&v.to_le_bytes()[..storage]);
+            }
+            let mut encoded = vec![];
+            conf.encode_buffer(&data, &mut encoded).unwrap();
+            let mut decoded = vec![];
+            conf.decode_buffer(&encoded, &mut decoded).unwrap();
+            assert_eq!(&decoded[..data.len()], data);
+        }
+
+        let conf = Configuration::new(8, 8, 4, Flags::empty()).with_predictor(Med, 0);
+        assert!(conf.encoder().is_err());
+        assert!(conf.decoder().is_err());
+    }
+}
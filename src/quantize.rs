@@ -0,0 +1,441 @@
+//! Lossy compression of floating-point arrays.
+//!
+//! Values are quantized to integers `Q` that reconstruct as
+//! `offset + Q * scale`, using as few bits as an error [`Bound`]
+//! allows, and the integers are compressed with AEC. NaN and fill
+//! values are left out, and recorded in a separate mask.
+//!
+//! [`encode_f64`] and [`encode_f32`] produce a [`Quantized`] array,
+//! which holds the [`Params`] needed to decode it, and can be stored
+//! with [`Quantized::to_bytes`].
+
+use crate::{Configuration, Error, ErrorKind, Flags};
+
+use std::convert::{TryFrom, TryInto};
+
+/// The length of the header written by [`Quantized::to_bytes`].
+pub const HEADER_SIZE: usize = 38;
+
+/// Block size used by [`encode_f64`] and [`encode_f32`].
+pub const DEFAULT_BLOCK_SIZE: u8 = 32;
+
+/// Reference sample interval used by [`encode_f64`] and
+/// [`encode_f32`].
+pub const DEFAULT_RSI: u16 = 128;
+
+/// The largest error allowed in decoded values.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bound {
+    /// Keep values within this absolute error.
+    Absolute(f64),
+    /// Keep this many digits after the decimal point, an absolute
+    /// error of half a unit in the last place.
+    Decimal(i16),
+}
+
+impl Bound {
+    fn absolute(&self) -> f64 {
+        match *self {
+            Self::Absolute(e) => e,
+            Self::Decimal(d) => 0.5 * 10f64.powi(-(d as i32)),
+        }
+    }
+}
+
+/// How a quantized array is stored.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Params {
+    /// The value of `Q = 0`, the minimum of the array.
+    pub offset: f64,
+    /// The step between quantized values.
+    pub scale: f64,
+    /// The value given to masked points when decoding, or NaN.
+    pub fill: f64,
+    /// The number of points, including masked points.
+    pub count: u64,
+    /// Bits per quantized value, or zero for a constant array.
+    pub bits_per_sample: u8,
+    /// CCSDS block size.
+    pub block_size: u8,
+    /// CCSDS reference sample interval.
+    pub rsi: u16,
+}
+
+impl Params {
+    /// The configuration used for the quantized values.
+    pub fn configuration(&self) -> Configuration {
+        let mut flags = Flags::DATA_PREPROCESS;
+        if self.bits_per_sample > 16 && self.bits_per_sample <= 24 {
+            flags |= Flags::DATA_3BYTE;
+        }
+        Configuration::new(
+            self.bits_per_sample as usize,
+            self.block_size as usize,
+            self.rsi as usize,
+            flags,
+        )
+    }
+}
+
+/// A quantized and compressed array.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Quantized {
+    /// How the values are stored.
+    pub params: Params,
+    /// One bit for each point, most significant bit first, set for
+    /// points that have a value. `None` if every point has one.
+    pub mask: Option<Vec<u8>>,
+    /// The compressed values of the points that have one.
+    pub payload: Vec<u8>,
+}
+
+impl Quantized {
+    /// Whether point `i` has a value.
+    pub fn is_valid(&self, i: usize) -> bool {
+        match &self.mask {
+            Some(mask) => mask[i / 8] & (0x80 >> (i % 8)) != 0,
+            None => true,
+        }
+    }
+
+    /// The number of points that have a value.
+    pub fn valid(&self) -> usize {
+        match &self.mask {
+            Some(_) => (0..self.params.count as usize)
+                .filter(|&i| self.is_valid(i))
+                .count(),
+            None => self.params.count as usize,
+        }
+    }
+
+    /// Decode the array. Masked points take the fill value.
+    pub fn decode_f64(&self) -> Result<Vec<f64>, Error> {
+        self.decode(|y| y)
+    }
+
+    /// Decode the array, as `f32`.
+    pub fn decode_f32(&self) -> Result<Vec<f32>, Error> {
+        self.decode(|y| y as f32)
+    }
+
+    fn decode<T, F>(&self, convert: F) -> Result<Vec<T>, Error>
+    where
+        T: Copy,
+        F: Fn(f64) -> T,
+    {
+        let p = &self.params;
+        let count = self.check_count()?;
+        let valid = self.valid();
+
+        let mut quantized = vec![];
+        if p.bits_per_sample > 0 {
+            quantized.resize(valid, 0u32);
+            let decoded = p
+                .configuration()
+                .decode_samples(&self.payload, &mut quantized)?
+                .len();
+            if decoded != valid {
+                return Err(ErrorKind::Data.into());
+            }
+        }
+
+        // a constant array has no payload to check its count against
+        let mut output = Vec::new();
+        output
+            .try_reserve_exact(count)
+            .map_err(|_| ErrorKind::Data)?;
+        let mut values = quantized.into_iter();
+        output.extend((0..count).map(|i| {
+            if self.is_valid(i) {
+                let q = values.next().unwrap_or(0);
+                convert(p.offset + q as f64 * p.scale)
+            } else {
+                convert(p.fill)
+            }
+        }));
+        Ok(output)
+    }
+
+    // check the count against what the mask and payload can hold,
+    // before allocating anything for it
+    fn check_count(&self) -> Result<usize, Error> {
+        let p = &self.params;
+        let count = usize::try_from(p.count).map_err(|_| ErrorKind::Data)?;
+        if let Some(mask) = &self.mask {
+            if mask.len() < count.div_ceil(8) {
+                return Err(ErrorKind::Data.into());
+            }
+        }
+        if p.bits_per_sample > 0 {
+            // a run of up to 64 zero blocks takes at least a bit
+            let most = self
+                .payload
+                .len()
+                .saturating_mul(8 * 64 * p.block_size as usize);
+            if self.valid() > most {
+                return Err(ErrorKind::Data.into());
+            }
+        }
+        Ok(count)
+    }
+
+    /// Store the parameters, mask and payload together.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let p = &self.params;
+        let mask = self.mask.as_deref().unwrap_or(&[]);
+        let mut bytes = Vec::with_capacity(HEADER_SIZE + mask.len() + self.payload.len());
+        bytes.extend_from_slice(&p.offset.to_le_bytes());
+        bytes.extend_from_slice(&p.scale.to_le_bytes());
+        bytes.extend_from_slice(&p.fill.to_le_bytes());
+        bytes.extend_from_slice(&p.count.to_le_bytes());
+        bytes.push(p.bits_per_sample);
+        bytes.push(p.block_size);
+        bytes.extend_from_slice(&p.rsi.to_le_bytes());
+        bytes.push(self.mask.is_some() as u8);
+        bytes.push(0);
+        bytes.extend_from_slice(mask);
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    /// Read an array written by [`Quantized::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(ErrorKind::Data.into());
+        }
+        let f = |i: usize| f64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let params = Params {
+            offset: f(0),
+            scale: f(8),
+            fill: f(16),
+            count: u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
+            bits_per_sample: bytes[32],
+            block_size: bytes[33],
+            rsi: u16::from_le_bytes([bytes[34], bytes[35]]),
+        };
+        let mut rest = &bytes[HEADER_SIZE..];
+        let mask = if bytes[36] != 0 {
+            let count = usize::try_from(params.count).map_err(|_| ErrorKind::Data)?;
+            let len = count.div_ceil(8);
+            if rest.len() < len {
+                return Err(ErrorKind::Data.into());
+            }
+            let (mask, payload) = rest.split_at(len);
+            rest = payload;
+            Some(mask.to_vec())
+        } else {
+            None
+        };
+        let quantized = Self {
+            params,
+            mask,
+            payload: rest.to_vec(),
+        };
+        quantized.check_count()?;
+        Ok(quantized)
+    }
+}
+
+// the number of bits needed to hold x
+fn bits_for(x: u64) -> u32 {
+    64 - x.leading_zeros()
+}
+
+/// Quantize and compress `values`, keeping them within `bound`.
+///
+/// NaN values, and values equal to `fill` if given, are masked out
+/// and decode as `fill`, or NaN. Other values must be finite.
+pub fn encode_f64(values: &[f64], bound: Bound, fill: Option<f64>) -> Result<Quantized, Error> {
+    let bound = bound.absolute();
+    if !(bound.is_finite() && bound > 0.0) {
+        return Err(ErrorKind::Configuration.into());
+    }
+    let is_valid = |v: f64| !v.is_nan() && Some(v) != fill;
+    if values.iter().any(|&v| is_valid(v) && !v.is_finite()) {
+        return Err(ErrorKind::Data.into());
+    }
+
+    let mask = if values.iter().all(|&v| is_valid(v)) {
+        None
+    } else {
+        let mut mask = vec![0; values.len().div_ceil(8)];
+        for (i, _) in values.iter().enumerate().filter(|(_, &v)| is_valid(v)) {
+            mask[i / 8] |= 0x80 >> (i % 8);
+        }
+        Some(mask)
+    };
+    let valid: Vec<f64> = values.iter().copied().filter(|&v| is_valid(v)).collect();
+    let (min, max) = valid
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+            (lo.min(v), hi.max(v))
+        });
+
+    // rounding to the nearest step is off by at most half a step
+    let scale = 2.0 * bound;
+    let offset = if valid.is_empty() { 0.0 } else { min };
+    let top = if valid.is_empty() {
+        0
+    } else {
+        ((max - min) / scale).round() as u64
+    };
+    let bits = bits_for(top);
+    if bits > 32 {
+        return Err(ErrorKind::Configuration.into());
+    }
+
+    let params = Params {
+        offset,
+        scale,
+        fill: fill.unwrap_or(f64::NAN),
+        count: values.len() as u64,
+        bits_per_sample: bits as u8,
+        block_size: DEFAULT_BLOCK_SIZE,
+        rsi: DEFAULT_RSI,
+    };
+    let mut payload = vec![];
+    if bits > 0 {
+        let quantized: Vec<u32> = valid
+            .iter()
+            .map(|v| (((v - offset) / scale).round() as u64).min(top) as u32)
+            .collect();
+        params
+            .configuration()
+            .encode_samples(&quantized, &mut payload)?;
+    }
+
+    Ok(Quantized {
+        params,
+        mask,
+        payload,
+    })
+}
+
+/// Quantize and compress `values`, keeping them within `bound`.
+///
+/// See [`encode_f64`]. Decoding as `f32` adds rounding of at most half
+/// a unit in the last place.
+pub fn encode_f32(values: &[f32], bound: Bound, fill: Option<f32>) -> Result<Quantized, Error> {
+    let values: Vec<f64> = values.iter().map(|&v| v as f64).collect();
+    encode_f64(&values, bound, fill.map(|v| v as f64))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode_f32, encode_f64, Bound, Quantized};
+    use crate::ErrorKind;
+
+    fn field() -> Vec<f64> {
+        (0..3000)
+            .map(|i| {
+                let x = i as f64 / 40.0;
+                280.0 + 15.0 * x.sin() + 0.3 * (7.0 * x).cos()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bound() {
+        let values = field();
+        for &bound in &[
+            Bound::Absolute(0.01),
+            Bound::Decimal(1),
+            Bound::Absolute(3.0),
+        ] {
+            let q = encode_f64(&values, bound, None).unwrap();
+            assert!(q.mask.is_none());
+            let e = bound.absolute();
+            // the smallest number of bits that covers the range
+            let max = values.iter().cloned().fold(f64::MIN, f64::max);
+            let top = ((max - q.params.offset) / (2.0 * e)).round() as u64;
+            assert_eq!(q.params.bits_per_sample as u32, 64 - top.leading_zeros());
+            let decoded = q.decode_f64().unwrap();
+            for (a, b) in values.iter().zip(&decoded) {
+                assert!((a - b).abs() <= e * (1.0 + 1e-9), "{} {} {:?}", a, b, bound);
+            }
+            assert!(q.payload.len() < values.len() * 4);
+        }
+
+        assert_eq!(
+            encode_f64(&[1.0], Bound::Absolute(0.0), None)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::Configuration
+        );
+        assert_eq!(
+            encode_f64(&[0.0, 1e20], Bound::Decimal(3), None)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::Configuration
+        );
+        assert_eq!(
+            encode_f64(&[f64::INFINITY], Bound::Decimal(3), None)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::Data
+        );
+    }
+
+    #[test]
+    fn mask() {
+        let mut values: Vec<f32> = field().iter().map(|&v| v as f32).collect();
+        values[3] = f32::NAN;
+        values[100] = -9999.0;
+        values[2999] = -9999.0;
+        let q = encode_f32(&values, Bound::Decimal(2), Some(-9999.0)).unwrap();
+        assert_eq!(q.valid(), 2997);
+        assert!(!q.is_valid(3) && !q.is_valid(100) && q.is_valid(101));
+
+        let q = Quantized::from_bytes(&q.to_bytes()).unwrap();
+        let decoded = q.decode_f32().unwrap();
+        assert_eq!(decoded.len(), values.len());
+        for (i, (a, b)) in values.iter().zip(&decoded).enumerate() {
+            if i == 3 || i == 100 || i == 2999 {
+                assert_eq!(*b, -9999.0);
+            } else {
+                assert!((a - b).abs() <= 0.005 + 1e-4, "{} {}", a, b);
+            }
+        }
+
+        // nothing but missing values, or a constant
+        let q = encode_f64(&[f64::NAN; 10], Bound::Decimal(0), None).unwrap();
+        assert!(q.payload.is_empty());
+        assert!(q.decode_f64().unwrap().iter().all(|v| v.is_nan()));
+        let q = encode_f64(&[2.5; 10], Bound::Decimal(0), None).unwrap();
+        assert_eq!(q.params.bits_per_sample, 0);
+        assert_eq!(q.decode_f64().unwrap(), [2.5; 10]);
+
+        assert!(Quantized::from_bytes(&[0; 10]).is_err());
+    }
+
+    #[test]
+    fn corrupt_count() {
+        let q = encode_f64(&field(), Bound::Decimal(2), None).unwrap();
+        let bytes = q.to_bytes();
+
+        // far more points than the payload could hold
+        for &count in &[u64::MAX, 1 << 40] {
+            let mut bad = bytes.clone();
+            bad[24..32].copy_from_slice(&count.to_le_bytes());
+            let err = Quantized::from_bytes(&bad).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Data);
+
+            let mut bad = q.clone();
+            bad.params.count = count;
+            assert_eq!(bad.decode_f64().unwrap_err().kind(), ErrorKind::Data);
+        }
+
+        // more than it does hold
+        let mut bad = bytes.clone();
+        bad[24..32].copy_from_slice(&(3000u64 * 1000).to_le_bytes());
+        let bad = Quantized::from_bytes(&bad).unwrap();
+        assert_eq!(bad.decode_f64().unwrap_err().kind(), ErrorKind::Data);
+
+        // or a mask too short for them
+        let mut values = field();
+        values[5] = f64::NAN;
+        let mut q = encode_f64(&values, Bound::Decimal(2), None).unwrap();
+        q.params.count = 3000 * 8;
+        assert_eq!(q.decode_f64().unwrap_err().kind(), ErrorKind::Data);
+    }
+}
@@ -0,0 +1,195 @@
+use crate::Flags;
+
+/// Integer types that can be used directly as AEC samples.
+///
+/// This trait is sealed, and is implemented for `u8`, `u16`, `u32`,
+/// `i8`, `i16` and `i32`.
+pub trait Sample: private::Sealed + Copy {
+    /// The width of this type, in bits.
+    const BITS: usize;
+    /// Whether this type is signed.
+    const SIGNED: bool;
+}
+
+mod private {
+    pub trait Sealed {
+        fn to_bits(self) -> u32;
+        fn from_bits(bits: u32) -> Self;
+    }
+}
+
+macro_rules! impl_sample {
+    ($t:ty, $signed:expr) => {
+        impl private::Sealed for $t {
+            fn to_bits(self) -> u32 {
+                self as u32
+            }
+
+            fn from_bits(bits: u32) -> Self {
+                bits as $t
+            }
+        }
+
+        impl Sample for $t {
+            const BITS: usize = std::mem::size_of::<$t>() * 8;
+            const SIGNED: bool = $signed;
+        }
+    };
+}
+
+impl_sample!(u8, false);
+impl_sample!(u16, false);
+impl_sample!(u32, false);
+impl_sample!(i8, true);
+impl_sample!(i16, true);
+impl_sample!(i32, true);
+
+// size of the on-stack buffer used when samples must be converted
+const SCRATCH_SIZE: usize = 4096;
+
+pub(crate) type Scratch = [u8; SCRATCH_SIZE];
+
+pub(crate) fn scratch() -> Scratch {
+    [0; SCRATCH_SIZE]
+}
+
+/// The number of bytes libaec uses to store each sample.
+pub(crate) fn storage_size(bits_per_sample: usize, flags: Flags) -> usize {
+    match bits_per_sample {
+        0..=8 => 1,
+        9..=16 => 2,
+        17..=24 if flags.contains(Flags::DATA_3BYTE) => 3,
+        _ => 4,
+    }
+}
+
+/// Sample flags appropriate for `T` on this host.
+pub(crate) fn flags_for<T: Sample>(flags: Flags) -> Flags {
+    let mut flags = flags - Flags::DATA_3BYTE;
+    flags.set(Flags::DATA_SIGNED, T::SIGNED);
+    flags.set(Flags::DATA_MSB, cfg!(target_endian = "big"));
+    flags
+}
+
+/// How samples of type `T` are laid out in a stream.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Layout {
+    pub bits: usize,
+    pub storage: usize,
+    pub msb: bool,
+    // true if the in-memory representation of T matches the stream
+    pub native: bool,
+}
+
+impl Layout {
+    pub fn new<T: Sample>(bits_per_sample: usize, flags: Flags) -> Option<Self> {
+        if bits_per_sample > T::BITS || flags.contains(Flags::DATA_SIGNED) != T::SIGNED {
+            return None;
+        }
+        let storage = storage_size(bits_per_sample, flags);
+        let msb = flags.contains(Flags::DATA_MSB);
+        Some(Self {
+            bits: bits_per_sample,
+            storage,
+            msb,
+            native: storage * 8 == T::BITS && msb == cfg!(target_endian = "big"),
+        })
+    }
+
+    pub fn store<T: Sample>(&self, input: &[T], output: &mut [u8]) {
+        for (sample, out) in input.iter().zip(output.chunks_exact_mut(self.storage)) {
+            let bytes = sample.to_bits().to_le_bytes();
+            for (i, b) in out.iter_mut().enumerate() {
+                *b = if self.msb {
+                    bytes[self.storage - 1 - i]
+                } else {
+                    bytes[i]
+                };
+            }
+        }
+    }
+
+    pub fn load<T: Sample>(&self, input: &[u8], output: &mut [T]) {
+        let shift = 32 - self.bits as u32;
+        for (bytes, sample) in input.chunks_exact(self.storage).zip(output.iter_mut()) {
+            let mut v = 0u32;
+            for (i, b) in bytes.iter().enumerate() {
+                let i = if self.msb { self.storage - 1 - i } else { i };
+                v |= (*b as u32) << (8 * i);
+            }
+            if T::SIGNED {
+                v = (((v << shift) as i32) >> shift) as u32;
+            }
+            *sample = T::from_bits(v);
+        }
+    }
+
+    /// Sign-extend samples decoded directly into `T`. Without
+    /// preprocessing, signed samples narrower than `T` are decoded
+    /// with only their low bits set.
+    pub fn extend<T: Sample>(&self, samples: &mut [T]) {
+        if !T::SIGNED || self.bits == T::BITS {
+            return;
+        }
+        let shift = 32 - self.bits as u32;
+        for sample in samples {
+            let v = sample.to_bits();
+            *sample = T::from_bits((((v << shift) as i32) >> shift) as u32);
+        }
+    }
+}
+
+pub(crate) fn as_bytes<T: Sample>(samples: &[T]) -> &[u8] {
+    // safe: T is a plain integer type with no padding
+    unsafe {
+        std::slice::from_raw_parts(
+            samples.as_ptr() as *const u8,
+            std::mem::size_of_val(samples),
+        )
+    }
+}
+
+pub(crate) fn as_bytes_mut<T: Sample>(samples: &mut [T]) -> &mut [u8] {
+    // safe: T is a plain integer type, and every bit pattern is valid
+    unsafe {
+        std::slice::from_raw_parts_mut(
+            samples.as_mut_ptr() as *mut u8,
+            std::mem::size_of_val(samples),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Layout;
+    use crate::Flags;
+
+    #[test]
+    fn layout_roundtrip() {
+        let samples: &[i32] = &[-3, 0, 100, -100_000, 8_000_000];
+        for &flags in &[Flags::empty(), Flags::DATA_MSB, Flags::DATA_3BYTE] {
+            let layout = Layout::new::<i32>(24, flags | Flags::DATA_SIGNED).unwrap();
+            let mut bytes = vec![0; samples.len() * layout.storage];
+            layout.store(samples, &mut bytes);
+            let mut back = vec![0; samples.len()];
+            layout.load(&bytes, &mut back);
+            assert_eq!(back, samples);
+        }
+
+        // only the low bits of each sample matter
+        let layout = Layout::new::<i16>(12, Flags::DATA_SIGNED).unwrap();
+        let mut back = [0i16; 2];
+        layout.load(&[0xff, 0x0f, 0x00, 0xf0], &mut back);
+        assert_eq!(back, [-1, 0]);
+        let mut samples = [0x0fffi16, 0x0800];
+        layout.extend(&mut samples);
+        assert_eq!(samples, [-1, -2048]);
+    }
+
+    #[test]
+    fn layout_rejects() {
+        assert!(Layout::new::<u8>(9, Flags::empty()).is_none());
+        assert!(Layout::new::<u16>(16, Flags::DATA_SIGNED).is_none());
+        assert!(Layout::new::<i16>(16, Flags::empty()).is_none());
+    }
+}
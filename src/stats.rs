@@ -0,0 +1,358 @@
+//! Encoder statistics, found by walking the encoded bitstream.
+//!
+//! libaec does not report which code options it chose, so the
+//! encoder's output is parsed as it is produced. Only the structure
+//! is parsed, and no samples are decoded.
+
+use crate::{sample, Configuration, Flags};
+
+/// The coded zero block count that means "remainder of segment".
+const ROS: u64 = 5;
+
+/// Zero block runs never cross a segment of this many blocks.
+const SEGMENT: usize = 64;
+
+/// How often each code option was used, in blocks.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct OptionCounts {
+    /// Blocks of all zeros.
+    pub zero_block: u32,
+    /// Blocks coded with the second extension option.
+    pub second_extension: u32,
+    /// Blocks coded with the split sample option, by `k`. Blocks
+    /// with `k = 0` use the fundamental sequence option.
+    pub split: [u32; 32],
+    /// Blocks stored uncompressed.
+    pub uncompressed: u32,
+}
+
+impl OptionCounts {
+    /// Blocks coded with the fundamental sequence option.
+    pub fn fundamental_sequence(&self) -> u32 {
+        self.split[0]
+    }
+
+    /// The total number of blocks.
+    pub fn blocks(&self) -> u32 {
+        self.zero_block + self.second_extension + self.split.iter().sum::<u32>() + self.uncompressed
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.zero_block += other.zero_block;
+        self.second_extension += other.second_extension;
+        for (a, b) in self.split.iter_mut().zip(other.split.iter()) {
+            *a += b;
+        }
+        self.uncompressed += other.uncompressed;
+    }
+}
+
+/// Statistics for an encoded stream, from [`Encoder::stats`].
+///
+/// [`Encoder::stats`]: crate::Encoder::stats
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    total_in: usize,
+    total_out: usize,
+    samples: usize,
+    rsis: Vec<OptionCounts>,
+}
+
+impl Stats {
+    /// Uncompressed bytes read by the encoder.
+    pub fn total_in(&self) -> usize {
+        self.total_in
+    }
+
+    /// Compressed bytes written by the encoder.
+    pub fn total_out(&self) -> usize {
+        self.total_out
+    }
+
+    /// Samples read by the encoder.
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// Compressed bits per sample achieved.
+    pub fn bits_per_sample(&self) -> f64 {
+        (self.total_out * 8) as f64 / self.samples as f64
+    }
+
+    /// The ratio of uncompressed to compressed size.
+    pub fn ratio(&self) -> f64 {
+        self.total_in as f64 / self.total_out as f64
+    }
+
+    /// Code options used in each reference sample interval.
+    pub fn rsis(&self) -> &[OptionCounts] {
+        &self.rsis
+    }
+
+    /// Code options used in the whole stream.
+    pub fn totals(&self) -> OptionCounts {
+        let mut totals = OptionCounts::default();
+        for rsi in &self.rsis {
+            totals.add(rsi);
+        }
+        totals
+    }
+}
+
+/// Reads bits from a complete slice.
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Bits<'a> {
+    fn get(&mut self, n: u32) -> Option<u32> {
+        if self.pos + n as usize > self.data.len() * 8 {
+            return None;
+        }
+        let mut v = 0u64;
+        let mut left = n;
+        while left > 0 {
+            let byte = self.data[self.pos / 8] as u64;
+            let offset = (self.pos % 8) as u32;
+            let take = left.min(8 - offset);
+            let bits = (byte >> (8 - offset - take)) & ((1 << take) - 1);
+            v = (v << take) | bits;
+            left -= take;
+            self.pos += take as usize;
+        }
+        Some(v as u32)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<()> {
+        if self.pos + n > self.data.len() * 8 {
+            return None;
+        }
+        self.pos += n;
+        Some(())
+    }
+
+    /// Read a fundamental sequence, the number of zeros before a one.
+    fn fs(&mut self) -> Option<u64> {
+        let mut count = 0;
+        while self.get(1)? == 0 {
+            count += 1;
+        }
+        Some(count)
+    }
+
+    fn align(&mut self) {
+        self.pos = self.pos.div_ceil(8) * 8;
+    }
+}
+
+/// Collects [`Stats`] from encoder output, one interval at a time.
+#[derive(Clone, Debug)]
+pub(crate) struct Walker {
+    bits_per_sample: u32,
+    block_size: usize,
+    rsi: usize,
+    storage: usize,
+    id_len: u32,
+    preprocess: bool,
+    pad_rsi: bool,
+    // output not yet parsed, starting at bit `pos`
+    pending: Vec<u8>,
+    pos: usize,
+    stats: Stats,
+}
+
+impl Walker {
+    pub fn new(conf: &Configuration) -> Self {
+        let bits = conf.bits_per_sample();
+        let flags = conf.flags();
+        let id_len = match bits {
+            17.. => 5,
+            9..=16 => 4,
+            1..=2 if flags.contains(Flags::RESTRICTED) => 1,
+            3..=4 if flags.contains(Flags::RESTRICTED) => 2,
+            _ => 3,
+        };
+        Self {
+            bits_per_sample: bits as u32,
+            block_size: conf.block_size(),
+            rsi: conf.rsi(),
+            storage: sample::storage_size(bits, flags),
+            id_len,
+            preprocess: flags.contains(Flags::DATA_PREPROCESS),
+            pad_rsi: flags.contains(Flags::PAD_RSI),
+            pending: vec![],
+            pos: 0,
+            stats: Stats::default(),
+        }
+    }
+
+    /// Parse newly encoded `output`, after `total_in` bytes of input
+    /// have been read.
+    pub fn push(&mut self, output: &[u8], total_in: usize) {
+        self.pending.extend_from_slice(output);
+        self.stats.total_out += output.len();
+        // only whole intervals are parsed, so that the end of the
+        // stream is not mistaken for more blocks
+        let rsi_samples = self.rsi * self.block_size;
+        let samples = total_in / self.storage;
+        while samples >= (self.stats.rsis.len() + 1) * rsi_samples {
+            if !self.parse_rsi(self.rsi) {
+                break;
+            }
+        }
+        // drop whole bytes already parsed
+        let done = self.pos / 8;
+        self.pending.drain(..done);
+        self.pos -= done * 8;
+    }
+
+    /// Statistics so far, after `total_in` bytes of input. The last
+    /// partial interval is included if the encoder has been flushed.
+    pub fn stats(&self, total_in: usize) -> Stats {
+        let mut walker = self.clone();
+        let samples = total_in / self.storage;
+        let blocks = samples.div_ceil(self.block_size);
+        loop {
+            let remaining = blocks.saturating_sub(walker.stats.rsis.len() * self.rsi);
+            if remaining == 0 || !walker.parse_rsi(remaining.min(self.rsi)) {
+                break;
+            }
+        }
+        walker.stats.total_in = total_in;
+        walker.stats.samples = samples;
+        walker.stats
+    }
+
+    /// Parse an interval of `blocks` blocks, returning false if more
+    /// output is needed.
+    fn parse_rsi(&mut self, blocks: usize) -> bool {
+        let mut bits = Bits {
+            data: &self.pending,
+            pos: self.pos,
+        };
+        let mut counts = OptionCounts::default();
+        let n = self.bits_per_sample as usize;
+        let bs = self.block_size;
+        let uncomp = (1 << self.id_len) - 1;
+
+        let mut b = 0;
+        while b < blocks {
+            let has_ref = self.preprocess && b == 0;
+            let ref_bits = if has_ref { n } else { 0 };
+            let parsed = (|| {
+                let id = bits.get(self.id_len)?;
+                if id == 0 {
+                    let zero = bits.get(1)? == 0;
+                    bits.skip(ref_bits)?;
+                    if zero {
+                        let fs = bits.fs()?;
+                        let count = match fs + 1 {
+                            c if c == ROS => (blocks - b).min(SEGMENT - b % SEGMENT),
+                            c if c > ROS => fs as usize,
+                            c => c as usize,
+                        };
+                        let count = count.min(blocks - b);
+                        counts.zero_block += count as u32;
+                        return Some(count);
+                    }
+                    for _ in 0..bs / 2 {
+                        bits.fs()?;
+                    }
+                    counts.second_extension += 1;
+                } else if id == uncomp {
+                    bits.skip(bs * n)?;
+                    counts.uncompressed += 1;
+                } else {
+                    let k = id - 1;
+                    bits.skip(ref_bits)?;
+                    let coded = bs - has_ref as usize;
+                    for _ in 0..coded {
+                        bits.fs()?;
+                    }
+                    bits.skip(coded * k as usize)?;
+                    counts.split[k as usize] += 1;
+                }
+                Some(1)
+            })();
+            match parsed {
+                Some(count) => b += count,
+                None => return false,
+            }
+        }
+        if self.pad_rsi {
+            bits.align();
+        }
+
+        self.pos = bits.pos;
+        self.stats.rsis.push(counts);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{Configuration, Flags};
+
+    #[test]
+    fn options() {
+        let conf = Configuration::new(16, 16, 8, Flags::DATA_PREPROCESS);
+        let mut state = 1u32;
+        let mut data = vec![];
+        // zeros, a smooth ramp, then noise
+        data.extend((0..512u32).flat_map(|_| 0u16.to_le_bytes()));
+        data.extend((0..512u32).flat_map(|i| (i as u16 * 3).to_le_bytes()));
+        data.extend((0..1000u32).flat_map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state as u16).to_le_bytes()
+        }));
+
+        let mut enc = conf.encoder().unwrap();
+        enc.enable_stats().unwrap();
+        let mut encoded = Vec::with_capacity(data.len());
+        for chunk in data.chunks(300) {
+            let (rest, _, _) = enc.encode(chunk, &mut encoded, false).unwrap();
+            assert!(rest.is_empty());
+        }
+        enc.encode_all(&[], &mut encoded).unwrap();
+        let stats = enc.stats().unwrap();
+        assert_eq!(stats.total_in(), data.len());
+        assert_eq!(stats.total_out(), encoded.len());
+        assert_eq!(stats.samples(), 2024);
+        assert!(stats.ratio() > 1.0);
+        assert!((stats.bits_per_sample() - encoded.len() as f64 * 8.0 / 2024.0).abs() < 1e-9);
+
+        // 2024 samples in 127 blocks, in intervals of 8
+        assert_eq!(stats.rsis().len(), 16);
+        assert_eq!(stats.rsis()[15].blocks(), 7);
+        let totals = stats.totals();
+        assert_eq!(totals.blocks(), 127);
+        assert_eq!(stats.rsis()[0].zero_block, 8);
+        assert!(totals.uncompressed > 50);
+        assert!(totals.second_extension + totals.split.iter().sum::<u32>() > 0);
+
+        // every block is accounted for, whatever the sample size
+        for conf in [
+            Configuration::new(8, 8, 4, Flags::empty()),
+            Configuration::new(4, 16, 3, Flags::RESTRICTED | Flags::DATA_PREPROCESS),
+            Configuration::new(24, 32, 5, Flags::DATA_PREPROCESS | Flags::PAD_RSI),
+        ] {
+            let mut enc = conf.encoder().unwrap();
+            enc.enable_stats().unwrap();
+            let input = &data[..data.len() / 4 * 4];
+            let mut encoded = vec![];
+            enc.encode_all(input, &mut encoded).unwrap();
+            let stats = enc.stats().unwrap();
+            let blocks = stats.samples().div_ceil(conf.block_size());
+            assert_eq!(stats.totals().blocks() as usize, blocks);
+            assert_eq!(stats.rsis().len(), blocks.div_ceil(conf.rsi()));
+        }
+
+        let mut enc = conf.encoder().unwrap();
+        assert!(enc.stats().is_none());
+        enc.encode_all(&data, &mut vec![]).unwrap();
+        assert!(enc.enable_stats().is_err());
+    }
+}
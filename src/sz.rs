@@ -1,8 +1,12 @@
-use crate::Buffer;
+use crate::{sample, Buffer, Configuration, Decoder, Encoder, Flags, Reader, Writer};
 
 use libaec_sys::szlib::*;
 use libc::{c_int, c_void, size_t};
 
+use std::convert::TryFrom;
+use std::io;
+use std::io::{Read, Write};
+
 bitflags::bitflags! {
     pub struct Options: u32 {
         const ALLOW_K13 = SZ_ALLOW_K13_OPTION_MASK as u32;
@@ -114,6 +118,60 @@ impl Sz {
         }
     }
 
+    /// An upper bound on the compressed size of `source_len` bytes.
+    pub fn compress_bound(&self, source_len: usize) -> usize {
+        let (bits, pixel) = match self.bits_per_pixel() {
+            // compressed as byte planes
+            32 | 64 => (8, 1),
+            bits => (bits, sample::storage_size(bits, Flags::empty())),
+        };
+        let block_size = self.pixels_per_block().max(1);
+        let scanline = self.pixels_per_scanline().max(1);
+        let scanlines = source_len.div_ceil(pixel).div_ceil(scanline);
+        let blocks = scanlines * scanline.div_ceil(block_size);
+        // at worst, every block is stored uncompressed behind its
+        // option id, and every scanline is padded to a byte
+        let total = blocks * (5 + block_size * bits) + scanlines * 8;
+        total.div_ceil(8) + 8
+    }
+
+    /// Compress `source`, appending to `dest` and growing it as
+    /// needed.
+    pub fn compress_to_vec<'a>(
+        &mut self,
+        source: &[u8],
+        dest: &'a mut Vec<u8>,
+    ) -> Result<&'a mut [u8], Error> {
+        let start = dest.len();
+        let mut reserve = self.compress_bound(source.len());
+        loop {
+            dest.reserve(reserve);
+            // nothing is written to dest unless compression succeeds
+            match self.compress(source, dest).map(|out| out.len()) {
+                Ok(len) => return Ok(&mut dest[start..start + len]),
+                Err(Error::OutputBufferFull) => reserve = dest.capacity() * 2,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Decompress `source` into `dest_len` bytes, appending to `dest`
+    /// and growing it as needed.
+    pub fn decompress_to_vec<'a>(
+        &mut self,
+        source: &[u8],
+        dest_len: usize,
+        dest: &'a mut Vec<u8>,
+    ) -> Result<&'a mut [u8], Error> {
+        let start = dest.len();
+        dest.reserve(dest_len);
+        let out = &mut dest.spare_capacity_mut()[..dest_len];
+        let len = self.decompress(source, out)?.len();
+        // safe: decompress initialized len bytes
+        unsafe { dest.set_len(start + len) };
+        Ok(&mut dest[start..])
+    }
+
     pub fn options(&self) -> Options {
         Options::from_bits_truncate(self.0.options_mask as u32)
     }
@@ -129,11 +187,304 @@ impl Sz {
     pub fn pixels_per_scanline(&self) -> usize {
         self.0.pixels_per_scanline as usize
     }
+
+    /// The stream configuration libaec uses for these parameters,
+    /// before any padding or byte plane splitting.
+    fn stream_configuration(&self) -> Result<Configuration, Error> {
+        let block_size = self.pixels_per_block();
+        let scanline = self.pixels_per_scanline();
+        if block_size == 0 || scanline == 0 {
+            return Err(Error::Parameter);
+        }
+        let mut flags = Flags::NOT_ENFORCE;
+        flags.set(Flags::DATA_MSB, self.options().contains(Options::MSB));
+        flags.set(Flags::DATA_PREPROCESS, self.options().contains(Options::NN));
+        Ok(Configuration::new(
+            self.bits_per_pixel(),
+            block_size,
+            scanline.div_ceil(block_size),
+            flags,
+        ))
+    }
+
+    fn scanlines(&self) -> Result<Scanlines, Error> {
+        // 32 and 64 bit pixels are split into byte planes across the
+        // whole buffer, which cannot be streamed
+        if self.bits_per_pixel() == 32 || self.bits_per_pixel() == 64 {
+            return Err(Error::Parameter);
+        }
+        let conf = self.stream_configuration()?;
+        let pixel = sample::storage_size(conf.bits_per_sample(), conf.flags());
+        let scanline = self.pixels_per_scanline();
+        // scanlines are padded out to a whole number of blocks
+        let padded = conf.rsi() * conf.block_size();
+        Ok(Scanlines {
+            pixel,
+            line: scanline * pixel,
+            padding: (padded - scanline) * pixel,
+            preprocess: conf.flags().contains(Flags::DATA_PREPROCESS),
+            conf,
+        })
+    }
+
+    /// Compress data written to the returned writer into `inner`, a
+    /// scanline at a time.
+    ///
+    /// This produces the same output as [`Sz::compress`], but 32 and
+    /// 64 bit pixels are not supported.
+    pub fn compress_writer<W: Write>(&self, inner: W) -> Result<SzWriter<W>, Error> {
+        let lines = self.scanlines()?;
+        let encoder = lines.conf.encoder().map_err(|_| Error::Parameter)?;
+        Ok(SzWriter {
+            writer: Writer::new(encoder, inner),
+            line: Vec::with_capacity(lines.line + lines.padding),
+            lines,
+        })
+    }
+
+    /// Decompress `len` bytes from `inner`, a scanline at a time.
+    ///
+    /// This produces the same output as [`Sz::decompress`], but 32
+    /// and 64 bit pixels are not supported.
+    pub fn decompress_reader<R: io::BufRead>(
+        &self,
+        inner: R,
+        len: u64,
+    ) -> Result<SzReader<R>, Error> {
+        let lines = self.scanlines()?;
+        let decoder = lines.conf.decoder().map_err(|_| Error::Parameter)?;
+        Ok(SzReader {
+            reader: Reader::new(decoder, inner),
+            line: Vec::with_capacity(lines.line + lines.padding),
+            pos: 0,
+            remaining: len,
+            lines,
+        })
+    }
+}
+
+/// The AEC stream configuration matching these szip parameters.
+///
+/// This fails for 32 and 64 bit pixels, which szip splits into byte
+/// planes, and for scanlines that are not a whole number of blocks,
+/// which szip pads.
+impl TryFrom<&Sz> for Configuration {
+    type Error = Error;
+
+    fn try_from(sz: &Sz) -> Result<Self, Error> {
+        let bits = sz.bits_per_pixel();
+        if bits == 32 || bits == 64 {
+            return Err(Error::Parameter);
+        }
+        let conf = sz.stream_configuration()?;
+        if !sz.pixels_per_scanline().is_multiple_of(conf.block_size()) {
+            return Err(Error::Parameter);
+        }
+        Ok(conf)
+    }
+}
+
+/// The szip parameters matching this AEC stream configuration.
+///
+/// This fails for signed samples, 3 byte storage, the restricted and
+/// RSI padding flags and predictors, none of which szip uses, for 32
+/// bit samples, and for blocks or scanlines longer than szip allows.
+impl TryFrom<&Configuration> for Sz {
+    type Error = Error;
+
+    fn try_from(conf: &Configuration) -> Result<Self, Error> {
+        let flags = conf.flags();
+        let unsupported = Flags::DATA_SIGNED | Flags::RESTRICTED | Flags::PAD_RSI;
+        let bits = conf.bits_per_sample();
+        let three_byte = flags.contains(Flags::DATA_3BYTE) && (17..=24).contains(&bits);
+        let predicted = conf.prediction_width().is_some();
+        if flags.intersects(unsupported) || three_byte || predicted || bits == 0 || bits >= 32 {
+            return Err(Error::Parameter);
+        }
+        if conf.block_size() > SZ_MAX_PIXELS_PER_BLOCK as usize
+            || conf.rsi() > SZ_MAX_BLOCKS_PER_SCANLINE as usize
+        {
+            return Err(Error::Parameter);
+        }
+        let mut options = Options::empty();
+        options |= if flags.contains(Flags::DATA_MSB) {
+            Options::MSB
+        } else {
+            Options::LSB
+        };
+        options |= if flags.contains(Flags::DATA_PREPROCESS) {
+            Options::NN
+        } else {
+            Options::EC
+        };
+        Ok(Sz::new(
+            options,
+            bits,
+            conf.block_size(),
+            conf.block_size() * conf.rsi(),
+        ))
+    }
+}
+
+/// Scanline layout of the uncompressed stream, in bytes.
+#[derive(Clone, Debug)]
+struct Scanlines {
+    conf: Configuration,
+    pixel: usize,
+    line: usize,
+    padding: usize,
+    // pad with the last pixel, instead of zeros
+    preprocess: bool,
+}
+
+/// Compresses szip data written to it, a scanline at a time.
+///
+/// The stream is only complete once [`SzWriter::finish`] is called.
+#[derive(Debug)]
+pub struct SzWriter<W: Write> {
+    writer: Writer<Encoder, W>,
+    // the current scanline
+    line: Vec<u8>,
+    lines: Scanlines,
+}
+
+impl<W: Write> SzWriter<W> {
+    // pad the current scanline, and encode it
+    fn write_line(&mut self) -> io::Result<()> {
+        let full = self.lines.line + self.lines.padding;
+        let pixel = self.lines.pixel;
+        let start = self.line.len();
+        if start < full {
+            if self.lines.preprocess {
+                let last = start - pixel;
+                for i in 0..full - start {
+                    self.line.push(self.line[last + i % pixel]);
+                }
+            } else {
+                self.line.resize(full, 0);
+            }
+        }
+        self.writer.write_all(&self.line)?;
+        self.line.clear();
+        Ok(())
+    }
+
+    /// Compress any remaining data, end the stream, and return the
+    /// inner writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.line.len().is_multiple_of(self.lines.pixel) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "data ends with a partial pixel",
+            ));
+        }
+        if !self.line.is_empty() {
+            // without padding, the encoder handles partial scanlines
+            if self.lines.padding == 0 {
+                self.writer.write_all(&self.line)?;
+                self.line.clear();
+            } else {
+                self.write_line()?;
+            }
+        }
+        self.writer.finish()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.writer.get_mut()
+    }
+}
+
+impl<W: Write> Write for SzWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(self.lines.line - self.line.len());
+        self.line.extend_from_slice(&buf[..n]);
+        if self.line.len() == self.lines.line {
+            self.write_line()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Decompresses szip data, a scanline at a time.
+#[derive(Debug)]
+pub struct SzReader<R> {
+    reader: io::BufReader<Reader<Decoder, R>>,
+    // the current scanline, without padding
+    line: Vec<u8>,
+    pos: usize,
+    remaining: u64,
+    lines: Scanlines,
+}
+
+impl<R: io::BufRead> SzReader<R> {
+    // decode the next scanline, and drop its padding
+    fn read_line(&mut self) -> io::Result<()> {
+        let full = self.lines.line + self.lines.padding;
+        self.line.resize(full, 0);
+        let mut got = 0;
+        while got < full {
+            let n = self.reader.read(&mut self.line[got..])?;
+            if n == 0 {
+                break;
+            }
+            got += n;
+        }
+        self.line.truncate(got.min(self.lines.line));
+        self.pos = 0;
+        if self.line.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader.into_inner().into_inner()
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.reader.get_ref().get_ref()
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        self.reader.get_mut().get_mut()
+    }
+}
+
+impl<R: io::BufRead> Read for SzReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos == self.line.len() {
+            self.read_line()?;
+        }
+        let available = &self.line[self.pos..];
+        let n = buf
+            .len()
+            .min(available.len())
+            .min(self.remaining.min(usize::MAX as u64) as usize);
+        buf[..n].copy_from_slice(&available[..n]);
+        self.pos += n;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::{Options, Sz};
+    use crate::{Configuration, Flags};
+    use std::convert::TryFrom;
+    use std::io::{Read, Write};
 
     #[test]
     fn round_trip() {
@@ -159,4 +510,135 @@ mod test {
         assert_eq!(decompressed[0], 42);
         assert_eq!(&decompressed[1..], data);
     }
+
+    #[test]
+    fn streaming() {
+        let data: Vec<u8> = (0..5000u32)
+            .flat_map(|i| ((i * 7 % 1000) as u16).to_le_bytes().to_vec())
+            .collect();
+        // whole and partial blocks per scanline, with and without
+        // preprocessing
+        for &(options, scanline) in &[
+            (Options::NN, 256),
+            (Options::NN, 100),
+            (Options::empty(), 100),
+            (Options::NN | Options::MSB, 333),
+        ] {
+            let mut sz = Sz::new(options, 16, 16, scanline);
+            let mut whole = Vec::with_capacity(data.len() * 2);
+            sz.compress(&data, &mut whole).unwrap();
+
+            let mut writer = sz.compress_writer(vec![]).unwrap();
+            for chunk in data.chunks(37) {
+                writer.write_all(chunk).unwrap();
+            }
+            let streamed = writer.finish().unwrap();
+            assert_eq!(streamed, whole);
+
+            let mut reader = sz
+                .decompress_reader(&streamed[..], data.len() as u64)
+                .unwrap();
+            let mut decompressed = vec![];
+            reader.read_to_end(&mut decompressed).unwrap();
+            assert_eq!(decompressed, data);
+        }
+
+        assert!(Sz::new(Options::NN, 32, 16, 256)
+            .compress_writer(vec![])
+            .is_err());
+    }
+
+    #[test]
+    fn to_vec() {
+        // noise compresses badly, so the output needs the bound
+        let mut state = 12345u32;
+        let data: Vec<u8> = (0..10000)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        for &bits in &[8, 32] {
+            let mut sz = Sz::new(Options::NN, bits, 8, 100);
+            let mut compressed = vec![42];
+            let len = sz.compress_to_vec(&data, &mut compressed).unwrap().len();
+            assert_eq!(compressed.len(), len + 1);
+            assert!(len <= sz.compress_bound(data.len()));
+            assert_eq!(compressed[0], 42);
+
+            let mut decompressed = vec![42];
+            sz.decompress_to_vec(&compressed[1..], data.len(), &mut decompressed)
+                .unwrap();
+            assert_eq!(&decompressed[1..], &data[..]);
+        }
+
+        // a full buffer is left untouched, so retrying loses nothing
+        let mut sz = Sz::new(Options::NN, 8, 8, 100);
+        let mut small = Vec::with_capacity(16);
+        assert_eq!(
+            sz.compress(&data, &mut small),
+            Err(super::Error::OutputBufferFull)
+        );
+        assert!(small.is_empty());
+    }
+
+    #[test]
+    fn configuration() {
+        let sz = Sz::new(Options::MSB | Options::NN | Options::RAW, 16, 16, 256);
+        let conf = Configuration::try_from(&sz).unwrap();
+        assert_eq!(
+            conf,
+            Configuration::new(
+                16,
+                16,
+                16,
+                Flags::DATA_MSB | Flags::DATA_PREPROCESS | Flags::NOT_ENFORCE
+            )
+        );
+        let back = Sz::try_from(&conf).unwrap();
+        assert_eq!(back.options(), Options::MSB | Options::NN);
+        assert_eq!(back.bits_per_pixel(), 16);
+        assert_eq!(back.pixels_per_block(), 16);
+        assert_eq!(back.pixels_per_scanline(), 256);
+
+        // szip data decodes with the AEC decoder, and the reverse
+        let data: Vec<u8> = (0..4096u32)
+            .flat_map(|i| ((i % 700) as u16).to_be_bytes().to_vec())
+            .collect();
+        let mut sz = sz;
+        let mut compressed = vec![];
+        sz.compress_to_vec(&data, &mut compressed).unwrap();
+        let mut decoded = vec![];
+        conf.decode_buffer(&compressed, &mut decoded).unwrap();
+        assert_eq!(&decoded[..data.len()], &data[..]);
+
+        let mut encoded = vec![];
+        conf.encode_buffer(&data, &mut encoded).unwrap();
+        let mut decompressed = vec![];
+        sz.decompress_to_vec(&encoded, data.len(), &mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+
+        for sz in &[
+            Sz::new(Options::NN, 32, 16, 256),
+            Sz::new(Options::NN, 16, 16, 100),
+            Sz::new(Options::NN, 16, 0, 256),
+        ] {
+            assert!(Configuration::try_from(sz).is_err());
+        }
+        for flags in &[Flags::DATA_SIGNED, Flags::RESTRICTED, Flags::PAD_RSI] {
+            let conf = Configuration::new(8, 16, 16, *flags);
+            assert!(Sz::try_from(&conf).is_err());
+        }
+        let conf = Configuration::new(20, 16, 16, Flags::DATA_3BYTE);
+        assert!(Sz::try_from(&conf).is_err());
+
+        // too long for szip
+        let conf = Configuration::new(8, 64, 16, Flags::NOT_ENFORCE);
+        assert!(Sz::try_from(&conf).is_err());
+        let conf = Configuration::new(8, 16, 129, Flags::empty());
+        assert!(Sz::try_from(&conf).is_err());
+        let conf = Configuration::new(8, 32, 128, Flags::empty());
+        assert!(Sz::try_from(&conf).is_ok());
+    }
 }